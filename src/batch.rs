@@ -0,0 +1,378 @@
+//! Runs of many instances listed in a manifest, executed concurrently within a total thread budget
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{Method, MethodResults, Prog, RunOptions, control, read_method_file, solve, write_run_results};
+use crate::error::TamcError;
+use crate::util::{thread_pool, with_seed};
+use crate::sweep::configuration_file;
+use crate::warmstart::Assignment;
+
+#[derive(StructOpt)]
+pub struct BatchProg{
+    /// Manifest with one job per line: instance file, method file, output prefix and an optional seed
+    pub manifest: String,
+    /// Total number of threads shared by the jobs (all cores by default)
+    #[structopt(long)]
+    pub threads: Option<u32>,
+    /// Summary table of the jobs (the manifest path with the extension .summary.tsv by default)
+    #[structopt(long)]
+    pub summary: Option<String>,
+    #[structopt(long)]
+    pub qubo: bool
+}
+
+/// One line of a manifest
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatchJob{
+    pub instance_file: String,
+    pub method_file: String,
+    /// The results are written to `<prefix>.yml` and the samples or final states to `<prefix>_samples.bin`
+    pub output_prefix: String,
+    pub seed: Option<u64>
+}
+
+impl BatchJob{
+    pub fn output_file(&self) -> String{
+        return format!("{}.yml", self.output_prefix);
+    }
+
+    pub fn sample_output(&self) -> String{
+        return format!("{}_samples.bin", self.output_prefix);
+    }
+
+    /// The outputs are written under these names and renamed once all of them are complete,
+    /// so that the results file only exists for finished jobs
+    fn partial_output_file(&self) -> String{
+        return format!("{}.part.yml", self.output_prefix);
+    }
+
+    fn partial_sample_output(&self) -> String{
+        return format!("{}_samples.part.bin", self.output_prefix);
+    }
+
+    /// Rename the partial outputs, the results file last
+    fn finish_outputs(&self, num_configurations: usize) -> anyhow::Result<()>{
+        let rename = |from: String, to: String| std::fs::rename(&from, &to)
+            .with_context(|| format!("Failed to rename {} to {}", from, to));
+        if Path::new(&self.partial_sample_output()).exists(){
+            rename(self.partial_sample_output(), self.sample_output())?;
+        }
+        // the samples of each configuration of a sweep
+        for k in 0..num_configurations{
+            let partial = configuration_file(&self.partial_sample_output(), k);
+            if Path::new(&partial).exists(){
+                rename(partial, configuration_file(&self.sample_output(), k))?;
+            }
+        }
+        rename(self.partial_output_file(), self.output_file())?;
+        Ok(())
+    }
+
+    fn prog(&self, qubo: bool) -> Prog{
+        return Prog{
+            method_file: self.method_file.clone(),
+            instance_file: self.instance_file.clone(),
+            output_file: self.partial_output_file(),
+            suscepts: Vec::new(),
+            sample_output: Some(self.partial_sample_output()),
+            qubo,
+            progress: None,
+            progress_file: None,
+            initial_states: None,
+            initial_assignment: Assignment::Distribute,
+            initial_perturbation: 0.0
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus{
+    Done,
+    /// The output file already existed
+    Skipped,
+    Failed,
+    /// The batch was interrupted before the job started
+    NotRun,
+    /// The batch was interrupted while the job ran. Its partial results are left in
+    /// `<prefix>.part.yml`, and the job is run again when the batch is resumed.
+    Interrupted
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobSummary{
+    pub job: BatchJob,
+    pub status: JobStatus,
+    pub threads: u32,
+    pub energy: Option<f32>,
+    /// Wall time of the job in seconds
+    pub time: Option<f64>,
+    pub error: Option<String>
+}
+
+impl JobSummary{
+    fn new(job: &BatchJob, status: JobStatus) -> Self{
+        return Self{job: job.clone(), status, threads: 0, energy: None, time: None, error: None};
+    }
+
+    fn failed(job: &BatchJob, error: String) -> Self{
+        return Self{error: Some(error), ..Self::new(job, JobStatus::Failed)};
+    }
+}
+
+pub fn read_manifest(file: &str) -> Result<Vec<BatchJob>, TamcError>{
+    let f = File::open(file).map_err(|e| TamcError::Io(e, file.to_string()))?;
+    return parse_manifest(BufReader::new(f)).map_err(|e| e.in_file(file));
+}
+
+/// Parse the whitespace-separated columns `instance method prefix [seed]` of each line.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_manifest<R: BufRead>(reader: R) -> Result<Vec<BatchJob>, TamcError>{
+    let mut jobs = Vec::new();
+    for (i, line) in reader.lines().enumerate(){
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#'){
+            continue;
+        }
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        if cols.len() < 3 || cols.len() > 4{
+            return Err(TamcError::Parse{file: String::new(), line: i + 1,
+                msg: format!("expected 'instance method prefix [seed]', found '{}'", line)});
+        }
+        let seed = match cols.get(3){
+            Some(s) => Some(s.parse::<u64>().map_err(|e| TamcError::Parse{file: String::new(), line: i + 1,
+                msg: format!("invalid seed '{}' ({})", s, e)})?),
+            None => None
+        };
+        jobs.push(BatchJob{instance_file: cols[0].to_string(), method_file: cols[1].to_string(),
+            output_prefix: cols[2].to_string(), seed});
+    }
+    return Ok(jobs);
+}
+
+/// Runs the jobs of a manifest concurrently, each on a thread pool of the threads of its method,
+/// such that the jobs running at any time use at most the thread budget
+pub struct BatchRunner{
+    jobs: Vec<BatchJob>,
+    threads: u32,
+    qubo: bool
+}
+
+impl BatchRunner{
+    pub fn new(jobs: Vec<BatchJob>, threads: u32) -> Result<Self, TamcError>{
+        if threads == 0{
+            return Err(TamcError::invalid_parameter("threads", "the thread budget must be positive"));
+        }
+        return Ok(Self{jobs, threads, qubo: false});
+    }
+
+    /// Read the instance files as QUBOs
+    pub fn with_qubo(mut self, qubo: bool) -> Self{
+        self.qubo = qubo;
+        return self;
+    }
+
+    /// Run every job and summarize them in the order of the manifest.
+    /// A failed job is recorded in its summary and does not stop the others.
+    pub fn run(&self) -> Vec<JobSummary>{
+        // each method file is read once
+        let mut methods: HashMap<&str, Result<Method, String>> = HashMap::new();
+        for job in self.jobs.iter(){
+            methods.entry(&job.method_file).or_insert_with(||
+                read_method_file(&job.method_file)
+                    .and_then(|m| { m.validate().into_result()?; Ok(m) })
+                    .map_err(|e| e.to_string()));
+        }
+        let available = Mutex::new(self.threads);
+        let released = Condvar::new();
+        let next = AtomicUsize::new(0);
+        let summaries = Mutex::new(vec![None; self.jobs.len()]);
+        let num_workers = (self.threads as usize).min(self.jobs.len());
+        thread::scope(|s| {
+            for _ in 0..num_workers{
+                s.spawn(|| loop {
+                    let k = next.fetch_add(1, Ordering::Relaxed);
+                    if k >= self.jobs.len(){
+                        break;
+                    }
+                    let summary = self.run_job(&self.jobs[k], &methods[self.jobs[k].method_file.as_str()],
+                                               &available, &released);
+                    summaries.lock().unwrap()[k] = Some(summary);
+                });
+            }
+        });
+        return summaries.into_inner().unwrap().into_iter().map(|s| s.unwrap()).collect();
+    }
+
+    fn run_job(&self, job: &BatchJob, method: &Result<Method, String>,
+               available: &Mutex<u32>, released: &Condvar) -> JobSummary{
+        if control::interrupted(){
+            return JobSummary::new(job, JobStatus::NotRun);
+        }
+        if Path::new(&job.output_file()).exists(){
+            info!("Skipping {}: {} exists", job.instance_file, job.output_file());
+            return JobSummary::new(job, JobStatus::Skipped);
+        }
+        let method = match method{
+            Ok(m) => m,
+            Err(e) => return JobSummary::failed(job, e.clone())
+        };
+        let threads = method.threads().max(1).min(self.threads);
+        {
+            let mut avail = released.wait_while(available.lock().unwrap(), |a| *a < threads).unwrap();
+            *avail -= threads;
+        }
+        info!("Running {} on {} with {} threads", method.name(), job.instance_file, threads);
+        let start = Instant::now();
+        let result = self.execute(job, method, threads);
+        let time = start.elapsed().as_secs_f64();
+        *available.lock().unwrap() += threads;
+        released.notify_all();
+        return match result{
+            Ok((status, energy)) => JobSummary{threads, energy, time: Some(time), ..JobSummary::new(job, status)},
+            Err(e) => {
+                warn!("{} failed: {}", job.instance_file, e);
+                JobSummary{threads, time: Some(time), ..JobSummary::failed(job, e.to_string())}
+            }
+        };
+    }
+
+    fn execute(&self, job: &BatchJob, method: &Method, threads: u32) -> anyhow::Result<(JobStatus, Option<f32>)>{
+        let pool = thread_pool(threads)?;
+        let prog = job.prog(self.qubo);
+        if let Some(dir) = Path::new(&job.output_prefix).parent().filter(|d| !d.as_os_str().is_empty()){
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+        }
+        let instance = prog.read_instance()?;
+        // a method asking for more threads than the budget is limited to it
+        let mut method = method.clone();
+        if method.threads() > threads{
+            method.set_threads(threads);
+        }
+        let options = RunOptions{qubo: self.qubo, ..Default::default()};
+        let run_results = pool.install(|| with_seed(job.seed, || solve(&instance, &method, &options)))?;
+        write_run_results(&prog, &run_results)?;
+        let energy = run_results.results.min_energy();
+        if control::interrupted(){
+            return Ok((JobStatus::Interrupted, energy));
+        }
+        let num_configurations = match &run_results.results{
+            MethodResults::Sweep(r) => r.runs.len(),
+            _ => 0
+        };
+        job.finish_outputs(num_configurations)?;
+        return Ok((JobStatus::Done, energy));
+    }
+}
+
+/// Write the summaries as a table of tab-separated columns
+pub fn write_summary(file: &str, summaries: &[JobSummary]) -> anyhow::Result<()>{
+    let mut f = File::create(file)
+        .with_context(|| format!("Failed to create summary file {}", file))?;
+    writeln!(f, "instance\tmethod\toutput\tseed\tstatus\tthreads\tenergy\ttime\terror")
+        .and_then(|_| summaries.iter().try_for_each(|s| writeln!(f, "{}", summary_row(s))))
+        .with_context(|| format!("Failed to write summary to {}", file))?;
+    Ok(())
+}
+
+fn summary_row(s: &JobSummary) -> String{
+    fn opt<T: ToString>(x: &Option<T>) -> String{
+        return x.as_ref().map_or("-".to_string(), |x| x.to_string());
+    }
+    let error = s.error.as_ref().map_or("-".to_string(), |e| e.replace(['\t', '\n'], " "));
+    return format!("{}\t{}\t{}\t{}\t{:?}\t{}\t{}\t{}\t{}", s.job.instance_file, s.job.method_file, s.job.output_file(),
+                   opt(&s.job.seed), s.status, s.threads, opt(&s.energy), opt(&s.time.map(|t| format!("{:.3}", t))), error);
+}
+
+pub fn run_batch(prog: BatchProg) -> Result<(), Box<dyn Error>>{
+    simple_logger::SimpleLogger::new().with_level(log::LevelFilter::Warn).env().init()?;
+    let jobs = read_manifest(&prog.manifest)?;
+    let threads = prog.threads.unwrap_or(rayon::current_num_threads() as u32);
+    println!(" ** Batch of {} jobs on {} threads **", jobs.len(), threads);
+    let summaries = BatchRunner::new(jobs, threads)?.with_qubo(prog.qubo).run();
+    let summary_file = prog.summary.clone().unwrap_or_else(||
+        Path::new(&prog.manifest).with_extension("summary.tsv").to_string_lossy().to_string());
+    write_summary(&summary_file, &summaries)?;
+
+    println!("** Batch summary **");
+    for s in summaries.iter(){
+        let energy = s.energy.map_or("-".to_string(), |e| e.to_string());
+        let time = s.time.map_or("-".to_string(), |t| format!("{:.3} s", t));
+        println!("  {:<8} {:>12} {:>12}  {}", format!("{:?}", s.status), energy, time, s.job.output_file());
+    }
+    let count = |status| summaries.iter().filter(|s| s.status == status).count();
+    let num_failed = count(JobStatus::Failed);
+    println!("  {} done, {} skipped, {} failed, {} interrupted, {} not run. Summary written to {}",
+             count(JobStatus::Done), count(JobStatus::Skipped), num_failed, count(JobStatus::Interrupted),
+             count(JobStatus::NotRun), summary_file);
+    if num_failed > 0{
+        return Err(format!("{} of {} jobs failed (see {})", num_failed, summaries.len(), summary_file).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{BatchRunner, JobStatus, parse_manifest};
+    use crate::error::TamcError;
+
+    #[test]
+    fn test_batch(){
+        let input = "# instance method prefix seed\nring.txt descent.yml out/ring 7\n\nring.txt descent.yml out/ring2\n";
+        let jobs = parse_manifest(input.as_bytes()).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].seed, Some(7));
+        assert_eq!(jobs[1].seed, None);
+        match parse_manifest("ring.txt descent.yml out x\n".as_bytes()).map_err(|e| e.in_file("jobs.txt")){
+            Err(TamcError::Parse{file, line, ..}) => { assert_eq!(file, "jobs.txt"); assert_eq!(line, 1); }
+            _ => panic!("expected an invalid seed")
+        }
+
+        let dir = std::env::temp_dir().join(format!("tamc_batch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |f: &str| dir.join(f).to_str().unwrap().to_string();
+        std::fs::write(path("ring.txt"), "0 1 -1.0\n1 2 -1.0\n2 3 -1.0\n3 0 -1.0\n").unwrap();
+        std::fs::write(path("descent.yml"), "---\nDescent:\n  kind: Greedy\n  num_restarts: 16\n  threads: 2\n").unwrap();
+        let manifest = format!("{0} {1} {2} 7\n{0} {1} {3} 7\nmissing.txt {1} {4}\n",
+                               path("ring.txt"), path("descent.yml"), path("a"), path("b"), path("c"));
+        let jobs = parse_manifest(manifest.as_bytes()).unwrap();
+        let runner = BatchRunner::new(jobs, 3).unwrap();
+        let summaries = runner.run();
+        let status: Vec<_> = summaries.iter().map(|s| s.status).collect();
+        assert_eq!(status, vec![JobStatus::Done, JobStatus::Done, JobStatus::Failed]);
+        assert_eq!(summaries[0].energy, Some(-4.0));
+        assert_eq!(summaries[0].threads, 2);
+        assert!(summaries[2].error.as_ref().unwrap().contains("missing.txt"));
+        // the same seed gives the same results
+        let results = |f: &str| {
+            let mut r: serde_yaml::Value = serde_yaml::from_str(&std::fs::read_to_string(path(f)).unwrap()).unwrap();
+            r.as_mapping_mut().unwrap().remove(&"timing".into());
+            r
+        };
+        assert_eq!(results("a.yml"), results("b.yml"));
+        assert!(!std::path::Path::new(&path("a.part.yml")).exists());
+        // a job whose results were left partial is run again
+        std::fs::rename(path("b.yml"), path("b.part.yml")).unwrap();
+
+        // completed jobs are skipped when the batch is run again
+        let summaries = runner.run();
+        assert_eq!(summaries[0].status, JobStatus::Skipped);
+        assert_eq!(summaries[1].status, JobStatus::Done);
+        assert_eq!(summaries[2].status, JobStatus::Failed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -8,6 +8,8 @@ use tamc::Method;
 use tamc::ising;
 use tamc::pt::{BetaOptions, PtIcmParams, PtIcmRunner};
 use tamc::ising::{BqmIsingInstance, IsingState};
+use tamc::error::TamcError;
+use tamc::util::run_in_pool;
 use ndarray::prelude::*;
 use tamc_core::util::monotonic_divisions;
 
@@ -38,7 +40,7 @@ pub fn pt_optimize_beta(
     alpha: f32,
     m: f32,
     tol: f32
-) -> (PtIcmParams, Array2<f32>) {
+) -> Result<(PtIcmParams, Array2<f32>), TamcError> {
     use interp::interp;
     use tamc_core::util::{StepwiseMeasure, finite_differences, monotonic_bisection};
 
@@ -65,10 +67,15 @@ pub fn pt_optimize_beta(
         let beta_arr = Array1::from_vec(beta_vec.clone());
         let beta_difs = &beta_arr.slice(s![1..]) - &beta_arr.slice(s![0..-1]);
         // Run PT on the current temperature array on all replicas
+        // params.threads is the total budget, split evenly between the instances
+        let mut run_params = params.clone();
+        run_params.threads = (params.threads / num_instances as u32).max(1);
         let pticm_vec: Vec<PtIcmRunner> = instances.iter()
-            .map(|i| PtIcmRunner::new(i, &params)).collect();
-        let results: Vec<Vec<PTState<IsingState>>> = pticm_vec.par_iter().zip_eq(init_states.par_iter())
-            .map(|(p, s)| p.run(s.clone()).2).collect();
+            .map(|i| PtIcmRunner::new(i, &run_params)).collect::<Result<_, _>>()?;
+        let results: Vec<Vec<PTState<IsingState>>> = run_in_pool(params.threads, ||
+            pticm_vec.par_iter().zip_eq(init_states.par_iter())
+                .map(|(p, s)| p.run(s.clone()).map(|r| r.2)).collect::<Result<_, _>>())?;
+        drop(pticm_vec);
         // Gather the diffusion histograms for each temperature summed over all replica chains
         // Also evaluate the round trip times
         let mut dif_probs_vec : Vec<Array1<f32>> = Vec::with_capacity(num_instances);
@@ -194,28 +201,30 @@ pub fn pt_optimize_beta(
     //double the number of sweeps
     params.num_sweeps *= 2;
 
-    return (params, tau_hist_arr);
+    return Ok((params, tau_hist_arr));
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let prog : PtOptim = StructOpt::from_args();
     SimpleLogger::new().with_level(log::LevelFilter::Warn).init().unwrap();
     let method_file = prog.params;
     let instance_files = &prog.instances;
     let instance_vec : Vec<BqmIsingInstance> = instance_files.iter()
-        .map(|s| BqmIsingInstance::from_instance_file(&s, false)).collect();
-    let yaml_str = std::fs::read_to_string(method_file).unwrap();
-    let opts: Method= serde_yaml::from_str(&yaml_str).unwrap();
+        .map(|s| BqmIsingInstance::from_instance_file(&s, false)).collect::<Result<_, _>>()?;
+    let yaml_str = std::fs::read_to_string(&method_file)
+        .map_err(|e| TamcError::Io(e, method_file.clone()))?;
+    let opts: Method = serde_yaml::from_str(&yaml_str)
+        .map_err(|e| TamcError::MethodParse(Box::new(e), method_file.clone()))?;
     match opts{
         Method::PT(pt_params) => {
             let (opt_params, tau_hist) = pt_optimize_beta(
-                &instance_vec, &pt_params, prog.max_iters, prog.step_size, prog.momentum, prog.tolerance);
+                &instance_vec, &pt_params, prog.max_iters, prog.step_size, prog.momentum, prog.tolerance)?;
             let opt_method = Method::PT(opt_params);
-            let yaml_string = serde_yaml::to_string(&opt_method).unwrap();
+            let yaml_string = serde_yaml::to_string(&opt_method)?;
             println!("{}", &yaml_string);
             println!("\n\n ** Writing to {} **", prog.opt_params);
-            std::fs::write(&prog.opt_params, &yaml_string);
-            let mut f = std::fs::File::create(&prog.tau_hist).unwrap();
+            std::fs::write(&prog.opt_params, &yaml_string)?;
+            let mut f = std::fs::File::create(&prog.tau_hist)?;
             for row in tau_hist.rows(){
                 for x in row.iter(){
                     write!(f, "{},", x);
@@ -227,4 +236,5 @@ fn main() {
             warn!("Must input a PT method for tamc-pt-opt")
         }
     };
+    Ok(())
 }
\ No newline at end of file
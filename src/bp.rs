@@ -0,0 +1,421 @@
+use std::time;
+
+use anyhow::Result;
+use log::{info, warn};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::traits::*;
+
+use crate::util::{seeded_rng, write_yaml};
+use crate::Prog;
+use crate::control;
+use crate::error::TamcError;
+use crate::ising::{BqmIsingInstance, IsingState, Spin};
+use crate::ising_results::MinResults;
+use crate::pt::BetaOptions;
+use crate::validate::Validation;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BpParams{
+    /// Inverse temperatures at which the marginals and Bethe free energy are evaluated.
+    /// Messages are warm-started from the previous beta.
+    pub beta: Option<BetaOptions>,
+    /// Search for a ground state by max-product guided decimation
+    pub decimation: Option<DecimationParams>,
+    /// Fraction of the previous message kept in each update
+    pub damping: f64,
+    pub max_iterations: u32,
+    /// Convergence threshold on the largest message change
+    pub tolerance: f64
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DecimationParams{
+    /// Fraction of the remaining free spins fixed after each round of message passing
+    pub fraction: f64
+}
+
+impl BpParams{
+    /// Check every parameter before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.beta.is_none() && self.decimation.is_none(){
+            v.error("beta", "neither a beta schedule nor a decimation is set, so there is nothing to run",
+                    "set beta, decimation, or both");
+        }
+        if let Some(beta) = &self.beta{
+            beta.validate("beta", &mut v);
+        }
+        if let Some(decimation) = &self.decimation{
+            if !(decimation.fraction > 0.0 && decimation.fraction <= 1.0){
+                v.error("decimation.fraction", format!("the decimated fraction must be in (0, 1], but is {}", decimation.fraction),
+                        "set fraction to e.g. 0.01");
+            }
+        }
+        if !(0.0..1.0).contains(&self.damping){
+            v.error("damping", format!("damping must be in [0, 1), but is {}", self.damping), "set damping to e.g. 0.5");
+        }
+        if self.max_iterations == 0{
+            v.error("max_iterations", "max_iterations must be positive", "set max_iterations, e.g. to 1000");
+        }
+        if !(self.tolerance > 0.0){
+            v.error("tolerance", format!("tolerance must be positive, but is {}", self.tolerance), "set tolerance to e.g. 1.0e-8");
+        }
+        return v;
+    }
+}
+
+/// Result of loopy belief propagation at one beta
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BpThermalResults{
+    pub beta: f32,
+    pub converged: bool,
+    pub iterations: u32,
+    pub max_delta: f64,
+    pub magnetizations: Vec<f64>,
+    /// Bethe estimates of the internal energy, free energy and log Z
+    pub energy: f64,
+    pub free_energy: f64,
+    pub log_z: f64
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BpDecimationResults{
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    pub num_rounds: u32,
+    /// Number of decimation rounds where max-product did not converge
+    pub num_unconverged: u32
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BpResults{
+    pub params: BpParams,
+    pub thermal: Vec<BpThermalResults>,
+    pub decimation: Option<BpDecimationResults>
+}
+
+/// Flat adjacency with the index of the reverse edge,
+/// so that messages along i -> j are stored at the reverse of the edge j -> i
+#[derive(Clone)]
+struct BpGraph{
+    offsets: Vec<usize>,
+    nbrs: Vec<u32>,
+    /// J_ij = -K_ij, so that the weight of each edge is exp(β J_ij s_i s_j)
+    j_cpl: Vec<f64>,
+    rev: Vec<usize>
+}
+
+impl BpGraph{
+    fn new(instance: &BqmIsingInstance) -> Result<Self, TamcError>{
+        let n = instance.size();
+        let mut offsets = Vec::with_capacity(n + 1);
+        let mut nbrs = Vec::new();
+        let mut j_cpl = Vec::new();
+        offsets.push(0);
+        for row in instance.coupling_vecs.iter(){
+            for &(j, k) in row.iter(){
+                nbrs.push(j);
+                j_cpl.push(-k as f64);
+            }
+            offsets.push(nbrs.len());
+        }
+        let mut rev = vec![0; nbrs.len()];
+        for i in 0..n{
+            for e in offsets[i]..offsets[i+1]{
+                let j = nbrs[e] as usize;
+                rev[e] = (offsets[j]..offsets[j+1]).find(|&e2| nbrs[e2] as usize == i)
+                    .ok_or_else(|| TamcError::invalid_parameter(
+                        "coupling", format!("the coupling matrix must be symmetric, but ({}, {}) has no reverse", i, j)))?;
+            }
+        }
+        return Ok(Self{offsets, nbrs, j_cpl, rev});
+    }
+
+    fn num_sites(&self) -> usize{
+        return self.offsets.len() - 1;
+    }
+}
+
+/// Loopy belief propagation over cavity fields.
+/// u[e] for an edge e = (i, k) in the row of i is the message u_{k->i}.
+/// The local field of spin i is  g_i = -h_i + Σ_k u_{k->i},  with marginal p(s_i) ∝ exp(β g_i s_i)
+struct BeliefPropagation<'a>{
+    instance: &'a BqmIsingInstance,
+    graph: BpGraph,
+    u: Vec<f64>,
+    u_new: Vec<f64>,
+    fixed: Vec<Option<Spin>>
+}
+
+impl<'a> BeliefPropagation<'a>{
+    fn new<Rn: Rng+?Sized>(instance: &'a BqmIsingInstance, graph: BpGraph, rng: &mut Rn) -> Self{
+        let num_edges = graph.nbrs.len();
+        let u = (0..num_edges).map(|_| 0.01 * (rng.gen::<f64>() - 0.5)).collect();
+        let fixed = vec![None; graph.num_sites()];
+        return Self{instance, graph, u, u_new: vec![0.0; num_edges], fixed};
+    }
+
+    fn local_field(&self, i: usize) -> f64{
+        let g = self.graph.offsets[i]..self.graph.offsets[i+1];
+        return -(self.instance.bias[i] as f64) + self.u[g].iter().sum::<f64>();
+    }
+
+    /// One damped synchronous update of all messages. `beta = None` is the max-product limit.
+    /// Returns the largest message change.
+    fn update(&mut self, beta: Option<f64>, damping: f64) -> f64{
+        let n = self.graph.num_sites();
+        for i in 0..n{
+            let g_i = self.local_field(i);
+            for e in self.graph.offsets[i]..self.graph.offsets[i+1]{
+                let j = self.graph.j_cpl[e];
+                let msg = match self.fixed[i]{
+                    Some(s) => j * (s as f64),
+                    None => {
+                        // cavity field of i without the message from k
+                        let g = g_i - self.u[e];
+                        match beta{
+                            Some(b) => f64::atanh(f64::tanh(b * j) * f64::tanh(b * g)) / b,
+                            None => f64::signum(j * g) * f64::min(j.abs(), g.abs())
+                        }
+                    }
+                };
+                self.u_new[self.graph.rev[e]] = msg;
+            }
+        }
+        let mut max_delta: f64 = 0.0;
+        for (u, &u_new) in self.u.iter_mut().zip(self.u_new.iter()){
+            let u_damped = damping * *u + (1.0 - damping) * u_new;
+            max_delta = max_delta.max((u_damped - *u).abs());
+            *u = u_damped;
+        }
+        return max_delta;
+    }
+
+    /// Iterate until convergence. Returns (converged, iterations, max_delta)
+    fn converge(&mut self, beta: Option<f64>, params: &BpParams) -> (bool, u32, f64){
+        let mut max_delta = f64::INFINITY;
+        for it in 1..=params.max_iterations{
+            max_delta = self.update(beta, params.damping);
+            if max_delta < params.tolerance{
+                return (true, it, max_delta);
+            }
+        }
+        return (false, params.max_iterations, max_delta);
+    }
+
+    fn magnetizations(&self, beta: f64) -> Vec<f64>{
+        return (0..self.graph.num_sites()).map(|i| f64::tanh(beta * self.local_field(i))).collect();
+    }
+
+    /// Bethe free energy from the single-site and edge beliefs
+    ///     F = U - S/β,  S = Σ_(ij) S(b_ij) - Σ_i (d_i - 1) S(b_i)
+    fn bethe_free_energy(&self, beta: f64) -> (f64, f64){
+        let n = self.graph.num_sites();
+        let mut energy = self.instance.offset as f64;
+        let mut entropy = 0.0;
+        let spins = [1.0, -1.0];
+        for i in 0..n{
+            let g_i = self.local_field(i);
+            let m_i = f64::tanh(beta * g_i);
+            energy += (self.instance.bias[i] as f64) * m_i;
+            let d_i = (self.graph.offsets[i+1] - self.graph.offsets[i]) as f64;
+            entropy -= (d_i - 1.0) * binary_entropy(m_i);
+            for e in self.graph.offsets[i]..self.graph.offsets[i+1]{
+                let k = self.graph.nbrs[e] as usize;
+                if k < i {
+                    continue;
+                }
+                let j = self.graph.j_cpl[e];
+                let g_ik = g_i - self.u[e];
+                let g_ki = self.local_field(k) - self.u[self.graph.rev[e]];
+                // log weights of the edge belief b_ik(s_i, s_k)
+                let mut lw = [0.0; 4];
+                for (a, &si) in spins.iter().enumerate(){
+                    for (b, &sk) in spins.iter().enumerate(){
+                        lw[2*a + b] = beta * (j * si * sk + g_ik * si + g_ki * sk);
+                    }
+                }
+                let max_lw = lw.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                let z: f64 = lw.iter().map(|&l| f64::exp(l - max_lw)).sum();
+                for (a, &si) in spins.iter().enumerate(){
+                    for (b, &sk) in spins.iter().enumerate(){
+                        let p = f64::exp(lw[2*a + b] - max_lw) / z;
+                        energy += -j * si * sk * p;
+                        if p > 0.0{
+                            entropy -= p * p.ln();
+                        }
+                    }
+                }
+            }
+        }
+        return (energy, energy - entropy / beta);
+    }
+
+    /// Fix the given fraction of free spins with the largest max-product fields |g_i|
+    fn decimate<Rn: Rng+?Sized>(&mut self, fraction: f64, rng: &mut Rn) -> usize{
+        let mut free: Vec<(usize, f64)> = (0..self.graph.num_sites())
+            .filter(|&i| self.fixed[i].is_none())
+            .map(|i| (i, self.local_field(i)))
+            .collect();
+        let num_fix = ((fraction * free.len() as f64).ceil() as usize).max(1).min(free.len());
+        free.sort_by(|x, y| y.1.abs().partial_cmp(&x.1.abs()).unwrap());
+        for &(i, g) in free[..num_fix].iter(){
+            let s = if g > 0.0 { 1 } else if g < 0.0 { -1 } else if rng.gen::<bool>() { 1 } else { -1 };
+            self.fixed[i] = Some(s);
+        }
+        return free.len() - num_fix;
+    }
+}
+
+fn binary_entropy(m: f64) -> f64{
+    let mut s = 0.0;
+    for p in [(1.0 + m) / 2.0, (1.0 - m) / 2.0]{
+        if p > 0.0{
+            s -= p * p.ln();
+        }
+    }
+    return s;
+}
+
+/// Sample an Ising state with independent spins from the magnetizations,
+/// e.g. to seed the initial states of another method
+pub fn sample_from_magnetizations<Rn: Rng+?Sized>(magnetizations: &[f64], rng: &mut Rn) -> IsingState{
+    let arr = magnetizations.iter()
+        .map(|&m| if rng.gen::<f64>() < (1.0 + m) / 2.0 { 1 } else { -1 })
+        .collect();
+    return IsingState{arr, energy: 0.0, energy_init: false};
+}
+
+pub struct BpRunner<'a>{
+    params: &'a BpParams,
+    instance: &'a BqmIsingInstance,
+    graph: BpGraph
+}
+
+impl<'a> BpRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a BpParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        let graph = BpGraph::new(instance)?;
+        return Ok(Self{params, instance, graph});
+    }
+
+    pub fn run(&self) -> Result<BpResults, TamcError>{
+        // seed and create random number generator
+        let mut rng = seeded_rng();
+        let mut results = BpResults{params: self.params.clone(), thermal: Vec::new(), decimation: None};
+        if let Some(beta) = &self.params.beta{
+            let mut bp = BeliefPropagation::new(self.instance, self.graph.clone(), &mut rng);
+            for b in beta.get_beta_arr(){
+                if control::interrupted(){
+                    break;
+                }
+                let b64 = b as f64;
+                let (converged, iterations, max_delta) = bp.converge(Some(b64), self.params);
+                if !converged{
+                    warn!("BP did not converge at beta = {} (max delta = {:5.4e})", b, max_delta);
+                }
+                let (energy, free_energy) = bp.bethe_free_energy(b64);
+                results.thermal.push(BpThermalResults{
+                    beta: b, converged, iterations, max_delta,
+                    magnetizations: bp.magnetizations(b64),
+                    energy, free_energy, log_z: -b64 * free_energy
+                });
+            }
+        }
+        if let Some(decimation) = &self.params.decimation{
+            results.decimation = Some(self.decimation_loop(decimation, &mut rng));
+        }
+        return Ok(results);
+    }
+
+    fn decimation_loop<Rn: Rng+?Sized>(&self, decimation: &DecimationParams, rng: &mut Rn) -> BpDecimationResults{
+        let n = self.instance.size();
+        let mut bp = BeliefPropagation::new(self.instance, self.graph.clone(), rng);
+        let mut num_rounds = 0;
+        let mut num_unconverged = 0;
+        info!("-- BP decimation begin");
+        let start = time::Instant::now();
+        loop {
+            let (converged, _, _) = bp.converge(None, self.params);
+            num_rounds += 1;
+            if !converged{
+                num_unconverged += 1;
+            }
+            // once interrupted, fix all the remaining spins by their current fields
+            let fraction = if control::interrupted() { 1.0 } else { decimation.fraction };
+            if bp.decimate(fraction, rng) == 0{
+                break;
+            }
+        }
+        let end = start.elapsed();
+        info!("-- BP decimation Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        if num_unconverged > 0{
+            warn!("Max-product did not converge in {}/{} decimation rounds", num_unconverged, num_rounds);
+        }
+        let arr = bp.fixed.iter().map(|s| s.unwrap()).collect();
+        let state = IsingState{arr, energy: 0.0, energy_init: false};
+        let mut min_results = MinResults::new(0, n as u32);
+        min_results.gs_energies.push(self.instance.energy_ref(&state));
+        min_results.gs_states.push(state.as_u64_vec());
+        min_results.gs_time_steps.push(num_rounds);
+        min_results.num_measurements = 1;
+        min_results.timing = end.as_micros() as f64;
+        return BpDecimationResults{min_results, num_rounds, num_unconverged};
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &BpResults) -> Result<()>{
+    println!("BP Done.");
+    if let Some(th) = results.thermal.last(){
+        println!("** Bethe free energy (beta = {}) **", th.beta);
+        println!("  f = {}", th.free_energy);
+        println!("  converged = {}", th.converged);
+    }
+    if let Some(e) = results.decimation.as_ref().and_then(|dec| dec.min_results.gs_energies.last()){
+        println!("** Decimation energy **");
+        println!("  e = {}", e);
+    }
+    write_yaml(&prog.output_file, results, "BP")
+}
+
+#[cfg(test)]
+mod tests {
+    use sprs::TriMat;
+    use crate::bp::{BpParams, BpRunner, DecimationParams};
+    use crate::ising::BqmIsingInstance;
+    use crate::pt::BetaOptions;
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_chain_bp(){
+        // BP is exact on an open ferromagnetic chain:  Z = 2 (2 cosh β)^{n-1}
+        let n = 16;
+        let mut tri_mat = TriMat::new((n, n));
+        for i in 0..n-1{
+            tri_mat.add_triplet(i, i+1, -1.0);
+            tri_mat.add_triplet(i+1, i, -1.0);
+        }
+        let instance = BqmIsingInstance::new_zero_bias(tri_mat.to_csr()).unwrap();
+        let params = BpParams{
+            beta: Some(BetaOptions::new_geometric(0.1, 2.0, 4)),
+            decimation: Some(DecimationParams{fraction: 0.01}),
+            damping: 0.5,
+            max_iterations: 1000,
+            tolerance: 1.0e-10
+        };
+        let results = with_seed(Some(1234), || BpRunner::new(&instance, &params).unwrap().run()).unwrap();
+        for th in results.thermal.iter(){
+            let b = th.beta as f64;
+            let log_z = f64::ln(2.0) + ((n-1) as f64) * f64::ln(2.0 * f64::cosh(b));
+            println!("beta = {}, log Z = {}, Bethe log Z = {}", b, log_z, th.log_z);
+            assert!(th.converged);
+            assert!((th.log_z - log_z).abs() < 1.0e-6);
+        }
+        let dec = results.decimation.unwrap();
+        assert_eq!(*dec.min_results.gs_energies.last().unwrap(), -((n-1) as f32));
+    }
+}
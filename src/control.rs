@@ -0,0 +1,185 @@
+//! Shared stopping conditions, interruption and best-so-far tracking for runners that execute concurrently
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// The lowest energy reported so far, by which member and when
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct BestSoFar{
+    pub energy: f32,
+    pub member: usize,
+    /// Seconds since the start of the run
+    pub time: f64
+}
+
+/// Why a run ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason{
+    /// The full number of sweeps or schedule was run
+    Completed,
+    TargetEnergy,
+    TimeLimit,
+    /// A stop was requested from outside the run
+    Requested,
+    /// SIGINT or SIGTERM was received. The results are partial.
+    Interrupted
+}
+
+/// Exit code of the program after an interruption
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Handle SIGINT and SIGTERM by requesting every running RunControl to stop,
+/// so that runs finish their current sweep and write partial results.
+/// A second signal exits immediately.
+pub fn install_interrupt_handler() -> Result<(), ctrlc::Error>{
+    return ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst){
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        eprintln!("Interrupted. Finishing the current sweep and writing partial results (interrupt again to abort).");
+    });
+}
+
+/// True once SIGINT or SIGTERM was received
+pub fn interrupted() -> bool{
+    return INTERRUPTED.load(Ordering::Relaxed);
+}
+
+pub struct RunControl{
+    start: Instant,
+    time_limit: Option<Duration>,
+    target_energy: Option<f32>,
+    stop: AtomicBool,
+    reason: Mutex<Option<StopReason>>,
+    best: Mutex<Option<BestSoFar>>
+}
+
+impl RunControl{
+    /// Stop once an energy at or below target_energy is reported, or after time_limit seconds
+    pub fn new(target_energy: Option<f32>, time_limit: Option<f64>) -> Self{
+        return Self{
+            start: Instant::now(),
+            time_limit: time_limit.map(Duration::from_secs_f64),
+            target_energy,
+            stop: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            best: Mutex::new(None)
+        };
+    }
+
+    /// The handle passed to the runner of one member
+    pub fn handle(&self, member: usize) -> ControlHandle<'_>{
+        return ControlHandle{control: self, member};
+    }
+
+    pub fn request_stop(&self){
+        self.stop_with(StopReason::Requested);
+    }
+
+    /// Stop and record the reason, unless a stop was already recorded
+    pub fn stop_with(&self, reason: StopReason){
+        let mut r = self.reason.lock().unwrap();
+        if r.is_none(){
+            *r = Some(reason);
+        }
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// The reason of the first stop, if any
+    pub fn stop_reason(&self) -> Option<StopReason>{
+        return *self.reason.lock().unwrap();
+    }
+
+    pub fn stop_requested(&self) -> bool{
+        if self.stop.load(Ordering::Relaxed){
+            return true;
+        }
+        if interrupted(){
+            self.stop_with(StopReason::Interrupted);
+            return true;
+        }
+        if let Some(t) = self.time_limit{
+            if self.start.elapsed() >= t{
+                self.stop_with(StopReason::TimeLimit);
+                return true;
+            }
+        }
+        return false;
+    }
+
+    pub fn best(&self) -> Option<BestSoFar>{
+        return *self.best.lock().unwrap();
+    }
+
+    /// Seconds since the start of the run
+    pub fn elapsed(&self) -> f64{
+        return self.start.elapsed().as_secs_f64();
+    }
+
+    /// Record an energy found by the member, stopping if it reaches the target energy
+    pub fn report(&self, member: usize, energy: f32){
+        let mut best = self.best.lock().unwrap();
+        if best.map_or(true, |b| energy < b.energy){
+            *best = Some(BestSoFar{energy, member, time: self.elapsed()});
+        }
+        if self.target_energy.map_or(false, |e| energy <= e){
+            self.stop_with(StopReason::TargetEnergy);
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct ControlHandle<'a>{
+    control: &'a RunControl,
+    member: usize
+}
+
+impl<'a> ControlHandle<'a>{
+    #[inline]
+    pub fn should_stop(&self) -> bool{
+        return self.control.stop_requested();
+    }
+
+    /// Report a new lowest energy found by this member
+    pub fn report(&self, energy: f32){
+        self.control.report(self.member, energy);
+    }
+
+    /// The lowest energy reported by any member
+    pub fn best_energy(&self) -> Option<f32>{
+        return self.control.best().map(|b| b.energy);
+    }
+
+    pub fn stop_reason(&self) -> Option<StopReason>{
+        return self.control.stop_reason();
+    }
+}
+
+/// Check the stopping condition of an optional handle, or only for an interruption without one
+#[inline]
+pub(crate) fn should_stop(control: &Option<ControlHandle>) -> bool{
+    return control.map_or_else(interrupted, |c| c.should_stop());
+}
+
+/// Check the limits of a run and then the optional handle, returning the reason to stop if any
+pub(crate) fn check_stop(limits: &RunControl, control: &Option<ControlHandle>) -> Option<StopReason>{
+    if limits.stop_requested(){
+        return limits.stop_reason();
+    }
+    if should_stop(control){
+        return control.and_then(|c| c.stop_reason()).or(Some(StopReason::Requested));
+    }
+    return None;
+}
+
+/// Report an energy through an optional handle
+#[inline]
+pub(crate) fn report(control: &Option<ControlHandle>, energy: f32){
+    if let Some(c) = control{
+        c.report(energy);
+    }
+}
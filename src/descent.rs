@@ -0,0 +1,348 @@
+use std::time;
+
+use anyhow::Result;
+use itertools::Itertools;
+use log::info;
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::traits::*;
+
+use crate::util::{run_in_pool, seeded_rng, split_rng, split_work, write_yaml};
+use crate::Prog;
+use crate::error::TamcError;
+use crate::control::{self, ControlHandle};
+use crate::eo::FitnessHeap;
+use crate::ising::{BqmIsingInstance, IsingState, rand_ising_state};
+use crate::ising_results::MinResults;
+use crate::validate::Validation;
+
+/// Zero-temperature local search over single spin flips
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum DescentKind{
+    /// Sweep over the spins in order, flipping every spin that lowers the energy,
+    /// until a full sweep makes no flips
+    Greedy,
+    /// Always flip the spin with the most negative energy change
+    Steepest
+}
+
+/// Quench the state into a local minimum. Returns the number of spin flips.
+/// The cached energy of the state is cleared, since samplers that flip spins may leave it stale.
+pub fn descend(instance: &BqmIsingInstance, state: &mut IsingState, kind: DescentKind) -> u32{
+    let num_flips = match kind{
+        DescentKind::Greedy => greedy_descent(instance, state),
+        DescentKind::Steepest => steepest_descent(instance, state)
+    };
+    state.energy_init = false;
+    return num_flips;
+}
+
+fn greedy_descent(instance: &BqmIsingInstance, state: &mut IsingState) -> u32{
+    let n = instance.size() as u32;
+    let mut num_flips = 0;
+    loop {
+        let mut sweep_flips = 0;
+        for i in 0..n{
+            let de = unsafe { instance.delta_energy(state, &i) };
+            if de < 0.0 {
+                state.accept_move(i);
+                sweep_flips += 1;
+            }
+        }
+        num_flips += sweep_flips;
+        if sweep_flips == 0 {
+            break;
+        }
+    }
+    return num_flips;
+}
+
+/// The steepest spin is kept at the top of an indexed heap of the local energies e_i = -ΔE_i / 2,
+/// so that each flip costs O(d log n) for d neighbors
+fn steepest_descent(instance: &BqmIsingInstance, state: &mut IsingState) -> u32{
+    let n = instance.size();
+    if n == 0 {
+        return 0;
+    }
+    let mut heap = FitnessHeap::new(n);
+    heap.init(instance, state);
+    let mut num_flips = 0;
+    loop {
+        let i = heap.at_rank(0);
+        let ei = heap.local_energy(i);
+        if ei <= 0.0 {
+            break;
+        }
+        state.accept_move(i);
+        num_flips += 1;
+        heap.update(i, -ei);
+        instance.for_each_neighbor(i, |j| {
+            heap.update(j, -0.5 * unsafe { instance.delta_energy(state, &j) });
+        });
+    }
+    return num_flips;
+}
+
+/// Energies of a set of states before and after a zero-temperature quench
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QuenchResults{
+    pub kind: DescentKind,
+    pub pre_energies: Vec<f32>,
+    pub post_energies: Vec<f32>,
+    pub num_flips: Vec<u32>,
+    /// Quenched states in the same order as the energies, if they are not written elsewhere
+    pub states: Vec<Vec<u64>>
+}
+
+impl QuenchResults{
+    /// Quench every state in place
+    pub fn quench_states(instance: &BqmIsingInstance, states: &mut [IsingState], kind: DescentKind) -> Self{
+        let mut pre_energies = Vec::with_capacity(states.len());
+        let mut post_energies = Vec::with_capacity(states.len());
+        let mut num_flips = Vec::with_capacity(states.len());
+        for st in states.iter_mut(){
+            pre_energies.push(instance.energy_ref(st));
+            num_flips.push(descend(instance, st, kind));
+            post_energies.push(instance.energy_ref(st));
+        }
+        return Self{kind, pre_energies, post_energies, num_flips, states: Vec::new()};
+    }
+
+    /// Quench a list of bit-packed states, keeping the quenched states
+    pub fn quench_u64_states(instance: &BqmIsingInstance, states: &[Vec<u64>], kind: DescentKind) -> Self{
+        let n = instance.size() as u32;
+        let mut ising_states = states.iter()
+            .map(|v| IsingState::from_u64_vec(v, n).unwrap())
+            .collect_vec();
+        let mut quench_results = Self::quench_states(instance, &mut ising_states, kind);
+        quench_results.states = ising_states.iter().map(|st| st.as_u64_vec()).collect();
+        return quench_results;
+    }
+
+    pub fn min_post_energy(&self) -> Option<f32>{
+        return self.post_energies.iter().copied().min_by(|x, y| x.partial_cmp(y).unwrap());
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DescentParams{
+    pub kind: DescentKind,
+    pub num_restarts: u32,
+    pub threads: u32
+}
+
+impl DescentParams{
+    /// Check every parameter before running
+    pub fn validate(&self) -> Validation{
+        let mut v = self.validate_quench();
+        if self.num_restarts == 0{
+            v.error("num_restarts", "num_restarts must be positive", "set num_restarts, e.g. to 1000");
+        }
+        return v;
+    }
+
+    /// Check the parameters used when quenching given states, as in a pipeline stage, where num_restarts is ignored
+    pub(crate) fn validate_quench(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DescentMinResults{
+    pub params: DescentParams,
+    /// The ground state trace is indexed by restart
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    pub quench: QuenchResults
+}
+
+/// Local search from uniformly random states
+pub struct DescentRunner<'a>{
+    params: &'a DescentParams,
+    instance: &'a BqmIsingInstance,
+    control: Option<ControlHandle<'a>>
+}
+
+impl<'a> DescentRunner<'a>{
+    /// Only the quench parameters are checked, as a runner that only quenches given states ignores num_restarts
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a DescentParams) -> Result<Self, TamcError>{
+        params.validate_quench().into_result()?;
+        return Ok(Self{params, instance, control: None});
+    }
+
+    /// Stop the run early when the control requests it, and report new minimum energies to it
+    pub fn with_control(mut self, control: ControlHandle<'a>) -> Self{
+        self.control = Some(control);
+        return self;
+    }
+
+    pub fn run(&self) -> Result<DescentMinResults, TamcError>{
+        // seed and create random number generators, one for each thread
+        let mut rng = seeded_rng();
+        let threads = self.params.threads.max(1);
+        let mut rng_vec = split_rng(&mut rng, threads as usize);
+        info!("-- Descent begin");
+        let start = time::Instant::now();
+        let quench_vec = run_in_pool(threads, ||
+            split_work(self.params.num_restarts, &mut rng_vec, |restarts, rng| self.descent_loop(restarts, rng)));
+        let end = start.elapsed();
+        info!("-- Descent Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+
+        return Ok(self.collect_results(quench_vec, end));
+    }
+
+    /// Quench the given states instead of random restarts, returning the local minima
+    pub fn run_seeded(&self, mut states: Vec<IsingState>) -> Result<(DescentMinResults, Vec<IsingState>), TamcError>{
+        let threads = self.params.threads.max(1) as usize;
+        let chunk_size = ((states.len() + threads - 1) / threads).max(1);
+        info!("-- Descent begin");
+        let start = time::Instant::now();
+        let mut quench_vec = Vec::with_capacity(threads);
+        run_in_pool(threads as u32, ||
+            states.par_chunks_mut(chunk_size)
+                .map(|chunk| {
+                    let mut q = QuenchResults::quench_states(self.instance, chunk, self.params.kind);
+                    q.states = chunk.iter().map(|st| st.as_u64_vec()).collect();
+                    q
+                })
+                .collect_into_vec(&mut quench_vec));
+        let end = start.elapsed();
+        info!("-- Descent Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        let results = self.collect_results(quench_vec, end);
+        if let Some(&e) = results.min_results.gs_energies.last(){
+            control::report(&self.control, e);
+        }
+
+        return Ok((results, states));
+    }
+
+    fn collect_results(&self, quench_vec: Vec<QuenchResults>, end: time::Duration) -> DescentMinResults{
+        let n = self.instance.size();
+        let mut min_results = MinResults::new(0, n as u32);
+        let mut quench = QuenchResults{
+            kind: self.params.kind, pre_energies: Vec::new(), post_energies: Vec::new(),
+            num_flips: Vec::new(), states: Vec::new()
+        };
+        for q in quench_vec.into_iter(){
+            quench.pre_energies.extend(q.pre_energies);
+            quench.post_energies.extend(q.post_energies);
+            quench.num_flips.extend(q.num_flips);
+            quench.states.extend(q.states);
+        }
+        for (i, (&e, st)) in quench.post_energies.iter().zip(quench.states.iter()).enumerate(){
+            min_results.record(e, i as u32, || st.clone());
+        }
+        // the trace keeps the minimum states, so individual restart states are not stored twice
+        quench.states.clear();
+        min_results.num_measurements = quench.post_energies.len() as u32;
+        min_results.timing = end.as_micros() as f64;
+        let mut params = self.params.clone();
+        params.num_restarts = min_results.num_measurements;
+
+        return DescentMinResults{params, min_results, quench};
+    }
+
+    fn descent_loop<Rn: Rng+?Sized>(&self, num_restarts: u32, rng: &mut Rn) -> QuenchResults{
+        let n = self.instance.size() as u32;
+        let mut quench_results = QuenchResults{
+            kind: self.params.kind, pre_energies: Vec::new(), post_energies: Vec::new(),
+            num_flips: Vec::new(), states: Vec::new()
+        };
+        for _ in 0..num_restarts{
+            if control::should_stop(&self.control){
+                break;
+            }
+            let mut states = [rand_ising_state(n, self.instance, rng)];
+            let q = QuenchResults::quench_states(self.instance, &mut states, self.params.kind);
+            control::report(&self.control, q.post_energies[0]);
+            quench_results.pre_energies.extend(q.pre_energies);
+            quench_results.post_energies.extend(q.post_energies);
+            quench_results.num_flips.extend(q.num_flips);
+            quench_results.states.push(states[0].as_u64_vec());
+        }
+        return quench_results;
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &DescentMinResults) -> Result<()>{
+    println!("Descent Done.");
+    println!("** Ground state energy **");
+    if let Some(e) = results.min_results.gs_energies.last(){
+        println!("  e = {}", e);
+    }
+    write_yaml(&prog.output_file, results, "descent")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+    use tamc_core::traits::*;
+    use crate::{Method, MethodResults, RunOptions, solve};
+    use crate::descent::{descend, DescentKind, DescentParams, DescentRunner};
+    use crate::ising::rand_ising_state;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pt::BetaOptions;
+    use crate::sa::SaParams;
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_ising_2d_descent(){
+        let l = 8;
+        let n = (l*l) as u32;
+        let instance = make_ising_2d_instance(l);
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1234);
+        for kind in [DescentKind::Greedy, DescentKind::Steepest]{
+            let mut st = rand_ising_state(n, &instance, &mut rng);
+            let e0 = instance.energy_ref(&st);
+            let num_flips = descend(&instance, &mut st, kind);
+            let e1 = instance.energy(&mut st);
+            println!("{:?}: {} -> {} in {} flips", kind, e0, e1, num_flips);
+            assert!(e1 <= e0);
+            // no single flip lowers the energy of a local minimum
+            for i in 0..n{
+                assert!(unsafe { instance.delta_energy(&mut st, &i) } >= 0.0);
+            }
+            let params = DescentParams{kind, num_restarts: 16, threads: 2};
+            let results = with_seed(Some(1234), || DescentRunner::new(&instance, &params).unwrap().run()).unwrap();
+            assert_eq!(results.quench.post_energies.len(), 16);
+            let &e_min = results.min_results.gs_energies.last().unwrap();
+            assert!(results.quench.post_energies.iter().all(|&e| e >= e_min));
+        }
+    }
+
+    #[test]
+    fn test_quench_sa_states(){
+        let instance = make_ising_2d_instance(8);
+        let method = Method::SA(SaParams{
+            beta: BetaOptions::new_geometric(0.1, 5.0, 256),
+            num_replicas: 8,
+            threads: 1,
+            ais: None,
+            quench: Some(DescentKind::Greedy),
+            time_limit: None,
+            target_energy: None,
+            observables: None
+        });
+        let run_results = with_seed(Some(1234), || solve(&instance, &method, &RunOptions::default())).unwrap();
+        let quench = match &run_results.results{
+            MethodResults::SA(r) => r.quench.as_ref().unwrap(),
+            _ => panic!("expected SA results")
+        };
+        // the annealed states leave their cached energies stale, so the post-quench energies are recomputed
+        let states = run_results.final_states.as_ref().unwrap();
+        assert_eq!(quench.post_energies.len(), states.len());
+        for (&e, st) in quench.post_energies.iter().zip(states.iter()){
+            assert_eq!(e, instance.energy_ref(st));
+        }
+    }
+}
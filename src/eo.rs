@@ -0,0 +1,281 @@
+use std::time;
+
+use anyhow::Result;
+use log::info;
+use rand::prelude::*;
+use rand::distributions::WeightedIndex;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::traits::*;
+
+use crate::util::{run_in_pool, seeded_rng, split_rng, split_work, write_yaml};
+use crate::Prog;
+use crate::error::TamcError;
+use crate::control::{self, ControlHandle};
+use crate::ising::{BqmIsingInstance, IsingState, rand_ising_state};
+use crate::ising_results::MinResults;
+use crate::validate::Validation;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EoParams{
+    /// Exponent of the power-law distribution over fitness ranks
+    pub tau: f64,
+    /// Number of spin updates in each restart
+    pub num_updates: u32,
+    pub num_restarts: u32,
+    pub threads: u32
+}
+
+impl EoParams{
+    /// Check every parameter before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if !(self.tau > 0.0 && self.tau.is_finite()){
+            v.error("tau", format!("tau must be positive and finite, but is {}", self.tau), "set tau to e.g. 1.4");
+        } else if self.tau <= 1.0{
+            v.warn("tau", format!("tau = {} barely favours the least fit spins, so the search is close to a random walk", self.tau),
+                   "set tau to e.g. 1.4");
+        }
+        if self.num_updates == 0{
+            v.error("num_updates", "num_updates must be positive", "set num_updates, e.g. to 100000");
+        }
+        if self.num_restarts == 0{
+            v.error("num_restarts", "num_restarts must be positive", "set num_restarts, e.g. to 100");
+        }
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EoMinResults{
+    pub params: EoParams,
+    /// The ground state trace is indexed by restart
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    /// Best energy found in each restart
+    pub restart_energies: Vec<f32>
+}
+
+/// Indexed binary max-heap of the local energies  e_i = s_i (h_i + Σ_j K_ij s_j),
+/// so that the least fit spins are near the top.
+/// Ranks are approximated by heap positions, which keeps every update O(log n).
+/// Since ΔE_i = -2 e_i, the top is also the spin of steepest descent.
+pub(crate) struct FitnessHeap{
+    local_e: Vec<f32>,
+    heap: Vec<u32>,
+    pos: Vec<usize>
+}
+
+impl FitnessHeap{
+    pub(crate) fn new(n: usize) -> Self{
+        return Self{local_e: vec![0.0; n], heap: (0..n as u32).collect(), pos: (0..n).collect()};
+    }
+
+    pub(crate) fn init(&mut self, instance: &BqmIsingInstance, state: &mut IsingState){
+        let n = self.local_e.len();
+        for i in 0..n{
+            // ΔE_i = -2 e_i
+            self.local_e[i] = -0.5 * unsafe { instance.delta_energy(state, &(i as u32)) };
+            self.heap[i] = i as u32;
+            self.pos[i] = i;
+        }
+        for k in (0..n/2).rev(){
+            self.sift_down(k);
+        }
+    }
+
+    /// Spin at the heap position of the given zero-based rank
+    pub(crate) fn at_rank(&self, k: usize) -> u32{
+        return self.heap[k];
+    }
+
+    pub(crate) fn local_energy(&self, i: u32) -> f32{
+        return self.local_e[i as usize];
+    }
+
+    pub(crate) fn update(&mut self, i: u32, e: f32){
+        let old_e = self.local_e[i as usize];
+        self.local_e[i as usize] = e;
+        let k = self.pos[i as usize];
+        if e > old_e{
+            self.sift_up(k);
+        } else {
+            self.sift_down(k);
+        }
+    }
+
+    fn swap(&mut self, k1: usize, k2: usize){
+        self.heap.swap(k1, k2);
+        self.pos[self.heap[k1] as usize] = k1;
+        self.pos[self.heap[k2] as usize] = k2;
+    }
+
+    fn key(&self, k: usize) -> f32{
+        return self.local_e[self.heap[k] as usize];
+    }
+
+    fn sift_up(&mut self, mut k: usize){
+        while k > 0{
+            let parent = (k - 1) / 2;
+            if self.key(k) <= self.key(parent){
+                break;
+            }
+            self.swap(k, parent);
+            k = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut k: usize){
+        let n = self.heap.len();
+        loop {
+            let (l, r) = (2*k + 1, 2*k + 2);
+            let mut largest = k;
+            if l < n && self.key(l) > self.key(largest){
+                largest = l;
+            }
+            if r < n && self.key(r) > self.key(largest){
+                largest = r;
+            }
+            if largest == k{
+                break;
+            }
+            self.swap(k, largest);
+            k = largest;
+        }
+    }
+}
+
+/// τ-extremal optimization: every update unconditionally flips the spin of rank k,
+/// drawn with probability  P(k) ∝ k^{-τ},  where rank 1 is the least fit spin
+pub struct EoRunner<'a>{
+    params: &'a EoParams,
+    instance: &'a BqmIsingInstance,
+    rank_distr: WeightedIndex<f64>,
+    control: Option<ControlHandle<'a>>
+}
+
+impl<'a> EoRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a EoParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        let n = instance.size();
+        let rank_distr = WeightedIndex::new((1..=n).map(|k| (k as f64).powf(-params.tau)))
+            .map_err(|e| TamcError::invalid_parameter("tau", format!("failed to construct the rank distribution: {}", e)))?;
+        return Ok(Self{params, instance, rank_distr, control: None});
+    }
+
+    /// Stop the run early when the control requests it, and report new minimum energies to it
+    pub fn with_control(mut self, control: ControlHandle<'a>) -> Self{
+        self.control = Some(control);
+        return self;
+    }
+
+    pub fn run(&self) -> Result<EoMinResults, TamcError>{
+        // seed and create random number generators, one for each thread
+        let mut rng = seeded_rng();
+        let threads = self.params.threads.max(1);
+        let mut rng_vec = split_rng(&mut rng, threads as usize);
+        info!("-- EO begin");
+        let start = time::Instant::now();
+        let eo_vec = run_in_pool(threads, || split_work(self.params.num_restarts, &mut rng_vec, |restarts, rng|
+            (0..restarts)
+                .take_while(|_| !control::should_stop(&self.control))
+                .map(|_| self.eo_restart(rng)).collect::<Vec<_>>()));
+        let end = start.elapsed();
+        info!("-- EO Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+
+        let n = self.instance.size();
+        let mut min_results = MinResults::new(0, n as u32);
+        let mut restart_energies = Vec::new();
+        for (i, (e, st)) in eo_vec.into_iter().flatten().enumerate(){
+            restart_energies.push(e);
+            min_results.record(e, i as u32, || st.as_u64_vec());
+        }
+        min_results.num_measurements = restart_energies.len() as u32;
+        min_results.timing = end.as_micros() as f64;
+        let mut params = self.params.clone();
+        params.num_restarts = min_results.num_measurements;
+
+        return Ok(EoMinResults{params, min_results, restart_energies});
+    }
+
+    fn eo_restart<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> (f32, IsingState){
+        let n = self.instance.size();
+        let mut state = rand_ising_state(n as u32, self.instance, rng);
+        let mut heap = FitnessHeap::new(n);
+        heap.init(self.instance, &mut state);
+        let mut e = self.instance.energy_ref(&state);
+        let mut best_e = e;
+        let mut best_state = state.clone();
+        for t in 0..self.params.num_updates{
+            if t % 1024 == 0 && control::should_stop(&self.control){
+                break;
+            }
+            let k = rng.sample(&self.rank_distr);
+            let i = heap.at_rank(k);
+            // flipping spin i changes the energy by -2 e_i and negates its local energy
+            let ei = heap.local_e[i as usize];
+            state.accept_move(i);
+            e -= 2.0 * ei;
+            heap.update(i, -ei);
+            let si = state[i as usize] as f32;
+            for &(j, kij) in self.instance.coupling_vecs[i as usize].iter(){
+                let sj = state[j as usize] as f32;
+                let ej = heap.local_e[j as usize] + 2.0 * kij * si * sj;
+                heap.update(j, ej);
+            }
+            if e < best_e{
+                best_e = e;
+                best_state.clone_from(&state);
+            }
+        }
+        best_state.energy = best_e;
+        best_state.energy_init = true;
+        control::report(&self.control, best_e);
+        return (best_e, best_state);
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &EoMinResults) -> Result<()>{
+    println!("EO Done.");
+    println!("** Ground state energy **");
+    if let Some(e) = results.min_results.gs_energies.last(){
+        println!("  e = {}", e);
+    }
+    write_yaml(&prog.output_file, results, "EO")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+    use crate::eo::{EoParams, EoRunner, FitnessHeap};
+    use crate::ising::rand_ising_state;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_ising_2d_eo(){
+        let l = 8;
+        let n = l*l;
+        let instance = make_ising_2d_instance(l);
+        // the heap keeps the least fit spin at the top
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1234);
+        let mut st = rand_ising_state(n as u32, &instance, &mut rng);
+        let mut heap = FitnessHeap::new(n);
+        heap.init(&instance, &mut st);
+        let &max_e = heap.local_e.iter().max_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
+        assert_eq!(heap.local_e[heap.at_rank(0) as usize], max_e);
+
+        let params = EoParams{tau: 1.4, num_updates: 20000, num_restarts: 4, threads: 2};
+        let results = with_seed(Some(1234), || EoRunner::new(&instance, &params).unwrap().run()).unwrap();
+        let &e = results.min_results.gs_energies.last().unwrap();
+        println!("e = {}, restart energies: {:?}", e, results.restart_energies);
+        assert_eq!(results.restart_energies.len(), 4);
+        assert_eq!(e, -2.0 * n as f32);
+    }
+}
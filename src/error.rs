@@ -0,0 +1,140 @@
+//! Errors raised by tamc while reading inputs and setting up runs
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::validate::ParamIssue;
+
+#[derive(Debug)]
+pub enum TamcError{
+    /// A file could not be opened or read
+    Io(io::Error, String),
+    /// A line of an input file could not be parsed. Lines are numbered from one.
+    Parse{file: String, line: usize, msg: String},
+    /// The method file is not a valid method specification
+    MethodParse(Box<dyn Error + Send + Sync>, String),
+    /// A parameter has an invalid value
+    InvalidParameter{name: String, msg: String},
+    /// Every problem found by validating a set of parameters
+    InvalidParameters(Vec<ParamIssue>),
+    /// The sizes of two related inputs do not match
+    SizeMismatch{what: String, expected: usize, found: usize},
+    /// A thread pool could not be built
+    ThreadPool(rayon::ThreadPoolBuildError),
+    /// A method run on its own thread panicked
+    Panicked(String)
+}
+
+pub type Result<T> = std::result::Result<T, TamcError>;
+
+impl TamcError{
+    pub fn invalid_parameter(name: &str, msg: impl Into<String>) -> Self{
+        return TamcError::InvalidParameter{name: name.to_string(), msg: msg.into()};
+    }
+
+    pub fn size_mismatch(what: &str, expected: usize, found: usize) -> Self{
+        return TamcError::SizeMismatch{what: what.to_string(), expected, found};
+    }
+
+    /// Attach the name of the file being read to an I/O or parse error
+    pub fn in_file(self, file: &str) -> Self{
+        return match self{
+            TamcError::Io(e, _) => TamcError::Io(e, file.to_string()),
+            TamcError::Parse{line, msg, ..} => TamcError::Parse{file: file.to_string(), line, msg},
+            e => e
+        };
+    }
+}
+
+impl fmt::Display for TamcError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            TamcError::Io(e, s) => {
+                write!(f, "Could not read '{}': {}", s, e)
+            },
+            TamcError::Parse{file, line, msg} => {
+                write!(f, "Failed to parse line {} of '{}': {}", line, file, msg)
+            },
+            TamcError::MethodParse(e, s) =>{
+                writeln!(f, "Failed to parse '{}' as valid input", s)?;
+                write!(f, "{}", e.as_ref())
+            },
+            TamcError::InvalidParameter{name, msg} => {
+                write!(f, "Invalid parameter {}: {}", name, msg)
+            },
+            TamcError::InvalidParameters(issues) => {
+                write!(f, "Invalid parameters:")?;
+                for p in issues.iter(){
+                    write!(f, "\n  {}", p)?;
+                }
+                Ok(())
+            },
+            TamcError::SizeMismatch{what, expected, found} => {
+                write!(f, "Size mismatch in {}: expected {}, but found {}", what, expected, found)
+            },
+            TamcError::ThreadPool(e) => {
+                write!(f, "Failed to build a thread pool: {}", e)
+            },
+            TamcError::Panicked(s) => {
+                write!(f, "{} panicked", s)
+            }
+        }
+    }
+}
+
+impl Error for TamcError{
+    fn source(&self) -> Option<&(dyn Error + 'static)>{
+        match self{
+            TamcError::Io(e, _) => Some(e),
+            TamcError::MethodParse(e, _) => Some(e.as_ref()),
+            TamcError::ThreadPool(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for TamcError{
+    fn from(e: io::Error) -> Self{
+        return TamcError::Io(e, String::new());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::TamcError;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pt::{BetaOptions, PtIcmParams, PtIcmRunner};
+    use crate::util::read_adjacency_list;
+
+    #[test]
+    fn test_tamc_errors(){
+        // comments and blank lines are skipped, but line numbers count them
+        let input = "# 2 spins\n0 1 -1.0\n\n1 1 x\n";
+        match read_adjacency_list(input.as_bytes()).map_err(|e| e.in_file("inst.txt")){
+            Err(TamcError::Parse{file, line, ..}) => {
+                assert_eq!(file, "inst.txt");
+                assert_eq!(line, 4);
+            }
+            _ => panic!("expected a parse error")
+        }
+
+        let instance = make_ising_2d_instance(4);
+        let params = PtIcmParams{
+            beta: BetaOptions::Arr(vec![1.0, 0.5, 2.0]),
+            icm: false,
+            ..Default::default()
+        };
+        match PtIcmRunner::new(&instance, &params){
+            Err(TamcError::InvalidParameters(issues)) => assert_eq!(issues[0].field, "beta"),
+            _ => panic!("expected an invalid beta array")
+        }
+        let params = PtIcmParams{
+            beta: BetaOptions::new_geometric(0.1, 1.0, 4),
+            icm: true,
+            lo_beta: None,
+            lo_num_beta: None,
+            ..Default::default()
+        };
+        assert!(matches!(PtIcmRunner::new(&instance, &params), Err(TamcError::InvalidParameters(_))));
+    }
+}
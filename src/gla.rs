@@ -6,7 +6,7 @@ use std::iter::FromIterator;
 use std::ops::Index;
 use std::path::Path;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use itertools::Itertools;
 use log::info;
 use petgraph::csr::Csr;
@@ -21,40 +21,72 @@ use rayon::prelude::*;
 use sprs::DenseVector;
 use crate::ising::{BqmIsingInstance, IsingState};
 use crate::Prog;
+use crate::error::TamcError;
+use crate::validate::Validation;
+use crate::descent::{DescentKind, QuenchResults};
 use crate::pt::{PtIcmParams, PtIcmRunner};
-use crate::util::{read_u32_lines, write_data};
+use crate::control::StopReason;
+use crate::util::{fill_seed, read_u32_lines, run_in_pool, with_seed, write_data};
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct GlaParams{
     pub num_threads: u32,
     pub pt_params: PtIcmParams,
     pub partition_file: String,
+    /// Quench the stitched state into a local minimum of the full instance
+    pub quench: Option<DescentKind>
 }
 
 impl GlaParams{
+    /// Check every parameter, including the PT-ICM sub-solver parameters, before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.num_threads == 0{
+            v.error("num_threads", "num_threads must be positive", "set num_threads to e.g. 1");
+        }
+        if self.pt_params.threads > self.num_threads{
+            v.warn("pt_params.threads", format!("each partition asks for {} threads, more than the {} threads of num_threads",
+                                                self.pt_params.threads, self.num_threads),
+                   "num_threads is the total number of threads, so set pt_params.threads to at most num_threads");
+        }
+        if !Path::new(&self.partition_file).is_file(){
+            v.error("partition_file", format!("the partition file '{}' does not exist", self.partition_file),
+                    "check the path, which is relative to the working directory");
+        }
+        v.extend_nested("pt_params", self.pt_params.validate());
+        return v;
+    }
+
     /// Read in the graph partition specification.
     /// Each line contains the indices of spins that belong to one partition.
     /// All spins in the instance must be grouped to exactly one partition.
     pub fn read_graph_partition(&self, instance: &BqmIsingInstance)
-            -> Result<(Vec<Vec<u32>>, HashMap<u32, u32>)>{
+            -> Result<(Vec<Vec<u32>>, HashMap<u32, u32>), TamcError>{
         // read in the partition data
         let n = instance.size();
         let mut in_part : Vec<bool> = [false].repeat(n );
         let mut partition_mapping = HashMap::new();
-        let file = File::open(&self.partition_file)?;
-        let partition_vecs = read_u32_lines(file)?;
+        let file = File::open(&self.partition_file)
+            .map_err(|e| TamcError::Io(e, self.partition_file.clone()))?;
+        let partition_vecs = read_u32_lines(file).map_err(|e| e.in_file(&self.partition_file))?;
         for (ip, p) in partition_vecs.iter().enumerate(){
             for i in p.iter().map(|&x| x as usize){
                 if i >= n {
-                    return Err(anyhow!("Partitition must be specified for instance size {}. Found index {}", n, i))
+                    return Err(TamcError::invalid_parameter("partition_file",
+                        format!("partition must be specified for instance size {}. Found index {}", n, i)));
                 }
                 if in_part[i] {
-                    return Err(anyhow!("Index {} specified more than once in partition {}", i, ip))
+                    return Err(TamcError::invalid_parameter("partition_file",
+                        format!("index {} specified more than once in partition {}", i, ip)));
                 }
                 in_part[i] = true;
                 partition_mapping.insert(i as u32, ip as u32);
             }
         }
+        if let Some(i) = in_part.iter().position(|&b| !b){
+            return Err(TamcError::invalid_parameter("partition_file",
+                format!("index {} does not belong to any partition", i)));
+        }
         return Ok((partition_vecs, partition_mapping));
     }
 }
@@ -66,7 +98,10 @@ pub struct GlaResults{
     pub partition_energies: Vec<f32>,
     pub boundary_energies: Vec<(u32, u32, f32)>,
     pub final_state: Vec<u8>,
-    pub total_energy: f32
+    pub total_energy: f32,
+    pub quench: Option<QuenchResults>,
+    /// The first early stop of a partition sub-solver. Interrupted results are partial.
+    pub stop_reason: Option<StopReason>
 }
 
 /// Returns:
@@ -119,17 +154,17 @@ fn instance_partition(partition_vecs: Vec<Vec<u32>>,
 pub fn join_solutions(instance_vec: &Vec<BqmIsingInstance>, ){
 
 }
-pub fn run_gla(prog: &Prog, params: &GlaParams) -> Result<()>{
-    simple_logger::SimpleLogger::new().with_level(log::LevelFilter::Info).env()
-        .with_module_level("tamc::pt", log::LevelFilter::Off).init().unwrap();
-    // Read in the instance and partition
+/// Solve each partition of the instance with PT-ICM and stitch the partition ground states together.
+/// Returns the results along with the stitched (and possibly quenched) state.
+pub fn solve_gla(instance: &BqmIsingInstance, params: &GlaParams) -> Result<(GlaResults, IsingState), TamcError>{
+    params.validate().into_result()?;
+    // Read in the partition
     let mut gla_results = GlaResults::default();
-    let instance = prog.read_instance();
     gla_results.instance_size = instance.size() as u32;
     let (partition_vecs, partition_mapping) = params.read_graph_partition(&instance)?;
     gla_results.num_partitions = partition_vecs.len() as u32;
     // Split the instance according to the partition data
-    let (partition_graph, aug_graph) = instance_partition(partition_vecs, &partition_mapping, &instance);
+    let (partition_graph, aug_graph) = instance_partition(partition_vecs, &partition_mapping, instance);
     // Solve each sub-instance individually
     let sub_instance_indices = partition_graph.node_references()
         .map(|(_, n)| BTreeSet::from_iter(n.iter().copied())).collect_vec();
@@ -140,9 +175,8 @@ pub fn run_gla(prog: &Prog, params: &GlaParams) -> Result<()>{
     // Gather the sub-instance solutions and evaluate the energies
     let num_instances = instance_vec.len();
     // seed and create random number generator
-    let mut rngt = thread_rng();
     let mut seed_seq = [0u8; 32];
-    rngt.fill_bytes(&mut seed_seq);
+    fill_seed(&mut seed_seq);
     let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
     let mut rng_vec = Vec::with_capacity(num_instances as usize);
     for _ in 0..num_instances{
@@ -150,20 +184,37 @@ pub fn run_gla(prog: &Prog, params: &GlaParams) -> Result<()>{
         rng.jump();
     };
     let runners = instance_vec.iter()
-        .map(|inst| PtIcmRunner::new(&inst, &params.pt_params))
-        .collect_vec();
+        .map(|inst| PtIcmRunner::new(inst, &params.pt_params))
+        .collect::<Result<Vec<_>, _>>()?;
     let mut pt_states = runners.iter().zip_eq(rng_vec.iter_mut())
         .map(|(pt, rng)| pt.generate_init_state(rng)).collect_vec();
 
-    info!(" ** Running GLA with PT-ICM sub-solvers ...");
+    // num_threads is the total budget: each partition runs on pt_params.threads of them,
+    // and as many partitions as fit into the budget run concurrently
+    let pt_threads = params.pt_params.threads.max(1);
+    let num_concurrent = (params.num_threads / pt_threads).max(1);
+    info!(" ** Running GLA with PT-ICM sub-solvers ({} partitions at a time) ...", num_concurrent);
     let mut pt_results = Vec::with_capacity(num_instances);
-    runners.par_iter().zip_eq(rng_vec.par_iter_mut().zip_eq(pt_states.par_iter_mut()))
-        .map(|(pt, (rng, state))|
-            pt.pt_loop(state, rng)).collect_into_vec(&mut pt_results);
+    run_in_pool(num_concurrent, ||
+        runners.par_iter().zip_eq(rng_vec.par_iter_mut().zip_eq(pt_states.par_iter_mut()))
+            .map(|(pt, (rng, state))| if pt_threads > 1 {
+                // the parallel run seeds itself on this worker thread, so pass it a seed from the partition rng
+                let seed = rng.next_u64();
+                let (min_results, samples, final_state) = with_seed(Some(seed),
+                    || pt.run_parallel_seeded(Some(std::mem::take(state))))?;
+                *state = final_state;
+                Ok((min_results, samples))
+            } else {
+                Ok(pt.pt_loop(state, rng))
+            }).collect_into_vec(&mut pt_results));
+    let pt_results = pt_results.into_iter().collect::<Result<Vec<_>, TamcError>>()?;
     // Join together the ground state solutions
     let min_pt_states = pt_results.iter().zip_eq(instance_vec.iter())
         .map(|(res,inst)| IsingState::from_u64_vec(res.0.min_results.gs_states.last().unwrap(), inst.size() as u32).unwrap()).collect_vec();
 
+    gla_results.stop_reason = pt_results.iter().filter_map(|(m, _)| m.stop_reason)
+        .find(|&r| r != StopReason::Completed)
+        .or(Some(StopReason::Completed));
     let min_pt_energies = pt_results.iter().map(|(m, t)| m.min_results.gs_energies.last().unwrap().to_owned()).collect_vec();
 
     let mut total_bnd_energy: f32 = 0.0;
@@ -189,8 +240,26 @@ pub fn run_gla(prog: &Prog, params: &GlaParams) -> Result<()>{
     info!("Boundary energy: {}", total_bnd_energy);
     gla_results.total_energy = bulk_energy + total_bnd_energy;
     info!("Total energy: {}", gla_results.total_energy);
-    // Save the result
-    write_data(&prog.output_file, &gla_results).with_context(
+    // Stitch together the partition solutions into a state of the full instance
+    let mut stitched_state = IsingState{arr: vec![1; instance.size()], energy: 0.0, energy_init: false};
+    for (p, st) in partition_graph.node_references().map(|(p, _)| p).zip_eq(min_pt_states.iter()){
+        let vp = partition_graph.node_weight(p).unwrap();
+        for (i_sub, &i) in vp.iter().enumerate(){
+            stitched_state.arr[i as usize] = st[i_sub];
+        }
+    }
+    if let Some(kind) = params.quench{
+        let quench = QuenchResults::quench_states(instance, std::slice::from_mut(&mut stitched_state), kind);
+        info!("Post-quench total energy: {}", quench.post_energies[0]);
+        gla_results.quench = Some(quench);
+    }
+    gla_results.final_state = stitched_state.as_bytes();
+    return Ok((gla_results, stitched_state));
+}
+
+/// Write the results to the output file of the program
+pub fn write_results(prog: &Prog, gla_results: &GlaResults) -> Result<()>{
+    write_data(&prog.output_file, gla_results).with_context(
         || format!("Failed to write GLA data to {}", prog.output_file))?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,308 @@
+use std::time;
+
+use anyhow::Result;
+use log::info;
+use ndarray::prelude::*;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::ensembles as ens;
+use tamc_core::hrem::hamiltonian_exchange_sampler;
+use tamc_core::parallel::ensembles as pens;
+use tamc_core::pt::PTState;
+use tamc_core::traits::*;
+
+use crate::util::{run_in_pool, seeded_rng, split_rng, write_yaml};
+use crate::Prog;
+use crate::control::{self, StopReason};
+use crate::error::TamcError;
+use crate::ising::{BqmIsingInstance, IsingSampler, IsingState, rand_ising_state};
+use crate::ising_results::MinResults;
+use crate::validate::Validation;
+
+/// The reference Hamiltonian H_ref that is combined with the target instance H
+#[derive(Clone, Serialize, Deserialize)]
+pub enum HremReference{
+    /// Uniform ferromagnet with coupling -J on the coupling graph of the instance
+    Ferromagnet(f32),
+    /// Uniform field h on every spin
+    Field(f32),
+    /// Another instance file on the same spins, e.g. a constraint penalty
+    InstanceFile(String)
+}
+
+/// The control parameter of each rung of the ladder
+#[derive(Clone, Serialize, Deserialize)]
+pub enum HremLadder{
+    /// H(λ) = (1-λ) H_ref + λ H, interpolating from the reference to the target instance
+    Interpolate(Vec<f32>),
+    /// H(w) = H + w H_ref, e.g. tempering the weight w of a penalty Hamiltonian
+    Weighted(Vec<f32>)
+}
+
+impl HremLadder{
+    pub fn control_arr(&self) -> &Vec<f32>{
+        return match self{
+            HremLadder::Interpolate(v) => v,
+            HremLadder::Weighted(v) => v
+        };
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HremParams{
+    pub num_sweeps: u32,
+    pub warmup_fraction: f64,
+    pub beta: f32,
+    pub reference: HremReference,
+    pub ladder: HremLadder,
+    pub num_replica_chains: u32,
+    pub threads: u32
+}
+
+impl HremParams{
+    /// Check every parameter and their combinations before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.num_sweeps == 0{
+            v.error("num_sweeps", "num_sweeps must be positive", "set num_sweeps, e.g. to 2000");
+        }
+        if !(0.0..1.0).contains(&self.warmup_fraction){
+            v.error("warmup_fraction", format!("warmup_fraction must be in [0, 1), but is {}", self.warmup_fraction),
+                    "set warmup_fraction to e.g. 0.5");
+        }
+        if !(self.beta > 0.0 && self.beta.is_finite()){
+            v.error("beta", format!("beta must be positive and finite, but is {}", self.beta), "set beta to e.g. 1.0");
+        }
+        let control = self.ladder.control_arr();
+        if control.is_empty(){
+            v.error("ladder", "the ladder must have at least one rung", "add the control parameter of each rung");
+        } else if control.iter().any(|c| !c.is_finite()){
+            v.error("ladder", "every control parameter of the ladder must be finite", "remove the infinite or NaN rungs");
+        } else {
+            if control.len() == 1{
+                v.warn("ladder", "a single rung makes no Hamiltonian exchanges", "use at least two rungs");
+            }
+            if let HremLadder::Interpolate(lambdas) = &self.ladder{
+                let last = *lambdas.last().unwrap();
+                if last != 1.0{
+                    v.warn("ladder", format!("the last rung has λ = {}, so the recorded states are not those of the target instance", last),
+                           "end the interpolation ladder at 1.0");
+                }
+            }
+        }
+        if self.num_replica_chains == 0{
+            v.error("num_replica_chains", "at least one replica chain is required", "set num_replica_chains to e.g. 2");
+        }
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        } else if self.num_replica_chains > 0 && self.threads > self.num_replica_chains{
+            v.warn("threads", format!("{} threads exceed the {} replica chains", self.threads, self.num_replica_chains),
+                   format!("set threads to at most {}", self.num_replica_chains));
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HremMinResults{
+    pub params: HremParams,
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    pub acceptance_counts: Vec<u32>,
+    pub round_trips: u32,
+    pub stop_reason: Option<StopReason>,
+    /// Number of sweeps run before stopping
+    pub sweeps_completed: Option<u32>
+}
+
+impl HremMinResults{
+    fn new(params: HremParams, num_rungs: u32, instance_size: u32) -> Self{
+        let min_results = MinResults::new(num_rungs, instance_size);
+        let acceptance_counts = vec![0; num_rungs as usize];
+        return Self{params, min_results, acceptance_counts, round_trips: 0, stop_reason: None, sweeps_completed: None};
+    }
+}
+
+/// Replica exchange over a ladder of Hamiltonians at a fixed beta.
+/// The last rung of the ladder is the target Hamiltonian whose minimum energy states are recorded.
+pub struct HremRunner<'a>{
+    params: &'a HremParams,
+    rung_instances: Vec<BqmIsingInstance>,
+    meas_init: u32
+}
+
+impl<'a> HremRunner<'a>{
+    pub fn new(instance: &BqmIsingInstance, reference: &BqmIsingInstance, params: &'a HremParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        let rung_instances: Vec<BqmIsingInstance> = match &params.ladder{
+            HremLadder::Interpolate(lambdas) => lambdas.iter()
+                .map(|&l| BqmIsingInstance::linear_combination(reference, 1.0 - l, instance, l))
+                .collect::<Result<_, _>>()?,
+            HremLadder::Weighted(weights) => weights.iter()
+                .map(|&w| BqmIsingInstance::linear_combination(instance, 1.0, reference, w))
+                .collect::<Result<_, _>>()?
+        };
+        let meas_init = (params.warmup_fraction * (params.num_sweeps as f64)) as u32;
+        return Ok(Self{params, rung_instances, meas_init});
+    }
+
+    /// The instance of the target Hamiltonian at the last rung
+    pub fn target_instance(&self) -> &BqmIsingInstance{
+        return self.rung_instances.last().unwrap();
+    }
+
+    pub fn run(&self, initial_state: Option<Vec<PTState<IsingState>>>) -> Result<(HremMinResults, Vec<PTState<IsingState>>), TamcError>{
+        // seed and create random number generator
+        let mut rng = seeded_rng();
+        // randomly generate initial states
+        let mut hrem_state = match initial_state{
+            None => self.generate_init_state(&mut rng),
+            Some(st) => {
+                let num_rungs = self.rung_instances.len();
+                if let Some(chain) = st.iter().find(|chain| chain.states_ref().len() != num_rungs){
+                    return Err(TamcError::size_mismatch("initial states of a replica chain", num_rungs, chain.states_ref().len()));
+                }
+                st
+            }
+        };
+        let n = self.target_instance().size();
+        let samplers: Vec<_> = self.rung_instances.iter()
+            .map(|inst| IsingSampler::new(inst, self.params.beta, n as u32))
+            .collect();
+        let hrem_sampler = hamiltonian_exchange_sampler(samplers);
+        let mut hrem_results = if self.params.threads > 1{
+            let mut rng_vec = split_rng(&mut rng, hrem_state.len());
+            let chains_sampler = pens::ThreadedEnsembleSampler::new(hrem_sampler);
+            run_in_pool(self.params.threads, || self.hrem_loop(&chains_sampler, &mut hrem_state, &mut rng_vec))
+        } else {
+            let chains_sampler = ens::EnsembleSampler::new(hrem_sampler);
+            self.hrem_loop(&chains_sampler, &mut hrem_state, &mut rng)
+        };
+        let mut acceptance_counts = Array1::zeros(self.rung_instances.len());
+        for st in hrem_state.iter(){
+            acceptance_counts += &st.num_acceptances;
+            hrem_results.round_trips += st.round_trips;
+        }
+        hrem_results.acceptance_counts = acceptance_counts.into_raw_vec();
+        return Ok((hrem_results, hrem_state));
+    }
+
+    fn hrem_loop<S, Rn>(&self, chains_sampler: &S, hrem_state: &mut Vec<PTState<IsingState>>, rng: &mut Rn) -> HremMinResults
+    where S: Sampler<Rn, SampleType=Vec<PTState<IsingState>>>
+    {
+        let n = self.target_instance().size();
+        let num_rungs = self.rung_instances.len();
+        let mut hrem_results = HremMinResults::new(self.params.clone(), num_rungs as u32, n as u32);
+        let mut minimum_e = None;
+        info!("-- HREM begin");
+        let start = time::Instant::now();
+        hrem_results.stop_reason = Some(StopReason::Completed);
+        hrem_results.sweeps_completed = Some(self.params.num_sweeps);
+        for i in 0..self.params.num_sweeps{
+            chains_sampler.sweep(hrem_state, rng);
+            if i >= self.meas_init{
+                self.apply_measurements(i, hrem_state, &mut minimum_e, &mut hrem_results.min_results);
+            }
+            if control::interrupted(){
+                hrem_results.stop_reason = Some(StopReason::Interrupted);
+                hrem_results.sweeps_completed = Some(i + 1);
+                break;
+            }
+        }
+        let end = start.elapsed();
+        info!("-- HREM Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        hrem_results.min_results.timing = end.as_micros() as f64;
+
+        return hrem_results;
+    }
+
+    fn apply_measurements(&self, i: u32, hrem_state: &mut Vec<PTState<IsingState>>,
+                          minimum_e: &mut Option<f32>, hrem_results: &mut MinResults){
+        let target = self.target_instance();
+        let (min_e, min_state) = hrem_state.iter()
+            .map(|pts| {
+                let st = pts.states_ref().last().unwrap();
+                (target.energy_ref(st), st)
+            })
+            .min_by(|x, y| x.0.partial_cmp(&y.0).unwrap())
+            .unwrap();
+        hrem_results.num_measurements += 1;
+        if minimum_e.map_or(true, |x| min_e < x) {
+            *minimum_e = Some(min_e);
+            hrem_results.gs_states.push(min_state.as_u64_vec());
+            hrem_results.gs_energies.push(min_e);
+            hrem_results.gs_time_steps.push(i)
+        }
+    }
+
+    pub fn generate_init_state<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> Vec<PTState<IsingState>>{
+        let n = self.target_instance().size() as u32;
+        let mut hrem_state = Vec::new();
+        for _ in 0..self.params.num_replica_chains{
+            let init_states = self.rung_instances.iter()
+                .map(|inst| rand_ising_state(n, inst, rng))
+                .collect();
+            hrem_state.push(PTState::new(init_states));
+        }
+        return hrem_state;
+    }
+}
+
+/// Build the reference Hamiltonian. An instance file is read as a QUBO if `qubo` is set.
+pub fn read_reference_instance(instance: &BqmIsingInstance, reference: &HremReference, qubo: bool)
+        -> Result<BqmIsingInstance, TamcError>{
+    return match reference{
+        HremReference::Ferromagnet(j) => Ok(instance.ferromagnet_on_graph(*j)),
+        HremReference::Field(h) => Ok(BqmIsingInstance::uniform_field(instance.size(), *h)),
+        HremReference::InstanceFile(file) => BqmIsingInstance::from_instance_file(file, qubo)
+    };
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &HremMinResults) -> Result<()>{
+    println!("HREM Done.");
+    println!("** Ground state energy **");
+    if let Some(e) = results.min_results.gs_energies.last(){
+        println!("  e = {}", e);
+    }
+    println!("  round trips = {}", results.round_trips);
+    if let (Some(reason), Some(sweeps)) = (results.stop_reason, results.sweeps_completed){
+        if reason != StopReason::Completed{
+            println!("  stopped on {:?} after {} sweeps", reason, sweeps);
+        }
+    }
+    write_yaml(&prog.output_file, results, "HREM")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hrem::{HremLadder, HremParams, HremReference, HremRunner};
+    use crate::ising::BqmIsingInstance;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_ising_2d_hrem(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        let reference = BqmIsingInstance::uniform_field(l*l, 1.0);
+        let params = HremParams{
+            num_sweeps: 200,
+            warmup_fraction: 0.5,
+            beta: 2.0,
+            reference: HremReference::Field(1.0),
+            ladder: HremLadder::Interpolate(vec![0.0, 0.25, 0.5, 0.75, 1.0]),
+            num_replica_chains: 2,
+            threads: 1
+        };
+        let runner = HremRunner::new(&instance, &reference, &params).unwrap();
+        let (results, _) = with_seed(Some(1234), || runner.run(None)).unwrap();
+        let &e = results.min_results.gs_energies.last().unwrap();
+        println!("e = {}, round trips = {}", e, results.round_trips);
+        println!("acceptance counts: {:?}", results.acceptance_counts);
+        // The ferromagnetic ground state is reached from the aligned state of the field reference
+        assert_eq!(e, -2.0 * (l*l) as f32);
+    }
+}
@@ -35,8 +35,12 @@ use tamc_core::traits::*;
 
 use crate::{Instance, State};
 use crate::util::{read_adjacency_list_from_file, read_txt_vec};
+use crate::error::TamcError;
 use tamc_core::pt::PTState;
 use crate::pt::BetaOptions::Arr;
+use crate::pt::{PtIcmInstance, PtIcmState};
+use crate::descent::{DescentKind, QuenchResults};
+use crate::observe::{ising_observer, ObservableSpec, Observer};
 use tamc_core::util::monotonic_divisions;
 
 pub type Spin=i8;
@@ -114,6 +118,17 @@ impl IsingState{
         return Ok(())
     }
     
+    /// Read a state of `size` spins written by `write_to_bytes`
+    pub fn from_bytes(bytes: &[u8], size: u32) -> Result<Self, ()>{
+        let size = size as usize;
+        let num_bytes = size/8 + (if size%8 == 0{ 0 } else { 1 });
+        if num_bytes > bytes.len(){
+            return Err(())
+        }
+        let arr = (0..size).map(|i| if (bytes[i / 8] >> (i % 8)) & 1 != 0 { -1 } else { 1 }).collect();
+        return Ok(IsingState{arr, energy: 0.0, energy_init: false});
+    }
+
     pub fn as_bytes(&self) -> Vec<u8>{
         let mut bytes_vec: Vec<u8> =(&[0]).repeat(self.num_bytes());
         self.write_to_bytes(bytes_vec.as_mut_slice()).unwrap_or(());
@@ -198,6 +213,21 @@ impl State<u32> for IsingState{
     }
 }
 
+impl ReplicaState for IsingState{
+    #[inline]
+    fn site_differs(&self, other: &IsingState, i: u32) -> bool {
+        unsafe { self.uget(i) != other.uget(i) }
+    }
+    #[inline]
+    fn exchange_site(&mut self, other: &mut IsingState, i: u32) {
+        unsafe { std::mem::swap(self.arr.get_unchecked_mut(i as usize),  other.arr.get_unchecked_mut(i as usize)); }
+    }
+    /// Invalidate energy cache
+    fn sites_exchanged(&mut self) {
+        self.energy_init = false;
+    }
+}
+
 pub struct IsingSampler<'a>{
     pub samp: MetropolisSampler<'a, f32, u32, IsingState, BqmIsingInstance, Uniform<u32>>
 }
@@ -247,6 +277,21 @@ for IsingSampler<'a>{
     }
 }
 
+/// The reduced energy is always evaluated from scratch, since in Hamiltonian replica exchange
+/// a state may arrive from a sampler with a different instance
+impl<'a> ReducedEnergy<f32>
+for IsingSampler<'a>{
+    type Microstate = IsingState;
+
+    fn reduced_energy(&self, st: &mut IsingState) -> f32 {
+        return self.samp.beta * self.samp.instance.energy_ref(st);
+    }
+
+    fn state_received(&self, st: &mut IsingState) {
+        st.energy_init = false;
+    }
+}
+
 
 /// An Ising instance specified by an arbitrary binary quadratic model
 /// in sparse matrix form.
@@ -263,17 +308,18 @@ pub struct BqmIsingInstance{
 }
 
 impl BqmIsingInstance{
-    pub fn new_zero_bias(coupling: CsMat<f32>) -> Self{
+    pub fn new_zero_bias(coupling: CsMat<f32>) -> Result<Self, TamcError>{
         let (n1, n2) = coupling.shape();
         if n1 != n2{
-            panic!("couplings matrix must be square, but has shape {}, {}",n1, n2);
+            return Err(TamcError::size_mismatch("columns of the square coupling matrix", n1, n2));
         }
         let mut coupling_vecs = Vec::new();
         coupling_vecs.resize(n1, Vec::new());
         for (i, row)in coupling.outer_iterator().enumerate(){
             for (j, &K) in row.iter() {
                 if i == j{
-                    panic!("Expected a zero-bias Csr instance");
+                    return Err(TamcError::invalid_parameter(
+                        "coupling", format!("expected a zero-bias matrix, but found a bias on spin {}", i)));
                 }
                 coupling_vecs[i].push((j.to_u32().unwrap(), K as f32));
             }
@@ -281,11 +327,10 @@ impl BqmIsingInstance{
         let mut bias = Vec::new();
         bias.resize(n1, 0.0);
 
-        return Self{offset: 0.0, bias, coupling, coupling_vecs, suscept_coefs: Vec::new()};
+        return Ok(Self{offset: 0.0, bias, coupling, coupling_vecs, suscept_coefs: Vec::new()});
     }
-    pub fn from_instance_file(file: &str, qubo: bool) -> Self{
-        let adj_list = read_adjacency_list_from_file(file)
-            .expect("Unable to read adjancency from instance file");
+    pub fn from_instance_file(file: &str, qubo: bool) -> Result<Self, TamcError>{
+        let adj_list = read_adjacency_list_from_file(file)?;
         let n = adj_list.len();
         let mut offset = 0.0;
         let mut tri_mat = TriMat::new((n, n));
@@ -320,8 +365,58 @@ impl BqmIsingInstance{
             }
         }
         let coupling = tri_mat.to_csr();
-        return Self{offset, bias, coupling, coupling_vecs, suscept_coefs: Vec::new() };
+        return Ok(Self{offset, bias, coupling, coupling_vecs, suscept_coefs: Vec::new() });
+    }
+    /// Construct an instance from its offset, biases and couplings
+    fn from_parts(offset: f32, bias: Vec<f32>, tri_mat: TriMat<f32>) -> Self{
+        let coupling: CsMat<f32> = tri_mat.to_csr();
+        let n = bias.len();
+        let mut coupling_vecs = Vec::with_capacity(n);
+        coupling_vecs.resize(n, Vec::new());
+        for (i, row) in coupling.outer_iterator().enumerate(){
+            for (j, &K) in row.iter(){
+                coupling_vecs[i].push((j as u32, K));
+            }
+        }
+        return Self{offset, bias, coupling, coupling_vecs, suscept_coefs: Vec::new()};
+    }
+
+    /// The linear combination of Hamiltonians  w1 H_1 + w2 H_2  of two instances on the same spins.
+    /// The susceptibility coefficients of the first instance are kept.
+    pub fn linear_combination(inst1: &Self, w1: f32, inst2: &Self, w2: f32) -> Result<Self, TamcError>{
+        let n = inst1.size();
+        if inst2.size() != n{
+            return Err(TamcError::size_mismatch("linear combination of instances", n, inst2.size()));
+        }
+        let mut tri_mat = TriMat::new((n, n));
+        for (inst, w) in [(inst1, w1), (inst2, w2)]{
+            for (&K, (i, j)) in inst.coupling.iter(){
+                tri_mat.add_triplet(i, j, w * K);
+            }
+        }
+        let bias = inst1.bias.iter().zip_eq(inst2.bias.iter())
+            .map(|(&h1, &h2)| w1 * h1 + w2 * h2).collect();
+        let offset = w1 * inst1.offset + w2 * inst2.offset;
+        let mut me = Self::from_parts(offset, bias, tri_mat);
+        me.suscept_coefs = inst1.suscept_coefs.clone();
+        return Ok(me);
+    }
+
+    /// Uniform ferromagnet with coupling -j on the coupling graph of this instance
+    pub fn ferromagnet_on_graph(&self, j: f32) -> Self{
+        let n = self.size();
+        let mut tri_mat = TriMat::new((n, n));
+        for (_, (i1, i2)) in self.coupling.iter(){
+            tri_mat.add_triplet(i1, i2, -j);
+        }
+        return Self::from_parts(0.0, vec![0.0; n], tri_mat);
     }
+
+    /// Uniform field h on each of n spins, with no couplings
+    pub fn uniform_field(n: usize, h: f32) -> Self{
+        return Self::from_parts(0.0, vec![h; n], TriMat::new((n, n)));
+    }
+
     /// Create a new instance induced from a subgraph of this instance
     /// sub_nodes must be sorted
     pub fn induced_subgraph_instance_sorted(&self, sub_nodes: &Vec<u32>) -> Self{
@@ -385,21 +480,19 @@ impl BqmIsingInstance{
         return Self{offset, bias, coupling, coupling_vecs, suscept_coefs};
     }
 
-    pub fn with_suscept(self, suscept_files: &Vec<String>) -> Self{
+    pub fn with_suscept(self, suscept_files: &Vec<String>) -> Result<Self, TamcError>{
         let mut me = self;
         for file in suscept_files.iter(){
-            let f = File::open(file).expect("Unable to open susceptibility file");
-            let dvec = read_txt_vec(f)
-                .expect("Unable to read susceptibility coefficients from file");
+            let f = File::open(file).map_err(|e| TamcError::Io(e, file.to_string()))?;
+            let dvec = read_txt_vec(f).map_err(|e| e.in_file(file))?;
             let n1 = dvec.len();
             let n2 = me.bias.len();
             if n1 != n2{
-                println!("WARNING: Ignoring suscept file {} - Expected {} coefficients, but got {}",
-                         file, n2, n1)
+                return Err(TamcError::size_mismatch(&format!("susceptibility coefficients of {}", file), n2, n1));
             }
             me.suscept_coefs.push(dvec)
         }
-        return me;
+        return Ok(me);
     }
 
     pub fn to_csr_graph(&self) -> Csr<(), ()>{
@@ -484,6 +577,72 @@ impl Instance<u32, IsingState> for BqmIsingInstance {
     }
 }
 
+impl GraphInstance for BqmIsingInstance{
+    fn num_sites(&self) -> usize {
+        return self.bias.len();
+    }
+
+    fn for_each_neighbor<F: FnMut(u32)>(&self, i: u32, mut f: F) {
+        for &(j, _) in self.coupling_vecs[i as usize].iter(){
+            f(j);
+        }
+    }
+}
+
+impl PtIcmState for IsingState{
+    fn overlap(&self, other: &IsingState) -> i64 {
+        return IsingState::overlap(self, other);
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        return IsingState::as_bytes(self);
+    }
+
+    fn as_u64_vec(&self) -> Vec<u64> {
+        return IsingState::as_u64_vec(self);
+    }
+
+    fn reset_energy(&mut self) {
+        self.energy_init = false;
+    }
+}
+
+impl<'a> PtIcmInstance<'a> for BqmIsingInstance{
+    type State = IsingState;
+    type Sampler = IsingSampler<'a>;
+
+    fn sampler(&'a self, beta: f32) -> IsingSampler<'a> {
+        return IsingSampler::new(self, beta, self.size() as u32);
+    }
+
+    fn random_state<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> IsingState {
+        return rand_ising_state(self.size() as u32, self, rng);
+    }
+
+    fn replica_energy(&self, state: &mut IsingState) -> f32 {
+        return self.energy(state);
+    }
+
+    fn num_suscepts(&self) -> usize {
+        return self.suscept_coefs.len();
+    }
+
+    fn suscepts(&self, state1: &IsingState, state2: &IsingState, chi: &mut [f64]) {
+        let overlap_vec: Vec<Spin> = state1.arr.iter().zip_eq(state2.arr.iter())
+            .map(|(&s1, &s2)| s1 * s2).collect();
+        for (k, chi_k) in chi.iter_mut().enumerate(){
+            *chi_k = self.suscept(&overlap_vec, k);
+        }
+    }
+
+    fn quench(&self, states: &[Vec<u64>], kind: DescentKind) -> Option<QuenchResults> {
+        return Some(QuenchResults::quench_u64_states(self, states, kind));
+    }
+
+    fn builtin_observer(spec: &ObservableSpec) -> Option<Box<dyn Observer<Self, IsingState>>> {
+        return Some(ising_observer(spec));
+    }
+}
 
 
 #[cfg(test)]
@@ -515,7 +674,7 @@ pub(crate) mod tests {
             }
         }
 
-        let instance = BqmIsingInstance::new_zero_bias(tri_mat.to_csr());
+        let instance = BqmIsingInstance::new_zero_bias(tri_mat.to_csr()).unwrap();
         return instance;
     }
     #[test]
@@ -535,10 +694,13 @@ pub(crate) mod tests {
             init_states.push(rand_ising_state(n, &instance, &mut rng));
         }
 
-        let beta_schedule = geometric_beta_schedule(beta0, betaf, num_sweeps);
+        let beta_schedule: Vec<f32> = geometric_beta_schedule(beta0, betaf, num_sweeps)
+            .into_iter().map(|b| b as f32).collect();
+        let sampler = MetropolisSampler::new_uniform(&instance, beta_schedule[0], n);
 
         //sampler.advance();
-        let mut states = simulated_annealing(&instance, init_states, &beta_schedule, &mut rng, |_i, _|{} );
+        let mut states = init_states;
+        simulated_annealing(sampler, &mut states, &beta_schedule, &mut rng, |_i, _|{} );
         for st in states.iter_mut(){
             let mz = st.mag();
             let e = instance.energy(st);
@@ -25,4 +25,16 @@ impl MinResults{
             //final_state: Vec::new()
         };
     }
+
+    /// Append a state to the ground state trace if its energy is lower than every state so far.
+    /// `state` is only evaluated for a new minimum.
+    pub fn record<F: FnOnce() -> Vec<u64>>(&mut self, energy: f32, step: u32, state: F) -> bool{
+        if self.gs_energies.last().map_or(false, |&e| energy >= e){
+            return false;
+        }
+        self.gs_energies.push(energy);
+        self.gs_states.push(state());
+        self.gs_time_steps.push(step);
+        return true;
+    }
 }
\ No newline at end of file
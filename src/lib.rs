@@ -32,14 +32,64 @@ pub mod percolation;
 pub mod pt;
 pub mod sa;
 pub mod gla;
+pub mod hrem;
+pub mod st;
+pub mod descent;
+pub mod tabu;
+pub mod eo;
+pub mod memetic;
+pub mod bp;
+pub mod mft;
+pub mod control;
+pub mod error;
+pub mod validate;
+pub mod observe;
+pub mod progress;
+pub mod portfolio;
+pub mod pipeline;
+pub mod sweep;
+pub mod batch;
+pub mod warmstart;
 pub mod ising_results;
 use std::fs::File;
 use crate::pt::PtIcmParams;
-use std::fmt;
 use std::path::Path;
 use crate::gla::GlaParams;
 use crate::ising::BqmIsingInstance;
 use crate::sa::SaParams;
+use crate::progress::ProgressReporter;
+use crate::warmstart::{Assignment, InitialStates};
+use crate::hrem::HremParams;
+use crate::st::StParams;
+use crate::descent::DescentParams;
+use crate::tabu::TabuParams;
+use crate::eo::EoParams;
+use crate::memetic::MemeticParams;
+use crate::bp::BpParams;
+use crate::mft::MftParams;
+use crate::portfolio::PortfolioParams;
+use crate::pipeline::PipelineParams;
+use crate::sweep::SweepParams;
+use crate::pt::PtIcmMinResults;
+use crate::sa::AnnealMinResults;
+use crate::descent::DescentMinResults;
+use crate::tabu::TabuMinResults;
+use crate::eo::EoMinResults;
+use crate::memetic::MemeticMinResults;
+use crate::gla::GlaResults;
+use crate::hrem::HremMinResults;
+use crate::st::StMinResults;
+use crate::bp::BpResults;
+use crate::mft::MftMinResults;
+use crate::portfolio::PortfolioResults;
+use crate::pipeline::PipelineResults;
+use crate::sweep::SweepResults;
+use crate::pt::PtIcmThermalSamples;
+use crate::ising::IsingState;
+use crate::control::ControlHandle;
+use crate::error::TamcError;
+use crate::validate::Validation;
+use log::info;
 
 #[derive(Serialize, Deserialize)]
 pub struct PTOptions{
@@ -47,40 +97,282 @@ pub struct PTOptions{
     pub beta_points: Vec<f64>
 }
 
-#[derive(Debug)]
-pub enum PTError {
-    IoError(std::io::Error, String),
-    MethodParse(Box<dyn Error + 'static>, String)
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Method{
+    PT(PtIcmParams),
+    SA(SaParams),
+    GLA(GlaParams),
+    HREM(HremParams),
+    ST(StParams),
+    Descent(DescentParams),
+    Tabu(TabuParams),
+    EO(EoParams),
+    Memetic(MemeticParams),
+    BP(BpParams),
+    MFT(MftParams),
+    Portfolio(PortfolioParams),
+    Pipeline(PipelineParams),
+    Sweep(SweepParams)
 }
 
-impl fmt::Display for PTError{
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl Method{
+    /// Validate the parameters of the method, including the stages of a pipeline and the configurations of a sweep
+    pub fn validate(&self) -> Validation{
+        return match self{
+            Method::PT(params) => params.validate(),
+            Method::SA(params) => params.validate(),
+            Method::GLA(params) => params.validate(),
+            Method::HREM(params) => params.validate(),
+            Method::ST(params) => params.validate(),
+            Method::Descent(params) => params.validate(),
+            Method::Tabu(params) => params.validate(),
+            Method::EO(params) => params.validate(),
+            Method::Memetic(params) => params.validate(),
+            Method::BP(params) => params.validate(),
+            Method::MFT(params) => params.validate(),
+            Method::Portfolio(params) => params.validate(),
+            Method::Pipeline(params) => params.validate(),
+            Method::Sweep(params) => params.validate()
+        };
+    }
+
+    pub fn name(&self) -> &'static str{
+        match self{
+            Method::PT(_) => "PT",
+            Method::SA(_) => "SA",
+            Method::GLA(_) => "GLA",
+            Method::HREM(_) => "HREM",
+            Method::ST(_) => "ST",
+            Method::Descent(_) => "Descent",
+            Method::Tabu(_) => "Tabu",
+            Method::EO(_) => "EO",
+            Method::Memetic(_) => "Memetic",
+            Method::BP(_) => "BP",
+            Method::MFT(_) => "MFT",
+            Method::Portfolio(_) => "Portfolio",
+            Method::Pipeline(_) => "Pipeline",
+            Method::Sweep(_) => "Sweep"
+        }
+    }
+
+    /// Number of threads the method runs on, as set by its parameters
+    pub fn threads(&self) -> u32{
+        match self{
+            Method::PT(params) => params.threads,
+            Method::SA(params) => params.threads,
+            Method::GLA(params) => params.num_threads,
+            Method::HREM(params) => params.threads,
+            Method::ST(params) => params.threads,
+            Method::Descent(params) => params.threads,
+            Method::Tabu(params) => params.threads,
+            Method::EO(params) => params.threads,
+            Method::Memetic(params) => params.threads,
+            Method::BP(_) => 1,
+            Method::MFT(params) => params.threads,
+            Method::Portfolio(params) => params.threads,
+            Method::Pipeline(params) => params.stages.iter().map(|m| m.threads()).max().unwrap_or(1),
+            Method::Sweep(params) => params.configurations()
+                .map_or(1, |points| points.iter().map(|p| p.method.threads()).max().unwrap_or(1))
+        }
+    }
+
+    /// Limit the method to `threads` threads, e.g. to fit it into a share of a larger thread budget.
+    /// Replicas are split evenly between threads, so PT and SA use at most one thread per replica.
+    /// The threads of a sweep are set by its own method and grid and are left as they are.
+    pub fn set_threads(&mut self, threads: u32){
+        let threads = threads.max(1);
         match self{
-            PTError::IoError(e, s) => {
-                write!(f, "Could not open '{}'", s)
-            },
-            PTError::MethodParse(e, s) =>{
-                writeln!(f, "Failed to parse '{}' as valid input", s)?;
-                write!(f, "{}", e.as_ref())
+            Method::PT(params) => {
+                let num_replicas = params.num_replica_chains * params.beta.get_beta_arr().len() as u32;
+                params.threads = threads.min(num_replicas).max(1)
             }
+            Method::SA(params) => params.threads = threads.min(params.num_replicas).max(1),
+            Method::GLA(params) => {
+                params.num_threads = threads;
+                params.pt_params.threads = params.pt_params.threads.min(threads);
+            }
+            Method::HREM(params) => params.threads = threads,
+            Method::ST(params) => params.threads = threads,
+            Method::Descent(params) => params.threads = threads,
+            Method::Tabu(params) => params.threads = threads,
+            Method::EO(params) => params.threads = threads,
+            Method::Memetic(params) => params.threads = threads,
+            Method::BP(_) => {},
+            Method::MFT(params) => params.threads = threads,
+            Method::Portfolio(params) => params.threads = threads,
+            Method::Pipeline(params) => params.stages.iter_mut().for_each(|m| m.set_threads(threads)),
+            Method::Sweep(_) => {}
         }
     }
 }
 
-impl std::error::Error for PTError{
-    fn source(&self) -> Option<&(dyn Error + 'static)>{
+/// Ground state results of a method run
+#[derive(Clone, Serialize, Deserialize)]
+pub enum MethodResults{
+    PT(PtIcmMinResults),
+    SA(AnnealMinResults),
+    GLA(GlaResults),
+    HREM(HremMinResults),
+    ST(StMinResults),
+    Descent(DescentMinResults),
+    Tabu(TabuMinResults),
+    EO(EoMinResults),
+    Memetic(MemeticMinResults),
+    BP(BpResults),
+    MFT(MftMinResults),
+    Portfolio(PortfolioResults),
+    Pipeline(PipelineResults),
+    Sweep(SweepResults)
+}
+
+impl MethodResults{
+    /// The lowest energy found, if any
+    pub fn min_energy(&self) -> Option<f32>{
+        match self{
+            MethodResults::PT(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::SA(r) => r.energies.iter().copied().reduce(f32::min),
+            MethodResults::GLA(r) => Some(r.quench.as_ref().map_or(r.total_energy, |q| q.post_energies[0])),
+            MethodResults::HREM(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::ST(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::Descent(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::Tabu(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::EO(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::Memetic(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::BP(r) => r.decimation.as_ref()
+                .and_then(|d| d.min_results.gs_energies.last().copied()),
+            MethodResults::MFT(r) => r.min_results.gs_energies.last().copied(),
+            MethodResults::Portfolio(r) => r.best.map(|b| b.energy),
+            MethodResults::Pipeline(r) => r.min_energy,
+            MethodResults::Sweep(r) => r.min_energy
+        }
+    }
+
+    /// The lowest energy state recorded in the results, if any.
+    /// SA and GLA do not record states in their results.
+    pub fn gs_state(&self) -> Option<Vec<u64>>{
         match self{
-            PTError::IoError(e, _) => Some(e),
-            PTError::MethodParse(e, _) => Some(e.as_ref())
+            MethodResults::PT(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::SA(_) | MethodResults::GLA(_) => None,
+            MethodResults::HREM(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::ST(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::Descent(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::Tabu(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::EO(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::Memetic(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::BP(r) => r.decimation.as_ref()
+                .and_then(|d| d.min_results.gs_states.last().cloned()),
+            MethodResults::MFT(r) => r.min_results.gs_states.last().cloned(),
+            MethodResults::Portfolio(r) => r.gs_state.clone(),
+            MethodResults::Pipeline(r) => r.gs_state.clone(),
+            MethodResults::Sweep(r) => r.gs_state.clone()
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum Method{
-    PT(PtIcmParams),
-    SA(SaParams),
-    GLA(GlaParams)
+/// Options of a library run of a method
+#[derive(Clone, Copy, Default)]
+pub struct RunOptions<'a>{
+    /// Stop condition shared with other runs, e.g. by a portfolio.
+    /// Used by PT, SA, Descent, Tabu, EO and Memetic.
+    pub control: Option<ControlHandle<'a>>,
+    /// Periodic progress reports. Used by PT and SA.
+    pub progress: Option<&'a ProgressReporter>,
+    /// Read instance files referenced by the method (the HREM reference) as QUBOs
+    pub qubo: bool,
+    /// States to start the replicas from. Used by PT and SA.
+    pub initial_states: Option<&'a InitialStates>
+}
+
+/// Results of a library run of a method
+pub struct RunResults{
+    pub results: MethodResults,
+    /// Thermal samples of a PT run
+    pub samples: Option<PtIcmThermalSamples>,
+    /// Final states handed off by the method.
+    /// PT returns the lowest temperature state of each replica chain, SA the final state of each replica,
+    /// and GLA the stitched state.
+    pub final_states: Option<Vec<IsingState>>
+}
+
+impl RunResults{
+    /// The lowest energy state recorded in the results, or else the lowest energy final state
+    pub fn gs_state(&self, instance: &BqmIsingInstance) -> Option<Vec<u64>>{
+        return self.results.gs_state().or_else(||
+            self.final_states.as_ref()?.iter()
+                .map(|st| (instance.energy_ref(st), st))
+                .min_by(|x, y| x.0.partial_cmp(&y.0).unwrap())
+                .map(|(_, st)| st.as_u64_vec())
+        );
+    }
+}
+
+/// Run a method on an instance.
+/// No files are written and no logger is initialized, so this can be called from other programs.
+/// Invalid parameters and unreadable reference files are returned as errors.
+pub fn solve(instance: &BqmIsingInstance, method: &Method, options: &RunOptions) -> Result<RunResults, TamcError>{
+    let validation = method.validate();
+    validation.log_warnings();
+    validation.into_result()?;
+    if options.initial_states.is_some() && !matches!(method, Method::PT(_) | Method::SA(_)){
+        return Err(TamcError::invalid_parameter("initial_states",
+            format!("{} does not start from initial states, only PT and SA do", method.name())));
+    }
+    let control = options.control;
+    let mut samples = None;
+    let mut final_states = None;
+    let results = match method{
+        Method::PT(params) => {
+            let (r, samp, pt_state) = pt::solve_pt(instance, params, control, options.progress, options.initial_states)?;
+            samples = Some(samp);
+            final_states = Some(pt_state.iter().map(|pts| pts.states_ref().last().unwrap().clone()).collect());
+            MethodResults::PT(r)
+        }
+        Method::SA(params) => {
+            let (r, states) = sa::solve_sa(instance, params, control, options.progress, options.initial_states)?;
+            final_states = Some(states);
+            MethodResults::SA(r)
+        }
+        Method::GLA(params) => {
+            let (r, state) = gla::solve_gla(instance, params)?;
+            final_states = Some(vec![state]);
+            MethodResults::GLA(r)
+        }
+        Method::HREM(params) => {
+            let reference = hrem::read_reference_instance(instance, &params.reference, options.qubo)?;
+            let (r, _) = hrem::HremRunner::new(instance, &reference, params)?.run(None)?;
+            MethodResults::HREM(r)
+        }
+        Method::ST(params) => {
+            let (r, _) = st::StRunner::new(instance, params)?.run(None)?;
+            MethodResults::ST(r)
+        }
+        Method::Descent(params) => {
+            let runner = descent::DescentRunner::new(instance, params)?;
+            let runner = match control { Some(c) => runner.with_control(c), None => runner };
+            MethodResults::Descent(runner.run()?)
+        }
+        Method::Tabu(params) => {
+            let runner = tabu::TabuRunner::new(instance, params)?;
+            let runner = match control { Some(c) => runner.with_control(c), None => runner };
+            MethodResults::Tabu(runner.run()?)
+        }
+        Method::EO(params) => {
+            let runner = eo::EoRunner::new(instance, params)?;
+            let runner = match control { Some(c) => runner.with_control(c), None => runner };
+            MethodResults::EO(runner.run()?)
+        }
+        Method::Memetic(params) => {
+            let runner = memetic::MemeticRunner::new(instance, params)?;
+            let runner = match control { Some(c) => runner.with_control(c), None => runner };
+            MethodResults::Memetic(runner.run()?)
+        }
+        Method::BP(params) => MethodResults::BP(bp::BpRunner::new(instance, params)?.run()?),
+        Method::MFT(params) => MethodResults::MFT(mft::MftRunner::new(instance, params)?.run()?),
+        Method::Portfolio(params) => MethodResults::Portfolio(portfolio::PortfolioRunner::new(instance, params)?.run()?),
+        Method::Pipeline(params) => MethodResults::Pipeline(pipeline::PipelineRunner::new(instance, params)?.run()?),
+        Method::Sweep(params) => MethodResults::Sweep(sweep::SweepRunner::new(instance, params)?.run(options)?)
+    };
+    return Ok(RunResults{results, samples, final_states});
 }
 
 #[derive(StructOpt)]
@@ -93,41 +385,229 @@ pub struct Prog{
     #[structopt(long)]
     pub sample_output: Option<String>,
     #[structopt(long)]
-    pub qubo: bool
+    pub qubo: bool,
+    /// Report the progress of PT and SA runs to stderr every given number of seconds
+    #[structopt(long)]
+    pub progress: Option<f64>,
+    /// Write the progress reports as JSON lines to this file instead
+    /// (every 10 seconds unless --progress is given)
+    #[structopt(long)]
+    pub progress_file: Option<String>,
+    /// Start the PT and SA replicas from the states in this file: SA states (.bin or .pkl),
+    /// the gs_states of PT results (.yml) or lines of 0 (spin +1) and 1 (spin -1)
+    #[structopt(long)]
+    pub initial_states: Option<String>,
+    /// Start every replica from the lowest energy initial state (broadcast)
+    /// or from each initial state in turn (distribute)
+    #[structopt(long, default_value = "distribute")]
+    pub initial_assignment: Assignment,
+    /// Fraction of spins flipped at random in every further copy of an initial state
+    #[structopt(long, default_value = "0")]
+    pub initial_perturbation: f64
 }
 
 impl Prog{
-    pub fn read_instance(&self) -> BqmIsingInstance{
+    pub fn read_instance(&self) -> Result<BqmIsingInstance, TamcError>{
         let instance_file = &self.instance_file;
-        let instance = ising::BqmIsingInstance::from_instance_file(&instance_file, self.qubo);
-        return instance;
+        let instance = ising::BqmIsingInstance::from_instance_file(&instance_file, self.qubo)?;
+        return Ok(instance);
     }
-    pub fn read_method(&self) -> Result<Method, Box<dyn Error>>{
-        let method_file = &self.method_file;
-        let yaml_str = std::fs::read_to_string(&method_file)
-            .map_err(|e| PTError::IoError(e, method_file.to_string() ))?;
-        let opts: Method = serde_yaml::from_str(&yaml_str)
-            .map_err(|e| PTError::MethodParse(Box::new(e), method_file.to_string()))?;
-
-        return Ok(opts);
+    pub fn read_method(&self) -> Result<Method, TamcError>{
+        return read_method_file(&self.method_file);
+    }
+    pub fn progress_reporter(&self) -> Result<Option<ProgressReporter>, TamcError>{
+        return match (&self.progress_file, self.progress){
+            (Some(file), interval) => Ok(Some(ProgressReporter::json_lines(interval.unwrap_or(10.0), file)?)),
+            (None, Some(interval)) => Ok(Some(ProgressReporter::stderr(interval))),
+            (None, None) => Ok(None)
+        };
+    }
+    pub fn read_initial_states(&self, instance: &BqmIsingInstance) -> Result<Option<InitialStates>, TamcError>{
+        return match &self.initial_states{
+            Some(file) => Ok(Some(InitialStates::read(file, instance.size() as u32)?
+                .with_assignment(self.initial_assignment)
+                .with_perturbation(self.initial_perturbation)?)),
+            None => Ok(None)
+        };
     }
 }
 
+/// Read a method specification from a YAML file.
+/// A method with inline lists of values, e.g. `num_sweeps: [1000, 2000]`, is read as a sweep over them
+pub fn read_method_file(method_file: &str) -> Result<Method, TamcError>{
+    let yaml_str = std::fs::read_to_string(&method_file)
+        .map_err(|e| TamcError::Io(e, method_file.to_string() ))?;
+    let opts: Method = match serde_yaml::from_str(&yaml_str){
+        Ok(opts) => opts,
+        Err(e) => {
+            let inline = serde_yaml::from_str(&yaml_str).ok().and_then(|v| sweep::inline_sweep(&v));
+            match inline{
+                Some(params) => Method::Sweep(params),
+                None => return Err(TamcError::MethodParse(Box::new(e), method_file.to_string()))
+            }
+        }
+    };
 
-pub fn run_program(prog: Prog) -> Result<(), Box<dyn Error>>{
-    let opts = prog.read_method()?;
+    return Ok(opts);
+}
 
-    match &opts{
-        Method::PT(pt_params) => {
-            pt::run_parallel_tempering(&prog, &pt_params);
+/// Print the title and main parameters of a method run
+fn print_header(instance: &BqmIsingInstance, method: &Method){
+    match method{
+        Method::PT(params) => {
+            println!(" ** Parallel Tempering - ICM **");
+            info!("Number of sweeps: {}", params.num_sweeps);
+            if params.icm {
+                info!("Using ICM")
+            } else{
+                info!("ICM Disabled")
+            }
         }
-        Method::SA(sa_params) => {
-            sa::run_simulated_annealing(&prog, &sa_params);
+        Method::SA(_) => info!(" ** Simulated Annealing **"),
+        Method::GLA(_) => info!(" ** Running GLA with PT-ICM sub-solvers ..."),
+        Method::HREM(params) => {
+            println!(" ** Hamiltonian Replica Exchange **");
+            info!("Number of sweeps: {}", params.num_sweeps);
+            info!("Control parameters:\n\t {:5.4?}", params.ladder.control_arr());
         }
-        Method::GLA(gla_params) =>{
-            gla::run_gla(&prog, &gla_params)?;
+        Method::ST(params) => {
+            println!(" ** Simulated Tempering **");
+            info!("Number of sweeps: {}", params.num_sweeps);
         }
+        Method::Descent(params) => {
+            println!(" ** Descent ({:?}) **", params.kind);
+            info!("Number of restarts: {}", params.num_restarts);
+        }
+        Method::Tabu(params) => {
+            println!(" ** Tabu Search **");
+            info!("Number of restarts: {}", params.num_restarts);
+            info!("Tenure: {}", params.tenure);
+        }
+        Method::EO(params) => {
+            println!(" ** Extremal Optimization **");
+            info!("tau = {}", params.tau);
+            info!("Number of updates: {}", params.num_updates);
+            info!("Number of restarts: {}", params.num_restarts);
+        }
+        Method::Memetic(params) => {
+            println!(" ** Memetic Algorithm **");
+            info!("Population size: {}", params.population_size);
+            info!("Number of generations: {}", params.num_generations);
+        }
+        Method::BP(_) => println!(" ** Belief Propagation **"),
+        Method::MFT(params) => {
+            if params.tap{
+                println!(" ** TAP Annealing **");
+            } else {
+                println!(" ** Mean-Field Annealing **");
+            }
+            info!("Number of initializations: {}", params.num_inits);
+        }
+        Method::Portfolio(params) => {
+            println!(" ** Portfolio **");
+            if let Ok(runner) = portfolio::PortfolioRunner::new(instance, params){
+                for (method, threads) in params.members.iter().zip(runner.thread_shares()){
+                    info!("Member {} with {} threads", method.name(), threads);
+                }
+            }
+        }
+        Method::Pipeline(params) => {
+            println!(" ** Pipeline **");
+            let names: Vec<&str> = params.stages.iter().map(|m| m.name()).collect();
+            info!("Stages: {}", names.join(" -> "));
+        }
+        Method::Sweep(params) => {
+            println!(" ** Parameter Sweep **");
+            if let Ok(points) = params.configurations(){
+                info!("{} configurations of {}", points.len(), points.first().map_or("", |p| p.method.name()));
+            }
+        }
+    }
+}
+
+pub fn run_program(prog: Prog) -> Result<(), Box<dyn Error>>{
+    let method = prog.read_method()?;
+    // fail on invalid parameters before reading the instance
+    method.validate().into_result()?;
+    let mut logger = simple_logger::SimpleLogger::new().with_level(log::LevelFilter::Info).env();
+    if let Method::GLA(_) = method{
+        logger = logger.with_module_level("tamc::pt", log::LevelFilter::Off);
+    }
+    logger.init()?;
+    let mut instance = prog.read_instance()?;
+    if prog.suscepts.len() > 0{
+        instance = instance.with_suscept(&prog.suscepts)?;
+    }
+    let progress = prog.progress_reporter()?;
+    let initial_states = prog.read_initial_states(&instance)?;
+    print_header(&instance, &method);
+    let run_results = solve(&instance, &method, &RunOptions{qubo: prog.qubo, progress: progress.as_ref(),
+                            initial_states: initial_states.as_ref(), ..Default::default()})?;
+    write_run_results(&prog, &run_results)?;
+    Ok(())
+}
+
+/// Print a summary of the results of a run and write them to the output files of the program
+pub fn write_run_results(prog: &Prog, run_results: &RunResults) -> anyhow::Result<()>{
+    match &run_results.results{
+        MethodResults::PT(r) => pt::write_results(prog, r, run_results.samples.as_ref().unwrap())?,
+        MethodResults::SA(r) => sa::write_results(prog, r, run_results.final_states.as_ref().unwrap())?,
+        MethodResults::GLA(r) => gla::write_results(prog, r)?,
+        MethodResults::HREM(r) => hrem::write_results(prog, r)?,
+        MethodResults::ST(r) => st::write_results(prog, r)?,
+        MethodResults::Descent(r) => descent::write_results(prog, r)?,
+        MethodResults::Tabu(r) => tabu::write_results(prog, r)?,
+        MethodResults::EO(r) => eo::write_results(prog, r)?,
+        MethodResults::Memetic(r) => memetic::write_results(prog, r)?,
+        MethodResults::BP(r) => bp::write_results(prog, r)?,
+        MethodResults::MFT(r) => mft::write_results(prog, r)?,
+        MethodResults::Portfolio(r) => portfolio::write_results(prog, r)?,
+        MethodResults::Pipeline(r) => pipeline::write_results(prog, r)?,
+        MethodResults::Sweep(r) => sweep::write_results(prog, r)?
     };
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{Method, RunOptions, solve};
+    use crate::ising::IsingState;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pt::BetaOptions;
+    use crate::sa::SaParams;
+    use crate::tabu::TabuParams;
+    use tamc_core::traits::*;
+
+    #[test]
+    fn test_ising_2d_solve(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        let tabu = Method::Tabu(TabuParams{
+            num_iterations: 1000, tenure: 8, num_restarts: 8, num_elite: 2,
+            perturbation_fraction: 0.1, threads: 2
+        });
+        let run_results = solve(&instance, &tabu, &RunOptions::default()).unwrap();
+        let e = run_results.results.min_energy().unwrap();
+        let gs_state = run_results.gs_state(&instance).unwrap();
+        let st = IsingState::from_u64_vec(&gs_state, (l*l) as u32).unwrap();
+        assert_eq!(instance.energy_ref(&st), e);
+        assert!(run_results.samples.is_none());
+
+        let sa = Method::SA(SaParams{
+            beta: BetaOptions::new_geometric(0.1, 5.0, 64),
+            num_replicas: 4,
+            threads: 2,
+            ais: None,
+            quench: None,
+            time_limit: None,
+            target_energy: None,
+            observables: None
+        });
+        let run_results = solve(&instance, &sa, &RunOptions::default()).unwrap();
+        // SA results only record the energies, so the ground state comes from the final states
+        assert_eq!(run_results.final_states.as_ref().unwrap().len(), 4);
+        let gs_state = run_results.gs_state(&instance).unwrap();
+        let st = IsingState::from_u64_vec(&gs_state, (l*l) as u32).unwrap();
+        assert_eq!(instance.energy_ref(&st), run_results.results.min_energy().unwrap());
+    }
+}
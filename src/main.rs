@@ -1,12 +1,29 @@
 use tamc::ising::BqmIsingInstance;
 use tamc::util::connectivity_list::parse_line;
 use tamc::{Prog, run_program};
+use tamc::control;
+use tamc::batch::{BatchProg, run_batch};
 use structopt::StructOpt;
 
 
 fn main() -> Result<(), Box<dyn std::error::Error>>{
-    let opts: Prog = StructOpt::from_args();
-    let result = run_program(opts);
+    control::install_interrupt_handler()?;
+    let result = if std::env::args().nth(1).as_deref() == Some("batch"){
+        // tamc batch [OPTIONS] <manifest>
+        let opts = BatchProg::from_iter(std::env::args().skip(1));
+        run_batch(opts)
+    } else {
+        let opts: Prog = StructOpt::from_args();
+        run_program(opts)
+    };
+    if control::interrupted(){
+        if let Err(e) = &result{
+            eprintln!("tamc terminated with an error:\n{}", e);
+        }
+        eprintln!("tamc was interrupted and wrote partial results");
+        eprintln!(" * * * * * * ");
+        std::process::exit(control::INTERRUPTED_EXIT_CODE);
+    }
     result.map_err(|e| {
         eprintln!("tamc terminated with an error:\n{}", &e);
         eprintln!(" * * * * * * ");
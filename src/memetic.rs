@@ -0,0 +1,296 @@
+use std::time;
+
+use anyhow::Result;
+use log::info;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::icm::{ClusterWorkspace, HoudayerOptions, HoudayerSampler};
+use tamc_core::metropolis::MetropolisSampler;
+use tamc_core::sa::simulated_annealing;
+use tamc_core::traits::*;
+
+use crate::util::{run_in_pool, seeded_rng, split_rng, split_work, write_yaml};
+use crate::Prog;
+use crate::error::TamcError;
+use crate::control::{self, ControlHandle};
+use crate::descent::{descend, DescentKind};
+use crate::ising::{BqmIsingInstance, IsingState, rand_ising_state};
+use crate::ising_results::MinResults;
+use crate::pt::BetaOptions;
+use crate::validate::Validation;
+
+/// Recombination of a pair of parents into a pair of children
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Crossover{
+    /// Exchange a connected cluster of the spins that differ between the parents,
+    /// as in the Houdayer cluster move
+    Houdayer,
+    /// Exchange each differing spin independently with probability 1/2
+    Uniform
+}
+
+/// Local search applied to every child
+#[derive(Clone, Serialize, Deserialize)]
+pub enum LocalSearch{
+    Descent(DescentKind),
+    /// A short Metropolis anneal along the beta schedule, followed by greedy descent
+    Anneal(BetaOptions)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MemeticParams{
+    pub population_size: u32,
+    pub num_generations: u32,
+    /// Number of parent pairs recombined in each generation
+    pub num_offspring_pairs: u32,
+    pub crossover: Crossover,
+    pub local_search: LocalSearch,
+    pub threads: u32
+}
+
+impl MemeticParams{
+    /// Check every parameter before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.population_size < 2{
+            v.error("population_size", "the population must have at least two members", "set population_size to e.g. 32");
+        }
+        if self.num_generations == 0{
+            v.error("num_generations", "num_generations must be positive", "set num_generations, e.g. to 1000");
+        }
+        if self.num_offspring_pairs == 0{
+            v.error("num_offspring_pairs", "at least one parent pair must be recombined in each generation",
+                    "set num_offspring_pairs to e.g. half of the population size");
+        }
+        if let LocalSearch::Anneal(beta) = &self.local_search{
+            beta.validate("local_search.Anneal", &mut v);
+        }
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MemeticMinResults{
+    pub params: MemeticParams,
+    /// The ground state trace is indexed by generation
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    /// Mean energy of the population after each generation
+    pub mean_energies: Vec<f32>,
+    /// Number of children accepted into the population in each generation
+    pub num_replacements: Vec<u32>,
+    pub final_energies: Vec<f32>
+}
+
+/// Population-based memetic search.
+/// Each generation recombines random pairs of parents, improves every child by local search,
+/// and inserts the children by crowding: a child replaces the population member closest to it
+/// in Hamming distance only if it has a lower energy, and duplicates are rejected.
+pub struct MemeticRunner<'a>{
+    params: &'a MemeticParams,
+    instance: &'a BqmIsingInstance,
+    icm_sampler: HoudayerSampler<IsingState>,
+    anneal_beta_vec: Vec<f32>,
+    control: Option<ControlHandle<'a>>
+}
+
+impl<'a> MemeticRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a MemeticParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        let icm_sampler = HoudayerSampler::new(instance, HoudayerOptions::default());
+        let anneal_beta_vec = match &params.local_search{
+            LocalSearch::Anneal(beta) => beta.get_beta_arr(),
+            LocalSearch::Descent(_) => Vec::new()
+        };
+        return Ok(Self{params, instance, icm_sampler, anneal_beta_vec, control: None});
+    }
+
+    /// Stop the run early when the control requests it, and report new minimum energies to it
+    pub fn with_control(mut self, control: ControlHandle<'a>) -> Self{
+        self.control = Some(control);
+        return self;
+    }
+
+    pub fn run(&self) -> Result<MemeticMinResults, TamcError>{
+        // seed and create random number generators, one for each thread
+        let mut rng = seeded_rng();
+        let threads = self.params.threads.max(1);
+        let mut rng_vec = split_rng(&mut rng, threads as usize);
+        let n = self.instance.size();
+        let mut results = MemeticMinResults{
+            params: self.params.clone(),
+            min_results: MinResults::new(0, n as u32),
+            mean_energies: Vec::with_capacity(self.params.num_generations as usize),
+            num_replacements: Vec::with_capacity(self.params.num_generations as usize),
+            final_energies: Vec::new()
+        };
+        info!("-- Memetic begin");
+        let start = time::Instant::now();
+        let mut population: Vec<(f32, IsingState)> = (0..self.params.population_size)
+            .map(|_| {
+                let mut st = rand_ising_state(n as u32, self.instance, &mut rng);
+                self.local_search(&mut st, &mut rng);
+                (self.instance.energy_ref(&st), st)
+            }).collect();
+        self.apply_measurements(0, &population, &mut results);
+        run_in_pool(threads, || {
+            for gen in 1..=self.params.num_generations{
+                if control::should_stop(&self.control){
+                    break;
+                }
+                let population_ref = &population;
+                let offspring = split_work(self.params.num_offspring_pairs, &mut rng_vec, |pairs, rng| {
+                    let mut ws = self.icm_sampler.workspace();
+                    let mut children = Vec::with_capacity(2 * pairs as usize);
+                    for _ in 0..pairs{
+                        let (c1, c2) = self.offspring_pair(population_ref, &mut ws, rng);
+                        children.push(c1);
+                        children.push(c2);
+                    }
+                    children
+                });
+                let mut num_replacements = 0;
+                for child in offspring.into_iter().flatten(){
+                    if self.replace(&mut population, child){
+                        num_replacements += 1;
+                    }
+                }
+                results.num_replacements.push(num_replacements);
+                self.apply_measurements(gen, &population, &mut results);
+            }
+        });
+        let end = start.elapsed();
+        info!("-- Memetic Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        results.min_results.timing = end.as_micros() as f64;
+        results.final_energies = population.iter().map(|(e, _)| *e).collect();
+
+        return Ok(results);
+    }
+
+    fn offspring_pair<Rn: Rng+?Sized>(&self, population: &[(f32, IsingState)], ws: &mut ClusterWorkspace, rng: &mut Rn)
+            -> ((f32, IsingState), (f32, IsingState)){
+        let p = population.len();
+        let i1 = rng.gen_range(0..p);
+        let i2 = (i1 + rng.gen_range(1..p)) % p;
+        let mut c1 = population[i1].1.clone();
+        let mut c2 = population[i2].1.clone();
+        match self.params.crossover{
+            Crossover::Houdayer => {
+                self.icm_sampler.pair_advance(&mut c1, &mut c2, ws, rng);
+            }
+            Crossover::Uniform => {
+                for i in 0..c1.arr.len() as u32{
+                    if c1.site_differs(&c2, i) && rng.gen::<bool>(){
+                        c1.exchange_site(&mut c2, i);
+                    }
+                }
+                c1.sites_exchanged();
+                c2.sites_exchanged();
+            }
+        }
+        self.local_search(&mut c1, rng);
+        self.local_search(&mut c2, rng);
+        return ((self.instance.energy_ref(&c1), c1), (self.instance.energy_ref(&c2), c2));
+    }
+
+    fn local_search<Rn: Rng+?Sized>(&self, state: &mut IsingState, rng: &mut Rn){
+        match &self.params.local_search{
+            LocalSearch::Descent(kind) => {
+                descend(self.instance, state, *kind);
+            }
+            LocalSearch::Anneal(_) => {
+                let n = self.instance.size() as u32;
+                let sampler = MetropolisSampler::new_uniform(self.instance, self.anneal_beta_vec[0], n);
+                let mut states = vec![state.clone()];
+                simulated_annealing(sampler, &mut states, &self.anneal_beta_vec, rng, |_, _| {});
+                *state = states.pop().unwrap();
+                descend(self.instance, state, DescentKind::Greedy);
+            }
+        }
+        state.energy_init = false;
+    }
+
+    /// Crowding replacement. Returns true if the child was accepted.
+    fn replace(&self, population: &mut Vec<(f32, IsingState)>, child: (f32, IsingState)) -> bool{
+        let (e, st) = child;
+        let mut closest = 0;
+        let mut min_dist = usize::MAX;
+        for (k, (_, member)) in population.iter().enumerate(){
+            let dist = st.arr.iter().zip(member.arr.iter()).filter(|(a, b)| a != b).count();
+            if dist == 0{
+                return false;
+            }
+            if dist < min_dist{
+                min_dist = dist;
+                closest = k;
+            }
+        }
+        if e < population[closest].0{
+            population[closest] = (e, st);
+            return true;
+        }
+        return false;
+    }
+
+    fn apply_measurements(&self, gen: u32, population: &[(f32, IsingState)], results: &mut MemeticMinResults){
+        let (min_e, min_state) = population.iter()
+            .min_by(|x, y| x.0.partial_cmp(&y.0).unwrap())
+            .unwrap();
+        let mean_e = population.iter().map(|(e, _)| *e).sum::<f32>() / population.len() as f32;
+        results.mean_energies.push(mean_e);
+        let min_results = &mut results.min_results;
+        min_results.num_measurements += 1;
+        if min_results.record(*min_e, gen, || min_state.as_u64_vec()){
+            control::report(&self.control, *min_e);
+        }
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &MemeticMinResults) -> Result<()>{
+    println!("Memetic Done.");
+    println!("** Ground state energy **");
+    if let (Some(e), Some(gen)) = (results.min_results.gs_energies.last(), results.min_results.gs_time_steps.last()){
+        println!("  e = {}", e);
+        println!("  generation = {}", gen);
+    }
+    write_yaml(&prog.output_file, results, "memetic")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::descent::DescentKind;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::memetic::{Crossover, LocalSearch, MemeticParams, MemeticRunner};
+    use crate::pt::BetaOptions;
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_ising_2d_memetic(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        for (crossover, local_search) in [
+                (Crossover::Houdayer, LocalSearch::Descent(DescentKind::Greedy)),
+                (Crossover::Uniform, LocalSearch::Anneal(BetaOptions::new_geometric(0.5, 3.0, 20)))]{
+            let params = MemeticParams{
+                population_size: 16,
+                num_generations: 50,
+                num_offspring_pairs: 8,
+                crossover,
+                local_search,
+                threads: 2
+            };
+            let results = with_seed(Some(1234), || MemeticRunner::new(&instance, &params).unwrap().run()).unwrap();
+            let &e = results.min_results.gs_energies.last().unwrap();
+            println!("e = {}, mean energies: {:?}", e, results.mean_energies.last().unwrap());
+            assert_eq!(results.mean_energies.len(), 51);
+            assert!(results.mean_energies.windows(2).all(|w| w[1] <= w[0]));
+            assert_eq!(e, -2.0 * (l*l) as f32);
+        }
+    }
+}
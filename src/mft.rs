@@ -0,0 +1,194 @@
+use std::time;
+
+use anyhow::Result;
+use log::info;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::traits::*;
+
+use crate::util::{run_in_pool, seeded_rng, split_rng, split_work, write_yaml};
+use crate::Prog;
+use crate::control;
+use crate::error::TamcError;
+use crate::descent::{DescentKind, QuenchResults};
+use crate::ising::{BqmIsingInstance, IsingState};
+use crate::ising_results::MinResults;
+use crate::pt::BetaOptions;
+use crate::validate::Validation;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MftParams{
+    pub beta: BetaOptions,
+    /// Include the Onsager reaction term of the TAP equations
+    pub tap: bool,
+    /// Number of sweeps of the mean-field equations at each beta
+    pub iterations_per_beta: u32,
+    /// Fraction of the previous magnetization kept in each update
+    pub damping: f64,
+    /// Number of random initial magnetizations
+    pub num_inits: u32,
+    /// Polish the rounded states with a zero-temperature descent
+    pub polish: Option<DescentKind>,
+    pub threads: u32
+}
+
+impl MftParams{
+    /// Check every parameter before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        self.beta.validate("beta", &mut v);
+        if self.iterations_per_beta == 0{
+            v.error("iterations_per_beta", "iterations_per_beta must be positive", "set iterations_per_beta to e.g. 4");
+        }
+        if !(0.0..1.0).contains(&self.damping){
+            v.error("damping", format!("damping must be in [0, 1), but is {}", self.damping), "set damping to e.g. 0.5");
+        }
+        if self.num_inits == 0{
+            v.error("num_inits", "at least one initial magnetization is required", "set num_inits to e.g. 64");
+        }
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MftMinResults{
+    pub params: MftParams,
+    /// The ground state trace is indexed by initialization
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    /// Energies of the final states of each initialization, after polishing if enabled
+    pub energies: Vec<f32>,
+    /// Energies before and after polishing
+    pub polish: Option<QuenchResults>
+}
+
+/// Deterministic annealing of the mean-field equations along the beta schedule,
+///     m_i = tanh( -β (h_i + Σ_j K_ij m_j) - β^2 m_i Σ_j K_ij^2 (1 - m_j^2) )
+/// where the last (Onsager) term is only included for TAP.
+/// The magnetizations are rounded to spins at the end of the schedule.
+pub struct MftRunner<'a>{
+    params: &'a MftParams,
+    instance: &'a BqmIsingInstance,
+    beta_vec: Vec<f32>
+}
+
+impl<'a> MftRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a MftParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        let beta_vec = params.beta.get_beta_arr();
+        return Ok(Self{params, instance, beta_vec});
+    }
+
+    pub fn run(&self) -> Result<MftMinResults, TamcError>{
+        // seed and create random number generators, one for each thread
+        let mut rng = seeded_rng();
+        let threads = self.params.threads.max(1);
+        let mut rng_vec = split_rng(&mut rng, threads as usize);
+        info!("-- MFT begin");
+        let start = time::Instant::now();
+        let states_vec = run_in_pool(threads, || split_work(self.params.num_inits, &mut rng_vec, |inits, rng|
+            (0..inits)
+                .take_while(|_| !control::interrupted())
+                .map(|_| self.anneal(rng)).collect::<Vec<_>>()));
+        let mut states: Vec<IsingState> = states_vec.into_iter().flatten().collect();
+        let polish = self.params.polish
+            .map(|kind| QuenchResults::quench_states(self.instance, &mut states, kind));
+        let end = start.elapsed();
+        info!("-- MFT Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+
+        let n = self.instance.size();
+        let mut min_results = MinResults::new(0, n as u32);
+        let energies: Vec<f32> = states.iter().map(|st| self.instance.energy_ref(st)).collect();
+        for (i, (&e, st)) in energies.iter().zip(states.iter()).enumerate(){
+            min_results.record(e, i as u32, || st.as_u64_vec());
+        }
+        min_results.num_measurements = energies.len() as u32;
+        min_results.timing = end.as_micros() as f64;
+        let mut params = self.params.clone();
+        params.num_inits = min_results.num_measurements;
+
+        return Ok(MftMinResults{params, min_results, energies, polish});
+    }
+
+    /// Anneal from small random magnetizations and round to a spin state
+    fn anneal<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> IsingState{
+        let n = self.instance.size();
+        let mut m: Vec<f64> = (0..n).map(|_| 0.1 * (2.0 * rng.gen::<f64>() - 1.0)).collect();
+        let damping = self.params.damping;
+        for &beta in self.beta_vec.iter(){
+            let beta = beta as f64;
+            for _ in 0..self.params.iterations_per_beta{
+                for i in 0..n{
+                    let mut h = self.instance.bias[i] as f64;
+                    let mut onsager = 0.0;
+                    for &(j, k) in self.instance.coupling_vecs[i].iter(){
+                        let mj = m[j as usize];
+                        h += (k as f64) * mj;
+                        if self.params.tap{
+                            onsager += (k as f64) * (k as f64) * (1.0 - mj * mj);
+                        }
+                    }
+                    let mi = f64::tanh(-beta * h - beta * beta * m[i] * onsager);
+                    m[i] = damping * m[i] + (1.0 - damping) * mi;
+                }
+            }
+        }
+        let arr = m.iter()
+            .map(|&mi| if mi > 0.0 { 1 } else if mi < 0.0 { -1 } else if rng.gen::<bool>() { 1 } else { -1 })
+            .collect();
+        return IsingState{arr, energy: 0.0, energy_init: false};
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &MftMinResults) -> Result<()>{
+    println!("MFT Done.");
+    println!("** Ground state energy **");
+    if let Some(e) = results.min_results.gs_energies.last(){
+        println!("  e = {}", e);
+    }
+    write_yaml(&prog.output_file, results, "MFT")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::descent::DescentKind;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::mft::{MftParams, MftRunner};
+    use crate::pt::BetaOptions;
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_ising_2d_mft(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        for tap in [false, true]{
+            let params = MftParams{
+                beta: BetaOptions::new_geometric(0.1, 3.0, 32),
+                tap,
+                iterations_per_beta: 4,
+                damping: 0.5,
+                num_inits: 8,
+                polish: Some(DescentKind::Greedy),
+                threads: 2
+            };
+            let results = with_seed(Some(1234), || MftRunner::new(&instance, &params).unwrap().run()).unwrap();
+            let &e = results.min_results.gs_energies.last().unwrap();
+            println!("tap = {}, e = {}, energies: {:?}", tap, e, results.energies);
+            let polish = results.polish.unwrap();
+            assert!(polish.post_energies.iter().zip(polish.pre_energies.iter()).all(|(e1, e0)| e1 <= e0));
+            assert_eq!(results.energies.len(), 8);
+            if tap {
+                // the reaction term leaves domain walls on a finite-dimensional ferromagnet
+                assert!(e <= -0.5 * 2.0 * (l*l) as f32);
+            } else {
+                assert_eq!(e, -2.0 * (l*l) as f32);
+            }
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Pluggable measurements of the replicas during PT and SA runs.
+//!
+//! An `Observer` is notified after each sweep, replica exchange and ICM move,
+//! and records an `ObservableOutput` that is written with the results of the run.
+//! Runners create their observers from factories, so that each run (and each SA thread) has its own.
+use std::marker::PhantomData;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use tamc_core::traits::Instance;
+
+use crate::ising::{BqmIsingInstance, IsingState};
+
+/// The replicas seen by an observer after a sweep
+pub struct Replicas<'s, St>{
+    /// The beta of each temperature index. SA has a single temperature
+    pub betas: &'s [f32],
+    /// The states of each chain, indexed by temperature.
+    /// Each SA replica is a chain of one temperature.
+    pub chains: Vec<&'s [St]>
+}
+
+impl<'s, St> Replicas<'s, St>{
+    pub fn num_temperatures(&self) -> usize{
+        return self.betas.len();
+    }
+}
+
+/// A time series of an observable
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ObservableOutput{
+    pub name: String,
+    /// Sweeps at which the observable was recorded
+    pub sweeps: Vec<u32>,
+    /// Temperature x Time x Replica (or replica pair) values
+    pub values: Vec<Vec<Vec<f64>>>
+}
+
+impl ObservableOutput{
+    pub fn new(name: &str) -> Self{
+        return Self{name: name.to_string(), sweeps: Vec::new(), values: Vec::new()};
+    }
+
+    /// Join the outputs of the same observer on disjoint sets of replicas along the replica axis,
+    /// keeping the sweeps recorded by every output
+    pub fn join_replicas(outputs: Vec<ObservableOutput>) -> Option<Self>{
+        let mut iter = outputs.into_iter();
+        let mut me = iter.next()?;
+        for other in iter{
+            let num_times = me.sweeps.len().min(other.sweeps.len());
+            me.sweeps.truncate(num_times);
+            for (v, w) in me.values.iter_mut().zip(other.values.into_iter()){
+                v.truncate(num_times);
+                for (vt, wt) in v.iter_mut().zip(w.into_iter()){
+                    vt.extend(wt);
+                }
+            }
+        }
+        return Some(me);
+    }
+}
+
+/// Measures the replicas during a run
+pub trait Observer<I: ?Sized, St>: Send{
+    /// Number of sweeps between calls to `on_sweep`
+    fn interval(&self) -> u32{
+        return 1;
+    }
+    /// Called every `interval` sweeps after the PT warmup, or every `interval` SA schedule steps
+    fn on_sweep(&mut self, _sweep: u32, _instance: &I, _replicas: &Replicas<St>){ }
+    /// Called after the replica exchanges of each PT chain in every sweep.
+    /// `accepted[j]` is whether the states at temperatures j and j+1 were exchanged
+    fn on_swap(&mut self, _sweep: u32, _chain: usize, _accepted: &[bool]){ }
+    /// Called after the ICM move between each pair of PT chains in every sweep,
+    /// with the cluster size at each temperature index in `temperatures`
+    fn on_icm(&mut self, _sweep: u32, _pair: usize, _temperatures: Range<usize>, _cluster_sizes: &[usize]){ }
+    /// The observable recorded so far
+    fn output(&self) -> ObservableOutput;
+}
+
+/// Creates a fresh observer for each run
+pub type ObserverFactory<'a, I, St> = Box<dyn Fn() -> Box<dyn Observer<I, St> + 'a> + Send + Sync + 'a>;
+
+/// Records a function of each replica at every temperature
+pub struct ReplicaObserver<I: ?Sized, St, F>{
+    f: F,
+    interval: u32,
+    output: ObservableOutput,
+    _phantom: PhantomData<fn(&I, &St)>
+}
+
+impl<I: ?Sized, St, F: Fn(&I, &St) -> f64 + Send> ReplicaObserver<I, St, F>{
+    pub fn new(name: &str, interval: u32, f: F) -> Self{
+        return Self{f, interval, output: ObservableOutput::new(name), _phantom: PhantomData};
+    }
+}
+
+impl<I: ?Sized, St, F: Fn(&I, &St) -> f64 + Send> Observer<I, St> for ReplicaObserver<I, St, F>{
+    fn interval(&self) -> u32{
+        return self.interval;
+    }
+
+    fn on_sweep(&mut self, sweep: u32, instance: &I, replicas: &Replicas<St>){
+        let f = &self.f;
+        let values = &mut self.output.values;
+        values.resize_with(replicas.num_temperatures(), Vec::new);
+        self.output.sweeps.push(sweep);
+        for (k, vk) in values.iter_mut().enumerate(){
+            vk.push(replicas.chains.iter().map(|ch| f(instance, &ch[k])).collect());
+        }
+    }
+
+    fn output(&self) -> ObservableOutput{
+        return self.output.clone();
+    }
+}
+
+/// Records a function of each pair of replicas (chains 2j and 2j+1) at every temperature.
+/// SA pairs consecutive replicas.
+pub struct PairObserver<I: ?Sized, St, F>{
+    f: F,
+    interval: u32,
+    output: ObservableOutput,
+    _phantom: PhantomData<fn(&I, &St)>
+}
+
+impl<I: ?Sized, St, F: Fn(&I, &St, &St) -> f64 + Send> PairObserver<I, St, F>{
+    pub fn new(name: &str, interval: u32, f: F) -> Self{
+        return Self{f, interval, output: ObservableOutput::new(name), _phantom: PhantomData};
+    }
+}
+
+impl<I: ?Sized, St, F: Fn(&I, &St, &St) -> f64 + Send> Observer<I, St> for PairObserver<I, St, F>{
+    fn interval(&self) -> u32{
+        return self.interval;
+    }
+
+    fn on_sweep(&mut self, sweep: u32, instance: &I, replicas: &Replicas<St>){
+        let f = &self.f;
+        let values = &mut self.output.values;
+        values.resize_with(replicas.num_temperatures(), Vec::new);
+        self.output.sweeps.push(sweep);
+        for (k, vk) in values.iter_mut().enumerate(){
+            vk.push(replicas.chains.chunks_exact(2).map(|p| f(instance, &p[0][k], &p[1][k])).collect());
+        }
+    }
+
+    fn output(&self) -> ObservableOutput{
+        return self.output.clone();
+    }
+}
+
+/// Built-in observables of Ising states
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum IsingObservable{
+    /// Energy of each replica
+    Energy,
+    /// Magnetization per spin of each replica
+    Magnetization,
+    /// Overlap per spin of each replica pair
+    Overlap,
+    /// Link overlap of each replica pair, averaged over the couplings
+    LinkOverlap
+}
+
+/// An observable recorded every `interval` sweeps
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ObservableSpec{
+    pub observable: IsingObservable,
+    pub interval: u32
+}
+
+fn link_overlap(instance: &BqmIsingInstance, st1: &IsingState, st2: &IsingState) -> f64{
+    let mut ql = 0i64;
+    let mut num_links = 0usize;
+    for (i, row) in instance.coupling_vecs.iter().enumerate(){
+        let qi = (st1.arr[i] * st2.arr[i]) as i64;
+        for &(j, _) in row.iter(){
+            ql += qi * (st1.arr[j as usize] * st2.arr[j as usize]) as i64;
+        }
+        num_links += row.len();
+    }
+    return if num_links > 0 { ql as f64 / num_links as f64 } else { 0.0 };
+}
+
+/// The observer of a built-in Ising observable
+pub fn ising_observer(spec: &ObservableSpec) -> Box<dyn Observer<BqmIsingInstance, IsingState>>{
+    let interval = spec.interval;
+    return match spec.observable{
+        IsingObservable::Energy => Box::new(ReplicaObserver::new("energy", interval,
+            |instance: &BqmIsingInstance, st: &IsingState| instance.energy_ref(st) as f64)),
+        IsingObservable::Magnetization => Box::new(ReplicaObserver::new("magnetization", interval,
+            |_: &BqmIsingInstance, st: &IsingState| st.mag() as f64 / st.arr.len() as f64)),
+        IsingObservable::Overlap => Box::new(PairObserver::new("overlap", interval,
+            |_: &BqmIsingInstance, st1: &IsingState, st2: &IsingState| st1.overlap(st2) as f64 / st1.arr.len() as f64)),
+        IsingObservable::LinkOverlap => Box::new(PairObserver::new("link_overlap", interval, link_overlap))
+    };
+}
+
+/// Observer factories of the built-in Ising observables
+pub fn ising_observer_factories<'a>(specs: &[ObservableSpec]) -> Vec<ObserverFactory<'a, BqmIsingInstance, IsingState>>{
+    return specs.iter().cloned()
+        .map(|spec| -> ObserverFactory<'a, BqmIsingInstance, IsingState> { Box::new(move || ising_observer(&spec)) })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use crate::ising::{BqmIsingInstance, IsingState};
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::observe::{IsingObservable, ObservableOutput, ObservableSpec, Observer};
+    use crate::pt::{BetaOptions, PtIcmParams, PtIcmRunner};
+    use crate::sa::{SaParams, SaRunner};
+
+    /// Counts the exchanges and ICM moves at each temperature
+    #[derive(Default)]
+    struct MoveCounter{
+        swaps: Vec<f64>,
+        icm_moves: Vec<f64>
+    }
+
+    impl Observer<BqmIsingInstance, IsingState> for MoveCounter{
+        fn on_swap(&mut self, _sweep: u32, _chain: usize, accepted: &[bool]){
+            self.swaps.resize(accepted.len(), 0.0);
+            for (s, &a) in self.swaps.iter_mut().zip(accepted.iter()){
+                *s += a as u8 as f64;
+            }
+        }
+        fn on_icm(&mut self, _sweep: u32, _pair: usize, temperatures: Range<usize>, cluster_sizes: &[usize]){
+            self.icm_moves.resize(temperatures.end, 0.0);
+            for (k, &c) in temperatures.zip(cluster_sizes.iter()){
+                self.icm_moves[k] += (c > 0) as u8 as f64;
+            }
+        }
+        fn output(&self) -> ObservableOutput{
+            ObservableOutput{name: "moves".to_string(), sweeps: vec![0],
+                values: vec![vec![self.swaps.clone()], vec![self.icm_moves.clone()]]}
+        }
+    }
+
+    #[test]
+    fn test_observers(){
+        let instance = make_ising_2d_instance(8);
+        let params = PtIcmParams{
+            num_sweeps: 200,
+            beta: BetaOptions::new_geometric(0.2, 2.0, 8),
+            lo_num_beta: Some(4),
+            num_replica_chains: 4,
+            observables: Some(vec![
+                ObservableSpec{observable: IsingObservable::Magnetization, interval: 10},
+                ObservableSpec{observable: IsingObservable::LinkOverlap, interval: 25}
+            ]),
+            ..Default::default()
+        };
+        let runner = PtIcmRunner::new(&instance, &params).unwrap()
+            .with_observer(|| Box::new(MoveCounter::default()));
+        let (results, _, _) = runner.run(None).unwrap();
+        let obs = results.observables.unwrap();
+        assert_eq!(obs.len(), 3);
+        assert_eq!(obs[0].name, "magnetization");
+        assert_eq!(obs[0].sweeps, (100..200).step_by(10).collect::<Vec<u32>>());
+        assert_eq!(obs[0].values.len(), 8);
+        assert!(obs[0].values.iter().flatten().all(|v| v.len() == 4 && v.iter().all(|m| m.abs() <= 1.0)));
+        assert_eq!(obs[1].sweeps, vec![100, 125, 150, 175]);
+        assert!(obs[1].values.iter().flatten().all(|v| v.len() == 2 && v.iter().all(|q| q.abs() <= 1.0)));
+        let swaps: f64 = obs[2].values[0][0].iter().sum();
+        assert_eq!(swaps as u32, results.acceptance_counts.iter().sum::<u32>());
+        assert!(obs[2].values[1][0][..4].iter().all(|&c| c == 0.0));
+
+        // Each SA thread observes its own replicas, which are then joined
+        let sa_params = SaParams{
+            beta: BetaOptions::new_geometric(0.1, 5.0, 50),
+            num_replicas: 8,
+            threads: 2,
+            ais: None,
+            quench: None,
+            time_limit: None,
+            target_energy: None,
+            observables: Some(vec![ObservableSpec{observable: IsingObservable::Energy, interval: 10}])
+        };
+        let (results, states) = SaRunner::new(&instance, &sa_params).unwrap().run_parallel(None).unwrap();
+        let obs = &results.observables.unwrap()[0];
+        assert_eq!(obs.sweeps, vec![0, 10, 20, 30, 40]);
+        assert_eq!(obs.values.len(), 1);
+        assert_eq!(obs.values[0][4].len(), 8);
+        assert!(obs.values[0][4].iter().all(|&e| e >= -128.0 && e <= 128.0));
+        assert_eq!(states.len(), 8);
+    }
+}
@@ -0,0 +1,234 @@
+use std::fs::File;
+use std::time;
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::traits::*;
+
+use crate::{Method, MethodResults, Prog};
+use crate::descent::DescentRunner;
+use crate::error::TamcError;
+use crate::ising::{BqmIsingInstance, IsingState};
+use crate::pt::PtIcmRunner;
+use crate::sa::SaRunner;
+use crate::validate::Validation;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PipelineParams{
+    /// Methods run in order, each seeded with the final states of the previous stage.
+    /// Supported stages are PT, SA and Descent.
+    pub stages: Vec<Method>
+}
+
+impl PipelineParams{
+    /// Check the sequence of stages and the parameters of each stage
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.stages.is_empty(){
+            v.error("stages", "a pipeline requires at least one stage", "add a PT or SA stage");
+        }
+        for (k, stage) in self.stages.iter().enumerate(){
+            let field = format!("stages.{}", k);
+            match stage{
+                Method::PT(_) | Method::SA(_) => v.extend_nested(&field, stage.validate()),
+                Method::Descent(_) if k == 0 => {
+                    v.error(&field, "a Descent stage must follow a stage that hands off states",
+                            "begin the pipeline with a PT or SA stage");
+                }
+                // the states handed off are quenched, so num_restarts is ignored
+                Method::Descent(params) => v.extend_nested(&field, params.validate_quench()),
+                _ => v.error(&field, format!("method {} is not supported as a pipeline stage", stage.name()),
+                             "use PT, SA or Descent")
+            }
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PipelineStageResults{
+    pub method: String,
+    pub min_energy: Option<f32>,
+    /// Number of states passed on to the next stage
+    pub num_states: u32,
+    pub results: MethodResults
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PipelineResults{
+    pub params: PipelineParams,
+    pub min_energy: Option<f32>,
+    /// Lowest energy state handed off by any stage
+    pub gs_state: Option<Vec<u64>>,
+    pub timing: f64,
+    pub stages: Vec<PipelineStageResults>
+}
+
+/// Runs a sequence of methods with state hand-off.
+/// PT hands off the lowest temperature state of every replica chain,
+/// SA the final state of every replica and Descent the local minimum of every state.
+/// A PT stage arranges the incoming states into its replica chains, cycling through them as needed,
+/// and an SA stage cycles through them to fill its replicas.
+pub struct PipelineRunner<'a>{
+    params: &'a PipelineParams,
+    instance: &'a BqmIsingInstance
+}
+
+impl<'a> PipelineRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a PipelineParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        return Ok(Self{params, instance});
+    }
+
+    pub fn run(&self) -> Result<PipelineResults, TamcError>{
+        info!("-- Pipeline begin");
+        let start = time::Instant::now();
+        let mut stages = Vec::with_capacity(self.params.stages.len());
+        let mut states: Option<Vec<IsingState>> = None;
+        let mut best: Option<(f32, Vec<u64>)> = None;
+        for (k, method) in self.params.stages.iter().enumerate(){
+            info!("Stage {}: {}", k, method.name());
+            let (results, mut out_states) = self.run_stage(method, states.take())?;
+            for st in out_states.iter_mut(){
+                st.energy_init = false;
+                let e = self.instance.energy_ref(st);
+                if best.as_ref().map_or(true, |(e0, _)| e < *e0){
+                    best = Some((e, st.as_u64_vec()));
+                }
+            }
+            stages.push(PipelineStageResults{
+                method: method.name().to_string(),
+                min_energy: results.min_energy(),
+                num_states: out_states.len() as u32,
+                results
+            });
+            states = Some(out_states);
+        }
+        let end = start.elapsed();
+        info!("-- Pipeline Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        let min_energy = stages.iter().filter_map(|s| s.min_energy)
+            .chain(best.as_ref().map(|(e, _)| *e))
+            .reduce(f32::min);
+
+        return Ok(PipelineResults{
+            params: self.params.clone(),
+            min_energy,
+            gs_state: best.map(|(_, st)| st),
+            timing: end.as_micros() as f64,
+            stages
+        });
+    }
+
+    fn run_stage(&self, method: &Method, states: Option<Vec<IsingState>>)
+            -> Result<(MethodResults, Vec<IsingState>), TamcError>{
+        let instance = self.instance;
+        match method{
+            Method::PT(params) => {
+                let runner = PtIcmRunner::new(instance, params)?;
+                let init_state = states.map(|st| runner.init_state_from(&st)).transpose()?;
+                let (r, _, pt_state) = if params.threads > 1 {
+                    runner.run_parallel_seeded(init_state)
+                } else {
+                    runner.run(init_state)
+                }?;
+                let out_states = pt_state.iter()
+                    .map(|pts| pts.states_ref().last().unwrap().clone())
+                    .collect();
+                return Ok((MethodResults::PT(r), out_states));
+            }
+            Method::SA(params) => {
+                let mut params = params.clone();
+                params.threads = params.threads.min(params.num_replicas).max(1);
+                let runner = SaRunner::new(instance, &params)?;
+                let (r, out_states) = if params.num_replicas > 1 {
+                    let reps_per_thread = (params.num_replicas / params.threads) as usize;
+                    let init_state = states.map(|st| {
+                        let mut iter = st.into_iter().cycle();
+                        (0..params.threads)
+                            .map(|_| iter.by_ref().take(reps_per_thread).collect())
+                            .collect()
+                    });
+                    runner.run_parallel(init_state)
+                } else {
+                    runner.run(states.map(|st| st.into_iter().take(1).collect()))
+                }?;
+                return Ok((MethodResults::SA(r), out_states));
+            }
+            Method::Descent(params) => {
+                let runner = DescentRunner::new(instance, params)?;
+                let (r, out_states) = runner.run_seeded(states.unwrap_or_default())?;
+                return Ok((MethodResults::Descent(r), out_states));
+            }
+            _ => unreachable!()
+        }
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &PipelineResults) -> Result<()>{
+    println!("Pipeline Done.");
+    println!("** Ground state energy **");
+    for s in results.stages.iter(){
+        println!("  {}: e = {:?}", s.method, s.min_energy);
+    }
+    if let Some(e) = results.min_energy{
+        println!("  e = {}", e);
+    }
+    let f = File::create(&prog.output_file)
+        .with_context(|| format!("Failed to create output file {}", prog.output_file))?;
+    serde_yaml::to_writer(f, results)
+        .with_context(|| format!("Failed to write pipeline results to {}", prog.output_file))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Method;
+    use crate::descent::{DescentKind, DescentParams};
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pipeline::{PipelineParams, PipelineRunner};
+    use crate::pt::{BetaOptions, PtIcmParams};
+    use crate::sa::SaParams;
+
+    #[test]
+    fn test_ising_2d_pipeline(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        let pt_params = PtIcmParams{
+            num_sweeps: 200,
+            beta: BetaOptions::new_geometric(0.1, 1.0, 8),
+            lo_num_beta: Some(4),
+            num_replica_chains: 4,
+            threads: 1,
+            ..Default::default()
+        };
+        let sa_params = SaParams{
+            beta: BetaOptions::new_geometric(1.0, 5.0, 64),
+            num_replicas: 8,
+            threads: 2,
+            ais: None,
+            quench: None,
+            time_limit: None,
+            target_energy: None,
+            observables: None
+        };
+        let params = PipelineParams{
+            stages: vec![
+                Method::PT(pt_params),
+                Method::SA(sa_params),
+                Method::Descent(DescentParams{kind: DescentKind::Greedy, num_restarts: 0, threads: 2})
+            ]
+        };
+        let results = PipelineRunner::new(&instance, &params).unwrap().run().unwrap();
+        let num_states: Vec<u32> = results.stages.iter().map(|s| s.num_states).collect();
+        println!("stage energies: {:?}", results.stages.iter().map(|s| s.min_energy).collect::<Vec<_>>());
+        assert_eq!(num_states, vec![4, 8, 8]);
+        // the descent can only lower the energies handed off by SA
+        assert!(results.stages[2].min_energy.unwrap() <= results.stages[1].min_energy.unwrap());
+        assert!(results.gs_state.is_some());
+        assert!(results.min_energy.unwrap() <= -0.5 * 2.0 * (l*l) as f32);
+    }
+}
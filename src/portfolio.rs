@@ -0,0 +1,213 @@
+use std::fs::File;
+use std::thread;
+
+use anyhow::{Context, Result};
+use log::info;
+use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
+
+use crate::{Method, MethodResults, Prog, RunOptions};
+use crate::control::{BestSoFar, RunControl, StopReason};
+use crate::error::TamcError;
+use crate::ising::BqmIsingInstance;
+use crate::util::{fill_seed, thread_pool, with_seed};
+use crate::validate::{validate_limits, Validation};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PortfolioParams{
+    /// Methods raced concurrently on the instance.
+    /// Supported members are PT, SA, Descent, Tabu, EO and Memetic.
+    pub members: Vec<Method>,
+    /// Total thread budget, split as evenly as possible between the members.
+    /// The threads parameter of each member is overridden by its share,
+    /// so there must be at least one thread per member.
+    pub threads: u32,
+    /// Stop all members once any of them reaches this energy
+    pub target_energy: Option<f32>,
+    /// Stop all members after this many seconds
+    pub time_limit: Option<f64>
+}
+
+impl PortfolioParams{
+    /// Check the members and the thread budget before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.members.is_empty(){
+            v.error("members", "a portfolio requires at least one member method", "add a member method");
+        }
+        for method in self.members.iter(){
+            match method{
+                Method::PT(_) | Method::SA(_) | Method::Descent(_) | Method::Tabu(_)
+                | Method::EO(_) | Method::Memetic(_) => {}
+                _ => v.error("members", format!("method {} is not supported as a portfolio member", method.name()),
+                             "use PT, SA, Descent, Tabu, EO or Memetic")
+            }
+        }
+        if (self.threads as usize) < self.members.len(){
+            v.error("threads", format!("{} threads cannot run {} members concurrently", self.threads, self.members.len()),
+                    format!("set threads to at least {}, or remove members", self.members.len()));
+        }
+        validate_limits(self.time_limit, self.target_energy, &mut v);
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PortfolioMemberResults{
+    pub method: String,
+    pub threads: u32,
+    pub min_energy: Option<f32>,
+    pub gs_state: Option<Vec<u64>>,
+    pub results: MethodResults
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PortfolioResults{
+    pub params: PortfolioParams,
+    /// Lowest energy reported by any member, with the member index and the time it was found
+    pub best: Option<BestSoFar>,
+    pub best_method: Option<String>,
+    pub gs_state: Option<Vec<u64>>,
+    pub stop_reason: StopReason,
+    pub timing: f64,
+    pub members: Vec<PortfolioMemberResults>
+}
+
+/// Races several methods on the same instance.
+/// Each member runs on its own thread pool and reports new minimum energies to a shared
+/// best-so-far record, and every member stops as soon as the target energy or time limit is reached.
+pub struct PortfolioRunner<'a>{
+    params: &'a PortfolioParams,
+    instance: &'a BqmIsingInstance
+}
+
+impl<'a> PortfolioRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a PortfolioParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        return Ok(Self{params, instance});
+    }
+
+    /// Split the thread budget between the members, giving any remainder to the first members
+    pub fn thread_shares(&self) -> Vec<u32>{
+        let m = self.params.members.len() as u32;
+        let threads = self.params.threads;
+        return (0..m).map(|k| threads / m + if k < threads % m { 1 } else { 0 }).collect();
+    }
+
+    pub fn run(&self) -> Result<PortfolioResults, TamcError>{
+        let shares = self.thread_shares();
+        let control = RunControl::new(self.params.target_energy, self.params.time_limit);
+        // the members run on their own threads, so their seeds are drawn here and passed to them
+        let mut seed_seq = [0u8; 32];
+        fill_seed(&mut seed_seq);
+        let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
+        let seeds: Vec<u64> = self.params.members.iter().map(|_| rng.next_u64()).collect();
+        info!("-- Portfolio begin");
+        let members: Vec<Result<PortfolioMemberResults, TamcError>> = thread::scope(|s| {
+            let handles: Vec<_> = self.params.members.iter().zip(shares.iter().zip(seeds.iter())).enumerate()
+                .map(|(k, (method, (&threads, &seed)))| {
+                    let control = &control;
+                    s.spawn(move || self.run_member(method, threads, seed, control, k))
+                }).collect();
+            handles.into_iter().enumerate()
+                .map(|(k, h)| h.join().unwrap_or_else(
+                    |_| Err(TamcError::Panicked(format!("Portfolio member {}", k)))))
+                .collect()
+        });
+        let members = members.into_iter().collect::<Result<Vec<_>, _>>()?;
+        let timing = control.elapsed() * 1.0e6;
+        info!("-- Portfolio Finished");
+        info!("Duration: {:5.4} s", timing / 1.0e6);
+
+        let best = control.best();
+        let best_method = best.map(|b| members[b.member].method.clone());
+        let gs_state = members.iter()
+            .filter(|m| m.min_energy.is_some())
+            .min_by(|x, y| x.min_energy.partial_cmp(&y.min_energy).unwrap())
+            .and_then(|m| m.gs_state.clone());
+
+        return Ok(PortfolioResults{
+            params: self.params.clone(),
+            best,
+            best_method,
+            gs_state,
+            stop_reason: control.stop_reason().unwrap_or(StopReason::Completed),
+            timing,
+            members
+        });
+    }
+
+    fn run_member(&self, method: &Method, threads: u32, seed: u64, control: &RunControl, k: usize)
+            -> Result<PortfolioMemberResults, TamcError>{
+        let pool = thread_pool(threads)?;
+        let mut method = method.clone();
+        method.set_threads(threads);
+        let options = RunOptions{control: Some(control.handle(k)), ..Default::default()};
+        let run_results = pool.install(|| with_seed(Some(seed), || crate::solve(self.instance, &method, &options)))?;
+        let gs_state = run_results.gs_state(self.instance);
+        let results = run_results.results;
+        let min_energy = results.min_energy();
+        return Ok(PortfolioMemberResults{method: method.name().to_string(), threads, min_energy, gs_state, results});
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &PortfolioResults) -> Result<()>{
+    println!("Portfolio Done.");
+    println!("** Ground state energy **");
+    if let (Some(best), Some(method)) = (&results.best, &results.best_method){
+        println!("  e = {}", best.energy);
+        println!("  found by {} (member {}) after {:5.4} s", method, best.member, best.time);
+    }
+    for m in results.members.iter(){
+        println!("  {}: e = {:?}", m.method, m.min_energy);
+    }
+    let f = File::create(&prog.output_file)
+        .with_context(|| format!("Failed to create output file {}", prog.output_file))?;
+    serde_yaml::to_writer(f, results)
+        .with_context(|| format!("Failed to write portfolio results to {}", prog.output_file))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Method;
+    use crate::control::StopReason;
+    use crate::descent::{DescentKind, DescentParams};
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::portfolio::{PortfolioParams, PortfolioRunner};
+    use crate::tabu::TabuParams;
+
+    #[test]
+    fn test_ising_2d_portfolio(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        let gs_e = -2.0 * (l*l) as f32;
+        let params = PortfolioParams{
+            members: vec![
+                Method::Descent(DescentParams{kind: DescentKind::Greedy, num_restarts: 1_000_000, threads: 1}),
+                Method::Tabu(TabuParams{
+                    num_iterations: 1000, tenure: 8, num_restarts: 1_000_000, num_elite: 2,
+                    perturbation_fraction: 0.1, threads: 1
+                })
+            ],
+            threads: 3,
+            target_energy: Some(gs_e),
+            time_limit: Some(60.0)
+        };
+        let one_thread = PortfolioParams{threads: 1, ..params.clone()};
+        assert!(PortfolioRunner::new(&instance, &one_thread).is_err());
+        let runner = PortfolioRunner::new(&instance, &params).unwrap();
+        assert_eq!(runner.thread_shares(), vec![2, 1]);
+        let results = runner.run().unwrap();
+        let best = results.best.unwrap();
+        println!("best: e = {} by {:?} after {} s", best.energy, results.best_method, best.time);
+        assert_eq!(best.energy, gs_e);
+        assert_eq!(results.stop_reason, StopReason::TargetEnergy);
+        assert!(results.gs_state.is_some());
+        assert_eq!(results.members.len(), 2);
+        // the members stopped long before completing their restarts
+        assert!(best.time < 60.0);
+    }
+}
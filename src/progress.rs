@@ -0,0 +1,209 @@
+//! Periodic progress reports of long runs, written to stderr or as JSON lines to a file
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::TamcError;
+
+/// The state of a run at the time of a report, as seen by the runner
+pub struct ProgressStatus<'s>{
+    pub method: &'s str,
+    pub sweeps_done: u32,
+    pub total_sweeps: u32,
+    /// Seconds since the start of the run
+    pub elapsed: f64,
+    /// The lowest energy among the current replicas
+    pub current_energy: Option<f32>,
+    /// Accepted exchanges between temperatures j and j+1, summed over the chains
+    pub swap_acceptances: Option<Vec<u32>>,
+    /// Number of exchanges attempted between each pair of temperatures
+    pub swap_attempts: u32,
+    pub round_trips: Option<u32>
+}
+
+/// One progress report
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProgressReport{
+    pub method: String,
+    pub sweeps_done: u32,
+    pub total_sweeps: u32,
+    /// Seconds since the start of the run
+    pub elapsed: f64,
+    pub sweeps_per_second: f64,
+    /// Estimated seconds until the last sweep
+    pub eta: Option<f64>,
+    pub best_energy: Option<f32>,
+    /// Sweep at which the best energy was found
+    pub best_sweep: Option<u32>,
+    /// Seconds since the start of the run at which the best energy was found
+    pub best_time: Option<f64>,
+    pub current_energy: Option<f32>,
+    /// Swap acceptance rate between temperatures j and j+1 so far
+    pub swap_acceptance: Option<Vec<f64>>,
+    pub round_trips: Option<u32>
+}
+
+impl std::fmt::Display for ProgressReport{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}] sweep {}/{} ({:.1}%), {:.1} sweeps/s", self.method, self.sweeps_done, self.total_sweeps,
+               100.0 * self.sweeps_done as f64 / self.total_sweeps.max(1) as f64, self.sweeps_per_second)?;
+        if let Some(eta) = self.eta{
+            write!(f, ", ETA {:.1} s", eta)?;
+        }
+        if let (Some(e), Some(sw), Some(t)) = (self.best_energy, self.best_sweep, self.best_time){
+            write!(f, ", best e = {} at sweep {} ({:.1} s)", e, sw, t)?;
+        }
+        if let Some(e) = self.current_energy{
+            write!(f, ", current e = {}", e)?;
+        }
+        if let Some(rt) = self.round_trips{
+            write!(f, ", round trips {}", rt)?;
+        }
+        if let Some(acc) = &self.swap_acceptance{
+            write!(f, ", swap acceptance [")?;
+            for (j, a) in acc.iter().enumerate(){
+                write!(f, "{}{:.2}", if j > 0 { " " } else { "" }, a)?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where progress reports are written
+pub enum ProgressSink{
+    Stderr,
+    JsonLines(Mutex<BufWriter<File>>)
+}
+
+/// Reports the progress of a run at most once every `interval` seconds.
+/// The reporter can be shared between threads, e.g. by the threads of an SA run,
+/// and keeps the best energy reported by any of them.
+pub struct ProgressReporter{
+    interval_ms: u64,
+    start: Instant,
+    next_report_ms: AtomicU64,
+    best: Mutex<Option<(f32, u32, f64)>>,
+    sink: ProgressSink
+}
+
+impl ProgressReporter{
+    pub fn new(interval: f64, sink: ProgressSink) -> Self{
+        let interval_ms = (interval.max(0.0) * 1000.0) as u64;
+        return Self{interval_ms, start: Instant::now(), next_report_ms: AtomicU64::new(interval_ms),
+            best: Mutex::new(None), sink};
+    }
+
+    pub fn stderr(interval: f64) -> Self{
+        return Self::new(interval, ProgressSink::Stderr);
+    }
+
+    /// Write the reports as JSON lines to a new file
+    pub fn json_lines(interval: f64, file: &str) -> Result<Self, TamcError>{
+        let f = File::create(file).map_err(|e| TamcError::Io(e, file.to_string()))?;
+        return Ok(Self::new(interval, ProgressSink::JsonLines(Mutex::new(BufWriter::new(f)))));
+    }
+
+    fn elapsed_ms(&self) -> u64{
+        return self.start.elapsed().as_millis() as u64;
+    }
+
+    /// Whether a report is due. Only one caller is told so for each interval.
+    pub fn due(&self) -> bool{
+        let now = self.elapsed_ms();
+        let next = self.next_report_ms.load(Ordering::Relaxed);
+        if now < next{
+            return false;
+        }
+        return self.next_report_ms.compare_exchange(next, now + self.interval_ms,
+                                                    Ordering::Relaxed, Ordering::Relaxed).is_ok();
+    }
+
+    /// Record an energy found at `sweep` after `elapsed` seconds of the run, keeping the lowest
+    pub fn record_energy(&self, energy: f32, sweep: u32, elapsed: f64){
+        let mut best = self.best.lock().unwrap();
+        if best.map_or(true, |(e, _, _)| energy < e){
+            *best = Some((energy, sweep, elapsed));
+        }
+    }
+
+    /// Write a report of the run
+    pub fn report(&self, status: &ProgressStatus){
+        if let Some(e) = status.current_energy{
+            self.record_energy(e, status.sweeps_done, status.elapsed);
+        }
+        let best = *self.best.lock().unwrap();
+        let sweeps_per_second = if status.elapsed > 0.0 { status.sweeps_done as f64 / status.elapsed } else { 0.0 };
+        let eta = if sweeps_per_second > 0.0 {
+            Some(status.total_sweeps.saturating_sub(status.sweeps_done) as f64 / sweeps_per_second)
+        } else {
+            None
+        };
+        let swap_acceptance = status.swap_acceptances.as_ref().map(|acc|
+            acc.iter().map(|&a| a as f64 / status.swap_attempts.max(1) as f64).collect());
+        let report = ProgressReport{
+            method: status.method.to_string(),
+            sweeps_done: status.sweeps_done,
+            total_sweeps: status.total_sweeps,
+            elapsed: status.elapsed,
+            sweeps_per_second,
+            eta,
+            best_energy: best.map(|b| b.0),
+            best_sweep: best.map(|b| b.1),
+            best_time: best.map(|b| b.2),
+            current_energy: status.current_energy,
+            swap_acceptance,
+            round_trips: status.round_trips
+        };
+        match &self.sink{
+            ProgressSink::Stderr => eprintln!("{}", report),
+            ProgressSink::JsonLines(f) => {
+                let mut f = f.lock().unwrap();
+                // progress is best effort, so a failed write does not stop the run
+                if serde_json::to_writer(&mut *f, &report).is_ok(){
+                    let _ = writeln!(f).and_then(|_| f.flush());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::progress::{ProgressReport, ProgressReporter};
+    use crate::pt::{BetaOptions, PtIcmParams, PtIcmRunner};
+
+    #[test]
+    fn test_progress_reports(){
+        let instance = make_ising_2d_instance(8);
+        let params = PtIcmParams{
+            num_sweeps: 2000,
+            beta: BetaOptions::new_geometric(0.2, 2.0, 8),
+            lo_num_beta: Some(4),
+            ..Default::default()
+        };
+        let file = std::env::temp_dir().join(format!("tamc_progress_{}.jsonl", std::process::id()));
+        let file = file.to_str().unwrap();
+        let progress = ProgressReporter::json_lines(0.0, file).unwrap();
+        let (results, _, _) = PtIcmRunner::new(&instance, &params).unwrap()
+            .with_progress(&progress).run(None).unwrap();
+        let reports: Vec<ProgressReport> = std::fs::read_to_string(file).unwrap().lines()
+            .map(|l| serde_json::from_str(l).unwrap()).collect();
+        std::fs::remove_file(file).unwrap();
+        assert!(!reports.is_empty());
+        let last = reports.last().unwrap();
+        assert_eq!(last.method, "PT");
+        assert_eq!(last.total_sweeps, 2000);
+        assert!(reports.windows(2).all(|r| r[0].sweeps_done < r[1].sweeps_done));
+        assert!(last.eta.unwrap() >= 0.0);
+        assert_eq!(last.swap_acceptance.as_ref().unwrap().len(), 7);
+        assert!(last.swap_acceptance.as_ref().unwrap().iter().all(|&a| a >= 0.0 && a <= 1.0));
+        assert!(last.best_energy.unwrap() <= *results.min_results.gs_energies.last().unwrap());
+        assert!(last.best_sweep.unwrap() <= last.sweeps_done);
+    }
+}
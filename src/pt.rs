@@ -2,21 +2,20 @@ use std::cmp::min;
 use std::ffi::OsStr;
 use std::fmt::Formatter;
 use std::fs::File;
-use std::ops::{AddAssign, Index, IndexMut};
+use std::ops::{AddAssign, Index, IndexMut, Range};
 use std::path::Path;
 use std::time;
 
-use fixedbitset::FixedBitSet;
-use itertools::Itertools;
+use anyhow::Context;
 use log::{debug, info, warn};
 use ndarray::prelude::*;
-use petgraph::csr::Csr;
 use rand::prelude::*;
 use rand_xoshiro::Xoshiro256PlusPlus;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use tamc_core::ensembles as ens;
+use tamc_core::icm::{ClusterWorkspace, HoudayerOptions, HoudayerSampler};
 use tamc_core::parallel::ensembles as pens;
 use tamc_core::parallel::pt as ppt;
 use tamc_core::pt as pt;
@@ -24,56 +23,109 @@ use tamc_core::pt::PTState;
 use tamc_core::sa::geometric_beta_schedule;
 use tamc_core::traits::*;
 
-use crate::{Instance, Prog};
-use crate::ising::{BqmIsingInstance, IsingSampler, IsingState, rand_ising_state};
+use crate::util::{fill_seed, run_in_pool};
+use crate::Prog;
+use crate::control::{self, ControlHandle, RunControl, StopReason};
+use crate::progress::{ProgressReporter, ProgressStatus};
+use crate::warmstart::InitialStates;
+use crate::descent::{DescentKind, QuenchResults};
+use crate::ising::{BqmIsingInstance, IsingState};
 use crate::ising_results::MinResults;
+use crate::error::TamcError;
+use crate::validate::{validate_limits, validate_observables, Validation};
+use crate::observe::{ObservableOutput, ObservableSpec, Observer, ObserverFactory, Replicas};
 
-fn houdayer_cluster_move<R: Rng+?Sized>(replica1: &mut IsingState, replica2: &mut IsingState,
-                                        graph: &Csr<(), ()>, rng: &mut R) -> Option<FixedBitSet>{
-    use rand::seq::SliceRandom;
-    use petgraph::visit::Bfs;
-    use petgraph::visit::NodeFiltered;
-    //let n = instance.size();
-    let n = graph.node_count();
-    if n  > u32::MAX as usize{
-        panic!("houdayer_cluster_move: instance size must fit in u32")
-    }
-    let s1 = ArrayView1::from(&replica1.arr);
-    let s2 = ArrayView1::from(&replica2.arr);
-    let overlap: Array1<i8> = &s1 * &s2;
-
-    // Select a random spin with q=-1
-    let mut idxs: Vec<usize> = Vec::new();
-    idxs.reserve(n);
-    for (i, &qi) in overlap.iter().enumerate(){
-        if qi < 0 {
-            idxs.push(i);
-        }
-    }
-    let init_spin = idxs.choose(rng);
-
-    let init_spin = match init_spin{
-        None => return None, // No spin has q=-1
-        Some(&k) => k as u32
-    };
+/// Cluster-size statistics of the ICM moves at each temperature
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IcmClusterStats{
+    /// Number of ICM moves that swapped a non-empty cluster
+    pub num_moves: Vec<u64>,
+    /// Mean size of the swapped clusters
+    pub mean_size: Vec<f64>,
+    /// Mean squared size of the swapped clusters
+    pub mean_sq_size: Vec<f64>,
+    /// Largest cluster swapped
+    pub max_size: Vec<u32>
+}
+
+impl IcmClusterStats{
+    fn new(num_betas: usize) -> Self{
+        return Self{
+            num_moves: vec![0; num_betas],
+            mean_size: vec![0.0; num_betas],
+            mean_sq_size: vec![0.0; num_betas],
+            max_size: vec![0; num_betas]
+        };
+    }
+
+    /// Accumulates a cluster of size `size` at temperature index `i`.
+    /// The running sums are normalized into means by `finalize`
+    fn push(&mut self, i: usize, size: usize){
+        if size == 0{
+            return;
+        }
+        let x = size as f64;
+        self.num_moves[i] += 1;
+        self.mean_size[i] += x;
+        self.mean_sq_size[i] += x * x;
+        self.max_size[i] = self.max_size[i].max(size as u32);
+    }
+
+    fn finalize(&mut self){
+        for ((&m, s), s2) in self.num_moves.iter()
+                .zip(self.mean_size.iter_mut())
+                .zip(self.mean_sq_size.iter_mut()){
+            if m > 0{
+                *s /= m as f64;
+                *s2 /= m as f64;
+            }
+        }
+    }
+}
 
-    let filtered_graph = NodeFiltered::from_fn(graph, |n|overlap[n as usize] < 0);
-    let mut bfs = Bfs::new(&filtered_graph, init_spin);
+/// Reusable ICM workspaces for each replica pair, with a buffer for the cluster sizes of a pair
+struct IcmWorkspaces{
+    workspaces: Vec<Vec<ClusterWorkspace>>,
+    cluster_sizes: Vec<usize>
+}
 
-    while let Some(x) = bfs.next(&filtered_graph){
-        ()
+/// A replica state that PT-ICM can measure and record
+pub trait PtIcmState: ReplicaState + Clone + Send + Sync{
+    /// The overlap between two replicas
+    fn overlap(&self, other: &Self) -> i64;
+    /// Bit-packed state for the thermal samples
+    fn as_bytes(&self) -> Vec<u8>;
+    /// Bit-packed state for the ground state trace
+    fn as_u64_vec(&self) -> Vec<u64>;
+    /// Invalidate any cached energy, e.g. when the state is handed over from another run
+    fn reset_energy(&mut self);
+}
+
+/// An instance that PT-ICM can sample. The sites of the instance graph are the ICM sites,
+/// and `Sampler` is the single temperature sampler swept at each beta with the random number generators of the runner.
+pub trait PtIcmInstance<'a>: GraphInstance + Sync{
+    type State: PtIcmState;
+    type Sampler: Sampler<Xoshiro256PlusPlus, SampleType=Self::State> + Macrostate<f32, Microstate=Self::State> + Sync;
+
+    fn sampler(&'a self, beta: f32) -> Self::Sampler;
+    fn random_state<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> Self::State;
+    /// The energy of a state, which may be cached in the state
+    fn replica_energy(&self, state: &mut Self::State) -> f32;
+    /// Number of weighted overlaps measured in the thermal samples
+    fn num_suscepts(&self) -> usize{
+        return 0;
+    }
+    /// Evaluate each weighted overlap between two replicas into `chi`
+    fn suscepts(&self, _state1: &Self::State, _state2: &Self::State, _chi: &mut [f64]){ }
+    /// Quench the ground state trace, if supported by the instance
+    fn quench(&self, _states: &[Vec<u64>], _kind: DescentKind) -> Option<QuenchResults>{
+        return None;
     }
-    let nodes = bfs.discovered;
-    let cluster_size = nodes.count_ones(..);
-    //println!("cluster size = {}", cluster_size);
-    // Finally, swap all
-    for i in nodes.ones(){
-        unsafe { std::mem::swap(replica1.arr.get_unchecked_mut(i),  replica2.arr.get_unchecked_mut(i)); }
+    /// The observer of a built-in observable, if supported by the instance
+    fn builtin_observer(_spec: &ObservableSpec) -> Option<Box<dyn Observer<Self, Self::State>>>
+    where Self: Sized{
+        return None;
     }
-    // Invalidate energy caches
-    replica1.energy_init=false;
-    replica2.energy_init=false;
-    return Some(nodes);
 }
 
 #[derive(Debug, Clone)]
@@ -98,17 +150,31 @@ pub struct PtIcmMinResults{
     pub params: PtIcmParams,
     #[serde(flatten)]
     pub min_results: MinResults,
-    pub acceptance_counts: Vec<u32>
+    pub acceptance_counts: Vec<u32>,
+    pub icm_cluster_stats: IcmClusterStats,
+    /// Quench of the ground state trace, in the same order as gs_states
+    pub quench: Option<QuenchResults>,
+    pub stop_reason: Option<StopReason>,
+    /// Number of sweeps run before stopping
+    pub sweeps_completed: Option<u32>,
+    /// Outputs of the observers, in the order they were registered after the built-in observables
+    pub observables: Option<Vec<ObservableOutput>>
 }
 
 impl PtIcmMinResults{
     fn new(params: PtIcmParams, num_betas: u32, instance_size: u32) -> Self{
         let min_results = MinResults::new(num_betas, instance_size);
         let acceptance_counts = Array1::zeros(num_betas as usize).into_raw_vec();
+        let icm_cluster_stats = IcmClusterStats::new(num_betas as usize);
         return Self{
             params,
             min_results,
-            acceptance_counts
+            acceptance_counts,
+            icm_cluster_stats,
+            quench: None,
+            stop_reason: None,
+            sweeps_completed: None,
+            observables: None
         };
     }
 }
@@ -164,33 +230,25 @@ impl PtIcmThermalSamples{
         return me;
     }
 
-    fn measure(&mut self, pt_state: &mut Vec<pt::PTState<IsingState>>, instance:& BqmIsingInstance) {
+    fn measure<'a, I: PtIcmInstance<'a>>(&mut self, pt_state: &mut Vec<pt::PTState<I::State>>, instance: &I) {
         let num_chains = pt_state.len();
         let num_betas = pt_state[0].states.len();
-        let n = pt_state[0].states[0].arr.len();
-        let nchi = instance.suscept_coefs.len();
-        let mut overlap_vec : Vec<i8> = Vec::new();
-        if nchi > 0 {
-            overlap_vec.resize(n, 0);
-        }
+        let nchi = instance.num_suscepts();
+        let mut chi_vec = vec![0.0; nchi];
 
         for i in 0..num_betas{
             for j in 0..num_chains{
                 let isn = &mut pt_state[j].states[i];
-                let e = instance.energy(isn);
+                let e = instance.replica_energy(isn);
                 self.e[i].push(e as f32);
             }
             for j in 0..(num_chains/2) {
                 let isn1 = &pt_state[2*j].states[i];
                 let isn2 = &pt_state[2*j+1].states[i];
                 if nchi > 0{
-                    for (qi,(&s1, &s2)) in overlap_vec.iter_mut().zip_eq(
-                        isn1.arr.iter().zip_eq(isn2.arr.iter())){
-                        *qi = s1 * s2;
-                    }
+                    instance.suscepts(isn1, isn2, &mut chi_vec);
                     for k in 0..nchi{
-                        let chi = instance.suscept(&overlap_vec, k);
-                        self.suscept[i][k].push(chi as f32);
+                        self.suscept[i][k].push(chi_vec[k] as f32);
                     }
                 }
 
@@ -199,7 +257,7 @@ impl PtIcmThermalSamples{
             }
         }
     }
-    fn sample_states(&mut self, pt_state: & Vec<pt::PTState<IsingState>>) {
+    fn sample_states<St: PtIcmState>(&mut self, pt_state: & Vec<pt::PTState<St>>) {
         let num_chains = pt_state.len();
         let num_betas = pt_state[0].states.len();
         if self.compression_level == 0 {
@@ -243,6 +301,33 @@ impl BetaOptions{
     pub fn new_geometric(beta_min: f32, beta_max: f32, num_beta: u32) -> Self{
         return BetaOptions::Geometric(BetaSpec{beta_min, beta_max, num_beta});
     }
+    /// Check that the schedule is a non-empty, non-decreasing sequence of positive betas
+    pub fn validate(&self, field: &str, v: &mut Validation){
+        match self{
+            BetaOptions::Geometric(b) => {
+                if b.num_beta == 0{
+                    v.error(field, "num_beta must be positive", "set num_beta to the number of temperatures, e.g. 32");
+                }
+                if !(b.beta_min > 0.0){
+                    v.error(field, format!("a geometric schedule requires beta_min > 0, but beta_min = {}", b.beta_min),
+                            "set beta_min to a small positive value, e.g. 0.1");
+                } else if !(b.beta_max >= b.beta_min){
+                    v.error(field, format!("beta_max = {} is less than beta_min = {}", b.beta_max, b.beta_min),
+                            "swap beta_min and beta_max");
+                }
+            }
+            BetaOptions::Arr(arr) => {
+                if arr.is_empty(){
+                    v.error(field, "the beta array is empty", "list at least one beta");
+                } else if !arr.iter().all(|&b| b >= 0.0 && b.is_finite()){
+                    v.error(field, "the beta array contains negative or non-finite values",
+                            "use only finite, non-negative betas");
+                } else if !arr.windows(2).all(|b| b[1] >= b[0]){
+                    v.error(field, "the beta array must be non-decreasing", "sort the beta array in increasing order");
+                }
+            }
+        }
+    }
     pub fn get_beta_arr(&self) -> Vec<f32>{
         return match &self {
             BetaOptions::Geometric(b) => {
@@ -264,11 +349,22 @@ pub struct PtIcmParams {
     pub lo_beta: Option<f32>,
     pub lo_num_beta: Option<u32>,
     pub icm: bool,
+    pub icm_max_cluster_size: Option<u32>,
+    pub icm_max_cluster_fraction: Option<f64>,
     pub num_replica_chains: u32,
     pub threads: u32,
     pub sample: Option<u32>,
     pub sample_states: Option<u32>,
-    pub sample_limiting: Option<u8>
+    pub sample_limiting: Option<u8>,
+    /// Quench every recorded ground state into a local minimum after the run
+    pub quench: Option<DescentKind>,
+    /// Stop after this many seconds
+    pub time_limit: Option<f64>,
+    /// Stop once a replica reaches this energy.
+    /// The minimum energy is then also measured during the warmup sweeps.
+    pub target_energy: Option<f32>,
+    /// Built-in observables to record after the warmup
+    pub observables: Option<Vec<ObservableSpec>>
 }
 
 impl Default for PtIcmParams{
@@ -280,39 +376,124 @@ impl Default for PtIcmParams{
             lo_beta: None,
             lo_num_beta: None,
             icm: true,
+            icm_max_cluster_size: None,
+            icm_max_cluster_fraction: None,
             num_replica_chains: 2,
             threads: 1,
             sample: Some(32),
             sample_states: Some(64),
-            sample_limiting: Some(0)
+            sample_limiting: Some(0),
+            quench: None,
+            time_limit: None,
+            target_energy: None,
+            observables: None
         }
     }
 }
 
 impl PtIcmParams {
-    // pub fn check_options(&self) -> Result<(), PtError>{
-    //
-    //     return Ok(())
-    // }
+    /// Check every parameter and their combinations before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.num_sweeps == 0{
+            v.error("num_sweeps", "num_sweeps must be positive", "set num_sweeps, e.g. to 2000");
+        }
+        if !(0.0..1.0).contains(&self.warmup_fraction){
+            v.error("warmup_fraction", format!("warmup_fraction must be in [0, 1), but is {}", self.warmup_fraction),
+                    "set warmup_fraction to e.g. 0.5");
+        }
+        self.beta.validate("beta", &mut v);
+        let num_betas = self.beta.get_beta_arr().len() as u32;
+        if num_betas == 1{
+            v.warn("beta", "a single temperature makes no replica exchanges", "use at least two betas");
+        }
+        match (self.lo_beta, self.lo_num_beta){
+            (None, None) if self.icm => {
+                v.error("lo_beta", "either lo_beta or lo_num_beta must be specified for ICM",
+                        "set lo_num_beta to the number of the largest betas using ICM, e.g. half of the betas");
+            }
+            (Some(_), Some(_)) => {
+                v.warn("lo_beta", "both lo_beta and lo_num_beta are set, and lo_beta is ignored", "remove one of them");
+            }
+            (_, Some(lo_num_beta)) if lo_num_beta > num_betas && num_betas > 0 => {
+                v.warn("lo_num_beta", format!("lo_num_beta = {} exceeds the {} betas", lo_num_beta, num_betas),
+                       format!("set lo_num_beta to at most {}", num_betas));
+            }
+            (_, Some(0)) if self.icm => {
+                v.warn("lo_num_beta", "lo_num_beta = 0 disables ICM at every temperature",
+                       "set icm: false, or a positive lo_num_beta");
+            }
+            _ => {}
+        }
+        if self.icm_max_cluster_size == Some(0){
+            v.error("icm_max_cluster_size", "the maximum cluster size must be positive", "remove it to allow any cluster size");
+        }
+        if let Some(f) = self.icm_max_cluster_fraction{
+            if !(f > 0.0 && f <= 1.0){
+                v.error("icm_max_cluster_fraction", format!("the maximum cluster fraction must be in (0, 1], but is {}", f),
+                        "set it to e.g. 0.5, or remove it");
+            }
+        }
+        if self.num_replica_chains == 0{
+            v.error("num_replica_chains", "at least one replica chain is required", "set num_replica_chains to e.g. 2");
+        } else if self.num_replica_chains % 2 == 1{
+            if self.icm{
+                v.error("num_replica_chains",
+                        format!("ICM pairs the replica chains, so one of the {} chains would never use ICM", self.num_replica_chains),
+                        format!("use an even number of chains, e.g. {}", self.num_replica_chains + 1));
+            } else {
+                v.warn("num_replica_chains", "overlaps are measured between pairs of chains, so the last chain is left out",
+                       format!("use an even number of chains, e.g. {}", self.num_replica_chains + 1));
+            }
+        }
+        let num_replicas = self.num_replica_chains * num_betas;
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        } else if num_replicas > 0 && self.threads > num_replicas{
+            v.error("threads", format!("{} threads exceed the {} replicas ({} chains x {} betas)",
+                                       self.threads, num_replicas, self.num_replica_chains, num_betas),
+                    format!("set threads to at most {}", num_replicas));
+        }
+        for (field, samp) in [("sample", self.sample), ("sample_states", self.sample_states)]{
+            match samp{
+                Some(0) => v.error(field, format!("the {} interval must be positive", field),
+                                   format!("set {} to a number of sweeps, e.g. 32, or remove it to disable sampling", field)),
+                Some(k) if self.num_sweeps > 0 && k as f64 > (1.0 - self.warmup_fraction) * self.num_sweeps as f64 => {
+                    v.warn(field, format!("the {} interval of {} sweeps is longer than the measurement phase", field, k),
+                           format!("decrease {} or increase num_sweeps", field));
+                }
+                _ => {}
+            }
+        }
+        if let Some(l) = self.sample_limiting{
+            if l > 2{
+                v.warn("sample_limiting", format!("sample_limiting = {} is treated as 2", l),
+                       "use 0 (all temperatures), 1 (lower half) or 2 (lowest temperature)");
+            }
+        }
+        validate_limits(self.time_limit, self.target_energy, &mut v);
+        validate_observables(&self.observables, &mut v);
+        return v;
+    }
 }
-pub struct PtIcmRunner<'a>{
+/// Runs PT-ICM on any instance and state pair implementing `PtIcmInstance`
+pub struct PtIcmRunner<'a, I: PtIcmInstance<'a> = BqmIsingInstance>{
     params: &'a PtIcmParams,
-    instance: &'a BqmIsingInstance,
-    g: Csr<(), ()>,
+    instance: &'a I,
+    icm_sampler: HoudayerSampler<I::State>,
     beta_vec: Vec<f32>,
     meas_init: u32,
-    lo_beta_idx: usize
+    lo_beta_idx: usize,
+    control: Option<ControlHandle<'a>>,
+    progress: Option<&'a ProgressReporter>,
+    observers: Vec<ObserverFactory<'a, I, I::State>>
 }
-impl<'a> PtIcmRunner<'a>{
-    pub fn new(instance: &'a BqmIsingInstance, params: &'a PtIcmParams) -> Self
+impl<'a, I: PtIcmInstance<'a>> PtIcmRunner<'a, I>{
+    pub fn new(instance: &'a I, params: &'a PtIcmParams) -> Result<Self, TamcError>
     {
+        params.validate().into_result()?;
         let beta_vec = params.beta.get_beta_arr();
         let num_betas = beta_vec.len();
-        let beta_arr = Array1::from_vec(beta_vec.clone());
-        let beta_diff : Array1<f32> = beta_arr.slice(s![1..]).to_owned() - beta_arr.slice(s![..-1]);
-        if !beta_diff.iter().all(|&x|x>=0.0) {
-            panic!("beta array must be non-decreasing")
-        }
         let lo_beta_idx;
 
         if let Some(lo_num_beta) = params.lo_num_beta{
@@ -335,32 +516,70 @@ impl<'a> PtIcmRunner<'a>{
             };
         } else {
             lo_beta_idx=0;
-            if params.icm{
-                panic!("Must specify either lo_beta or lo_num_beta for ICM")
-            }
          }
 
-        // Construct csr graph
-        let edges: Vec<_> = instance.coupling.iter()
-            .map(|(_, (i,j))| (i as u32,j as u32)).collect();
-        let g: Csr<(), ()> = Csr::from_sorted_edges(&edges).unwrap();
+        let icm_options = HoudayerOptions{
+            max_cluster_size: params.icm_max_cluster_size.map(|x| x as usize),
+            max_cluster_fraction: params.icm_max_cluster_fraction
+        };
+        let icm_sampler = HoudayerSampler::new(instance, icm_options);
 
         let meas_init = (params.warmup_fraction * (params.num_sweeps as f64)) as u32;
 
-        return Self{params, instance, beta_vec, g, meas_init, lo_beta_idx};
+        for spec in params.observables.iter().flatten(){
+            if I::builtin_observer(spec).is_none(){
+                warn!("The observable {:?} is not supported by the instance and will not be recorded.", spec.observable);
+            }
+        }
+
+        return Ok(Self{params, instance, beta_vec, icm_sampler, meas_init, lo_beta_idx, control: None,
+            progress: None, observers: Vec::new()});
+    }
+
+    /// Register an observer, created anew for each run from `factory`, that is recorded along with
+    /// the built-in observables of the parameters
+    pub fn with_observer<F>(mut self, factory: F) -> Self
+    where F: Fn() -> Box<dyn Observer<I, I::State> + 'a> + Send + Sync + 'a
+    {
+        self.observers.push(Box::new(factory));
+        return self;
+    }
+
+    fn make_observers(&self) -> Vec<Box<dyn Observer<I, I::State> + 'a>>{
+        let mut observers: Vec<Box<dyn Observer<I, I::State> + 'a>> = Vec::new();
+        for spec in self.params.observables.iter().flatten(){
+            observers.extend(I::builtin_observer(spec));
+        }
+        observers.extend(self.observers.iter().map(|f| f()));
+        return observers;
+    }
+
+    /// Stop the run early when the control requests it, and report new minimum energies to it
+    pub fn with_control(mut self, control: ControlHandle<'a>) -> Self{
+        self.control = Some(control);
+        return self;
+    }
+
+    /// Periodically report the progress of the run
+    pub fn with_progress(mut self, progress: &'a ProgressReporter) -> Self{
+        self.progress = Some(progress);
+        return self;
     }
 
 
-    pub fn run_parallel(&self) -> (PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<IsingState>>){
+    pub fn run_parallel(&self) -> Result<(PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<I::State>>), TamcError>{
+        return self.run_parallel_seeded(None);
+    }
+
+    pub fn run_parallel_seeded(&self, initial_state: Option<Vec<PTState<I::State>>>) -> Result<(PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<I::State>>), TamcError>{
         let m = self.params.num_replica_chains;
         let num_betas = self.beta_vec.len();
         // seed and create random number generator
-        let mut rngt = thread_rng();
         let mut seed_seq = [0u8; 32];
-        rngt.fill_bytes(&mut seed_seq);
+        fill_seed(&mut seed_seq);
         let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
         // randomly generate initial states
-        let mut pt_state = self.generate_init_state(&mut rng);
+        let mut pt_state = self.initial_pt_state(initial_state, &mut rng)?;
         // generate ensemble rngs
         let mut rng_vec = Vec::with_capacity(num_betas);
         for _ in 0..m{
@@ -372,52 +591,47 @@ impl<'a> PtIcmRunner<'a>{
             rng_vec.push(rng_chain);
         };
 
-        let (mut pt_results, pt_samps) = self.parallel_pt_loop(&mut pt_state, &mut rng_vec);
+        // the chains and temperatures are advanced in parallel only on a pool of `threads` threads
+        let (mut pt_results, pt_samps) = run_in_pool(self.params.threads,
+                                                     || self.parallel_pt_loop(&mut pt_state, &mut rng_vec));
         self.count_acc(&pt_state, &mut pt_results);
-        return (pt_results, pt_samps, pt_state);
+        return Ok((pt_results, pt_samps, pt_state));
     }
 
-    pub fn run_seeded(&self, initial_state: Option<Vec<PTState<IsingState>>>) -> (PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<IsingState>>){
+    pub fn run_seeded(&self, initial_state: Option<Vec<PTState<I::State>>>) -> Result<(PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<I::State>>), TamcError>{
         // seed and create random number generator
-        let mut rngt = thread_rng();
         let mut seed_seq = [0u8; 32];
-        rngt.fill_bytes(&mut seed_seq);
+        fill_seed(&mut seed_seq);
         let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
         // randomly generate initial states
-        let mut pt_state = match initial_state{
-            None => self.generate_init_state(&mut rng),
-            Some(st) => { st }
-        };
+        let mut pt_state = self.initial_pt_state(initial_state, &mut rng)?;
         let (mut pt_results, pt_samps) = self.pt_loop(&mut pt_state, &mut rng);
         self.count_acc(&pt_state, &mut pt_results);
         //pt_results.final_state = pt_state;
-        return (pt_results, pt_samps, pt_state);
+        return Ok((pt_results, pt_samps, pt_state));
     }
 
-    pub fn run(&self, initial_state: Option<Vec<PTState<IsingState>>>) -> (PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<IsingState>>){
+    pub fn run(&self, initial_state: Option<Vec<PTState<I::State>>>) -> Result<(PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<I::State>>), TamcError>{
         // seed and create random number generator
-        let mut rngt = thread_rng();
         let mut seed_seq = [0u8; 32];
-        rngt.fill_bytes(&mut seed_seq);
+        fill_seed(&mut seed_seq);
         let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
         // randomly generate initial states
-        let mut pt_state = match initial_state{
-            None => self.generate_init_state(&mut rng),
-            Some(st) => { st }
-        };
+        let mut pt_state = self.initial_pt_state(initial_state, &mut rng)?;
         let (mut pt_results, pt_samps) = self.pt_loop(&mut pt_state, &mut rng);
         self.count_acc(&pt_state, &mut pt_results);
         //pt_results.final_state = pt_state;
-        return (pt_results, pt_samps, pt_state);
+        return Ok((pt_results, pt_samps, pt_state));
     }
 
     fn parallel_pt_loop<Rn: Rng+Send>(
-        &self, pt_state: &mut Vec<pt::PTState<IsingState>>,
+        &self, pt_state: &mut Vec<pt::PTState<I::State>>,
         rng_vec: &mut Vec<Vec<Rn>>
     ) -> (PtIcmMinResults, PtIcmThermalSamples)
+    where I::Sampler: Sampler<Rn, SampleType=I::State>
     {
         // Initialize samplers
-        let n = self.instance.size();
+        let n = self.instance.num_sites();
         let num_betas = self.beta_vec.len();
         let num_sweeps = self.params.num_sweeps;
         let num_chains = self.params.num_replica_chains;
@@ -432,37 +646,56 @@ impl<'a> PtIcmRunner<'a>{
             0
         } as usize;
         let samplers: Vec<_> = self.beta_vec.iter()
-            .map(|&b | IsingSampler::new(self.instance,b, n as u32))
+            .map(|&b | self.instance.sampler(b))
             .collect();
         let pt_sampler = ppt::parallel_tempering_sampler(samplers);
         let mut pt_results = PtIcmMinResults::new(self.params.clone(),num_betas as u32, n as u32);
         let mut pt_samps = PtIcmThermalSamples::new(&self.beta_vec, n as u64,samp_capacity,
-                                                    state_samp_capacity, self.instance.suscept_coefs.len() as u32,
+                                                    state_samp_capacity, self.instance.num_suscepts() as u32,
                                                     self.params.sample_limiting.unwrap_or(0));
         let mut pt_chains_sampler = pens::ThreadedEnsembleSampler::new(pt_sampler);
+        let mut icm_workspaces = self.generate_icm_workspaces();
         let mut minimum_e = None;
+        let mut observers = self.make_observers();
         info!("-- PT-ICM begin");
         let start = time::Instant::now();
+        let limits = RunControl::new(self.params.target_energy, self.params.time_limit);
+        pt_results.stop_reason = Some(StopReason::Completed);
+        pt_results.sweeps_completed = Some(num_sweeps);
         for i in 0..num_sweeps{
-            self.apply_icm(pt_state, &mut rng_vec[0][0]);
+            let pt_sampler = &pt_chains_sampler.sub_sampler;
+            let rng = &mut rng_vec[0];
+            self.apply_icm(i, pt_state, &mut icm_workspaces, &mut pt_results.icm_cluster_stats, &mut observers,
+                |ch1, ch2, temps, ws, moves|
+                    pt_sampler.pair_advance(&self.icm_sampler, ch1, ch2, temps, ws, moves, rng));
+            let acc_before = self.acceptances_before_swaps(pt_state, &observers);
             pt_chains_sampler.sweep(pt_state, rng_vec);
-            self.apply_measurements(i, pt_state, &mut minimum_e, &mut pt_results.min_results, &mut pt_samps);
+            self.observe_swaps(i, pt_state, acc_before, &mut observers);
+            if self.end_sweep(i, pt_state, &mut minimum_e, &mut pt_results, &mut pt_samps, &mut observers, &limits){
+                break;
+            }
         }
         let end = start.elapsed();
         info!("-- PT-ICM Finished");
         info!("Duration: {:5.4} s", end.as_secs_f64());
         pt_results.min_results.timing = end.as_micros() as f64;
+        pt_results.icm_cluster_stats.finalize();
+        self.apply_quench(&mut pt_results);
+        if !observers.is_empty(){
+            pt_results.observables = Some(observers.iter().map(|o| o.output()).collect());
+        }
 
         return (pt_results, pt_samps);
     }
 
     pub fn pt_loop<Rn: Rng>(
-        &self, pt_state: &mut Vec<pt::PTState<IsingState>>,
+        &self, pt_state: &mut Vec<pt::PTState<I::State>>,
         rng: &mut Rn
     ) -> (PtIcmMinResults, PtIcmThermalSamples)
+    where I::Sampler: Sampler<Rn, SampleType=I::State>
     {
         // Initialize samplers
-        let n = self.instance.size();
+        let n = self.instance.num_sites();
         let num_betas = self.beta_vec.len();
         let num_sweeps = self.params.num_sweeps;
         let num_chains = self.params.num_replica_chains;
@@ -478,77 +711,212 @@ impl<'a> PtIcmRunner<'a>{
         } as usize;
 
         let samplers: Vec<_> = self.beta_vec.iter()
-            .map(|&b | IsingSampler::new(self.instance,b, n as u32))
+            .map(|&b | self.instance.sampler(b))
             .collect();
         let pt_sampler = pt::parallel_tempering_sampler(samplers);
         let mut pt_results = PtIcmMinResults::new(self.params.clone(),num_betas as u32, n as u32);
 
         let mut pt_samps = PtIcmThermalSamples::new(&self.beta_vec, n as u64, samp_capacity,
-                                                    state_samp_capacity, self.instance.suscept_coefs.len() as u32,
+                                                    state_samp_capacity, self.instance.num_suscepts() as u32,
                                                     self.params.sample_limiting.unwrap_or(0));
         let mut pt_chains_sampler = ens::EnsembleSampler::new(pt_sampler);
+        let mut icm_workspaces = self.generate_icm_workspaces();
         let mut minimum_e = None;
+        let mut observers = self.make_observers();
         info!("-- PT-ICM begin");
         let start = time::Instant::now();
+        let limits = RunControl::new(self.params.target_energy, self.params.time_limit);
+        pt_results.stop_reason = Some(StopReason::Completed);
+        pt_results.sweeps_completed = Some(num_sweeps);
         for i in 0..num_sweeps{
-            self.apply_icm(pt_state, rng);
+            let pt_sampler = &pt_chains_sampler.sub_sampler;
+            self.apply_icm(i, pt_state, &mut icm_workspaces, &mut pt_results.icm_cluster_stats, &mut observers,
+                |ch1, ch2, temps, ws, moves|
+                    pt_sampler.pair_advance(&self.icm_sampler, ch1, ch2, temps, ws, moves, rng));
+            let acc_before = self.acceptances_before_swaps(pt_state, &observers);
             pt_chains_sampler.sweep(pt_state, rng);
-            self.apply_measurements(i, pt_state, &mut minimum_e, &mut pt_results.min_results, &mut pt_samps);
+            self.observe_swaps(i, pt_state, acc_before, &mut observers);
+            if self.end_sweep(i, pt_state, &mut minimum_e, &mut pt_results, &mut pt_samps, &mut observers, &limits){
+                break;
+            }
         }
         let end = start.elapsed();
         info!("-- PT-ICM Finished");
         info!("Duration: {:5.4} s", end.as_secs_f64());
         pt_results.min_results.timing = end.as_micros() as f64;
+        pt_results.icm_cluster_stats.finalize();
+        self.apply_quench(&mut pt_results);
+        if !observers.is_empty(){
+            pt_results.observables = Some(observers.iter().map(|o| o.output()).collect());
+        }
 
         return (pt_results, pt_samps);
     }
 
-    pub fn generate_init_state<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> Vec<pt::PTState<IsingState>>{
+    pub fn generate_init_state<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> Vec<pt::PTState<I::State>>{
         // randomly generate initial states
-        let n = self.instance.size() as u32;
         let num_betas = self.beta_vec.len();
         let mut pt_state = Vec::new();
         for _ in 0..self.params.num_replica_chains{
             let mut init_states = Vec::with_capacity(num_betas);
             for _ in 0..num_betas{
-                init_states.push(rand_ising_state(n, self.instance, rng));
+                init_states.push(self.instance.random_state(rng));
             }
             pt_state.push(pt::PTState::new(init_states));
         }
         return pt_state;
     }
 
-    fn apply_icm<Rn: Rng+?Sized>(&self, pt_state: &mut Vec<pt::PTState<IsingState>>, rng: &mut Rn)
-                                 -> Vec<Option<FixedBitSet>>
+    /// The given initial states, checked to have a state at every temperature of every chain, or else random states
+    fn initial_pt_state<Rn: Rng+?Sized>(&self, initial_state: Option<Vec<PTState<I::State>>>, rng: &mut Rn)
+            -> Result<Vec<PTState<I::State>>, TamcError>{
+        let pt_state = match initial_state{
+            None => return Ok(self.generate_init_state(rng)),
+            Some(st) => st
+        };
+        let num_chains = self.params.num_replica_chains as usize;
+        let num_betas = self.beta_vec.len();
+        if pt_state.len() != num_chains{
+            return Err(TamcError::size_mismatch("initial replica chains", num_chains, pt_state.len()));
+        }
+        if let Some(chain) = pt_state.iter().find(|chain| chain.states_ref().len() != num_betas){
+            return Err(TamcError::size_mismatch("initial states of a replica chain", num_betas, chain.states_ref().len()));
+        }
+        return Ok(pt_state);
+    }
+
+    /// Arrange the given states into replica chains, cycling through them chain by chain
+    /// and from the lowest to the highest temperature
+    pub fn init_state_from(&self, states: &[I::State]) -> Result<Vec<pt::PTState<I::State>>, TamcError>{
+        if states.is_empty(){
+            return Err(TamcError::invalid_parameter("initial_states", "at least one initial state is required"));
+        }
+        let num_betas = self.beta_vec.len();
+        let mut iter = states.iter().cycle();
+        let mut pt_state = Vec::with_capacity(self.params.num_replica_chains as usize);
+        for _ in 0..self.params.num_replica_chains{
+            let mut init_states: Vec<I::State> = iter.by_ref().take(num_betas).cloned().collect();
+            init_states.reverse();
+            for st in init_states.iter_mut(){
+                st.reset_energy();
+            }
+            pt_state.push(pt::PTState::new(init_states));
+        }
+        return Ok(pt_state);
+    }
+
+    /// Workspaces for each replica pair and temperature that ICM is applied to
+    fn generate_icm_workspaces(&self) -> IcmWorkspaces{
+        if !self.params.icm{
+            return IcmWorkspaces{ workspaces: Vec::new(), cluster_sizes: Vec::new() };
+        }
+        let num_pairs = (self.params.num_replica_chains / 2) as usize;
+        let num_icm_betas = self.beta_vec.len() - self.lo_beta_idx;
+        let workspaces = (0..num_pairs).map(
+            |_| (0..num_icm_betas).map(|_| self.icm_sampler.workspace()).collect()
+        ).collect();
+        return IcmWorkspaces{ workspaces, cluster_sizes: vec![0; num_icm_betas] };
+    }
+
+    /// Applies the ICM move between each pair of PT chains at the temperatures above `lo_beta_idx`
+    /// using the pair move `pair_advance` of the PT sampler
+    fn apply_icm<F>(&self, i: u32, pt_state: &mut Vec<pt::PTState<I::State>>,
+                    icm_workspaces: &mut IcmWorkspaces, icm_stats: &mut IcmClusterStats,
+                    observers: &mut [Box<dyn Observer<I, I::State> + 'a>],
+                    mut pair_advance: F)
+    where F: FnMut(&mut PTState<I::State>, &mut PTState<I::State>, Range<usize>,
+                   &mut [ClusterWorkspace], &mut [usize])
     {
         if !self.params.icm{
-            return Vec::new();
+            return;
         }
         // Apply ICM move
         let lo_beta_idx = self.lo_beta_idx;
-        let mut icm_vec = Vec::new();
-        for pt_pairs in pt_state.chunks_exact_mut(2){
+        let num_betas = self.beta_vec.len();
+        let cluster_sizes = &mut icm_workspaces.cluster_sizes;
+        for (p, (pt_pairs, ws)) in pt_state.chunks_exact_mut(2).zip(icm_workspaces.workspaces.iter_mut()).enumerate(){
             let (pt0, pt1) = pt_pairs.split_at_mut(1);
-            for (replica1, replica2) in pt0[0].states_mut()[lo_beta_idx..].iter_mut()
-                .zip(pt1[0].states_mut()[lo_beta_idx..].iter_mut()){
-                let icm_cluster = houdayer_cluster_move(
-                    replica1, replica2, &self.g, rng);
-                icm_vec.push(icm_cluster);
+            pair_advance(&mut pt0[0], &mut pt1[0], lo_beta_idx..num_betas, ws, cluster_sizes);
+            for (k, &c) in cluster_sizes.iter().enumerate(){
+                icm_stats.push(lo_beta_idx + k, c);
+            }
+            for obs in observers.iter_mut(){
+                obs.on_icm(i, p, lo_beta_idx..num_betas, cluster_sizes);
+            }
+        }
+    }
+
+    /// The acceptance counts of each chain before the replica exchanges, if any observer needs them
+    fn acceptances_before_swaps(&self, pt_state: &Vec<pt::PTState<I::State>>,
+                                observers: &[Box<dyn Observer<I, I::State> + 'a>]) -> Option<Vec<Array1<u32>>>{
+        if observers.is_empty(){
+            return None;
+        }
+        return Some(pt_state.iter().map(|ch| ch.num_acceptances.clone()).collect());
+    }
+
+    /// Notify the observers of the replica exchanges accepted in each chain during sweep i
+    fn observe_swaps(&self, i: u32, pt_state: &Vec<pt::PTState<I::State>>, acc_before: Option<Vec<Array1<u32>>>,
+                     observers: &mut [Box<dyn Observer<I, I::State> + 'a>]){
+        if let Some(acc_before) = acc_before{
+            let num_swaps = self.beta_vec.len() - 1;
+            for (j, (ch, acc)) in pt_state.iter().zip(acc_before.iter()).enumerate(){
+                let accepted: Vec<bool> = ch.num_acceptances.iter().zip(acc.iter())
+                    .take(num_swaps).map(|(&a1, &a0)| a1 > a0).collect();
+                for obs in observers.iter_mut(){
+                    obs.on_swap(i, j, &accepted);
+                }
             }
         }
-        return icm_vec;
     }
 
-    fn apply_measurements(&self, i: u32, pt_state: &mut Vec<pt::PTState<IsingState>>,
+    /// Measure after sweep i and check the stopping conditions. Returns true if the run should stop.
+    fn end_sweep(&self, i: u32, pt_state: &mut Vec<pt::PTState<I::State>>,
+                 minimum_e: &mut Option<f32>, pt_results: &mut PtIcmMinResults,
+                 pt_samples: &mut PtIcmThermalSamples, observers: &mut [Box<dyn Observer<I, I::State> + 'a>],
+                 limits: &RunControl) -> bool
+    {
+        let mut new_min = self.apply_measurements(i, pt_state, minimum_e, &mut pt_results.min_results, pt_samples,
+                                                  observers);
+        let reason = control::check_stop(limits, &self.control);
+        if reason.is_some() && i < self.meas_init{
+            // record the lowest energy state even if the run stops during warmup
+            new_min = self.measure_minimum(i, pt_state, minimum_e, &mut pt_results.min_results).or(new_min);
+        }
+        if let Some(e) = new_min{
+            limits.report(0, e);
+            control::report(&self.control, e);
+        }
+        // a new minimum may have just reached the target
+        let reason = reason.or_else(|| control::check_stop(limits, &self.control));
+        if let Some(progress) = self.progress{
+            if let Some(e) = new_min{
+                progress.record_energy(e, i, limits.elapsed());
+            }
+            if progress.due() || reason.is_some() || i + 1 == self.params.num_sweeps{
+                self.report_progress(progress, i, pt_state, limits);
+            }
+        }
+        if let Some(reason) = reason{
+            pt_results.stop_reason = Some(reason);
+            pt_results.sweeps_completed = Some(i + 1);
+            return true;
+        }
+        return false;
+    }
+
+    /// Returns the energy of a new minimum if one was found
+    fn apply_measurements(&self, i: u32, pt_state: &mut Vec<pt::PTState<I::State>>,
                           minimum_e: &mut Option<f32>, pt_results: &mut MinResults,
-                          pt_samples: &mut PtIcmThermalSamples)
+                          pt_samples: &mut PtIcmThermalSamples,
+                          observers: &mut [Box<dyn Observer<I, I::State> + 'a>]) -> Option<f32>
     {
 
         if i >= self.meas_init {
             let stp = i-self.meas_init;
             if let Some(samp_steps) = self.params.sample{
                 if stp % samp_steps == 0 || i == self.params.num_sweeps-1{
-                    pt_samples.measure(pt_state, &self.instance);
+                    pt_samples.measure(pt_state, self.instance);
                 }
             }
             if let Some(state_samp_steps) = self.params.sample_states{
@@ -556,33 +924,76 @@ impl<'a> PtIcmRunner<'a>{
                     pt_samples.sample_states(pt_state);
                 }
             }
+            if observers.iter().any(|obs| stp % obs.interval().max(1) == 0){
+                let replicas = Replicas{betas: &self.beta_vec, chains: pt_state.iter().map(|ch| ch.states_ref()).collect()};
+                for obs in observers.iter_mut().filter(|obs| stp % obs.interval().max(1) == 0){
+                    obs.on_sweep(i, self.instance, &replicas);
+                }
+            }
             // Measure statistics/lowest energy state so far
-            let mut min_energies = Vec::with_capacity(pt_state.len());
-            for pts in pt_state.iter_mut() {
-                let energies : Vec<f32> = pts.states_mut().iter_mut()
-                    .map(|st| self.instance.energy(st)).collect();
-                let (i1, &e1) = energies.iter().enumerate()
-                    .min_by(|&x, &y| x.1.partial_cmp(&y.1).unwrap())
-                    .unwrap();
-                min_energies.push((i1, e1))
-            }
-
-            let (min_e_ch, &(min_idx, min_e)) = min_energies.iter().enumerate()
-                .min_by(|&x, &y| x.1.1.partial_cmp(&y.1.1).unwrap())
+            return self.measure_minimum(i, pt_state, minimum_e, pt_results);
+        } else if self.params.target_energy.is_some(){
+            return self.measure_minimum(i, pt_state, minimum_e, pt_results);
+        }
+        return None;
+    }
+
+    fn measure_minimum(&self, i: u32, pt_state: &mut Vec<pt::PTState<I::State>>,
+                       minimum_e: &mut Option<f32>, pt_results: &mut MinResults) -> Option<f32>
+    {
+        let mut min_energies = Vec::with_capacity(pt_state.len());
+        for pts in pt_state.iter_mut() {
+            let energies : Vec<f32> = pts.states_mut().iter_mut()
+                .map(|st| self.instance.replica_energy(st)).collect();
+            let (i1, &e1) = energies.iter().enumerate()
+                .min_by(|&x, &y| x.1.partial_cmp(&y.1).unwrap())
                 .unwrap();
-            let chain = pt_state[min_e_ch].states_ref();
-            let min_state = &chain[min_idx];
+            min_energies.push((i1, e1))
+        }
 
-            if minimum_e.map_or(true, |x| min_e < x) {
-                *minimum_e = Some(min_e);
-                pt_results.gs_states.push(min_state.as_u64_vec());
-                pt_results.gs_energies.push(min_e);
-                pt_results.gs_time_steps.push(i)
+        let (min_e_ch, &(min_idx, min_e)) = min_energies.iter().enumerate()
+            .min_by(|&x, &y| x.1.1.partial_cmp(&y.1.1).unwrap())
+            .unwrap();
+        let chain = pt_state[min_e_ch].states_ref();
+        let min_state = &chain[min_idx];
+
+        if minimum_e.map_or(true, |x| min_e < x) {
+            *minimum_e = Some(min_e);
+            pt_results.gs_states.push(min_state.as_u64_vec());
+            pt_results.gs_energies.push(min_e);
+            pt_results.gs_time_steps.push(i);
+            return Some(min_e);
+        }
+        return None;
+    }
+
+    /// Report the progress after sweep i, with the swap acceptances and round trips of all chains so far
+    fn report_progress(&self, progress: &ProgressReporter, i: u32, pt_state: &mut Vec<pt::PTState<I::State>>,
+                       limits: &RunControl){
+        let num_swaps = self.beta_vec.len() - 1;
+        let mut swap_acceptances = vec![0; num_swaps];
+        for ch in pt_state.iter(){
+            for (a, &n) in swap_acceptances.iter_mut().zip(ch.num_acceptances.iter()){
+                *a += n;
             }
         }
+        let current_energy = pt_state.iter_mut()
+            .flat_map(|ch| ch.states_mut().iter_mut())
+            .map(|st| self.instance.replica_energy(st))
+            .fold(None, |m: Option<f32>, e| Some(m.map_or(e, |m| m.min(e))));
+        progress.report(&ProgressStatus{
+            method: "PT",
+            sweeps_done: i + 1,
+            total_sweeps: self.params.num_sweeps,
+            elapsed: limits.elapsed(),
+            current_energy,
+            swap_acceptances: Some(swap_acceptances),
+            swap_attempts: self.params.num_replica_chains * (i + 1),
+            round_trips: Some(pt_state.iter().map(|ch| ch.round_trips).sum())
+        });
     }
 
-    fn count_acc(&self, pt_state: & Vec<pt::PTState<IsingState>>, pt_results: &mut PtIcmMinResults){
+    fn count_acc(&self, pt_state: & Vec<pt::PTState<I::State>>, pt_results: &mut PtIcmMinResults){
         let mut acceptance_counts = Array1::zeros(self.beta_vec.len());
         for st in pt_state.iter(){
             acceptance_counts += &st.num_acceptances;
@@ -590,70 +1001,89 @@ impl<'a> PtIcmRunner<'a>{
         pt_results.acceptance_counts = acceptance_counts.into_raw_vec();
     }
 
+    fn apply_quench(&self, pt_results: &mut PtIcmMinResults){
+        if let Some(kind) = self.params.quench{
+            let quench = self.instance.quench(&pt_results.min_results.gs_states, kind);
+            match quench.as_ref().and_then(|q| q.min_post_energy()){
+                Some(e) => info!("Post-quench ground state energy: {}", e),
+                None if quench.is_none() => warn!("The instance does not support quenching. Skipping the quench."),
+                None => {}
+            }
+            pt_results.quench = quench;
+        }
+    }
 }
 pub fn pt_icm_minimize(instance: &BqmIsingInstance,
                        params: &PtIcmParams)
-                       -> PtIcmMinResults
+                       -> Result<PtIcmMinResults, TamcError>
 {
-
-    println!(" ** Parallel Tempering - ICM **");
-    let pticm = PtIcmRunner::new(instance, params);
-    return if params.threads > 1 {
-        pticm.run_parallel().0
+    info!(" ** Parallel Tempering - ICM **");
+    let pticm = PtIcmRunner::new(instance, params)?;
+    return Ok(if params.threads > 1 {
+        pticm.run_parallel()?.0
     } else {
-        pticm.run(None).0
-    }
+        pticm.run(None)?.0
+    });
 }
 
 
-pub fn run_parallel_tempering(prog: &Prog, params: &PtIcmParams){
-    simple_logger::SimpleLogger::new().with_level(log::LevelFilter::Info).env().init().unwrap();
-    let sample_output = prog.sample_output.clone().unwrap_or("samples.bin".to_string());
-    let mut instance = prog.read_instance();
-    if prog.suscepts.len() > 0{
-        instance = instance.with_suscept(&prog.suscepts);
-    }
-    //let results = ising::pt_icm_minimize(&instance,&pt_params);
-    println!(" ** Parallel Tempering - ICM **");
-
-    info!("Number of sweeps: {}", params.num_sweeps);
-    if params.icm {
-        info!("Using ICM")
-    } else{
-        info!("ICM Disabled")
-    }
-    let pticm = PtIcmRunner::new(&instance, &params);
-    let results = if params.threads > 1 {
-        pticm.run_parallel()
+/// Run PT-ICM on the instance, in parallel over the replica chains if more than one thread is requested
+pub fn solve_pt<'a>(instance: &'a BqmIsingInstance, params: &'a PtIcmParams, control: Option<ControlHandle<'a>>,
+                    progress: Option<&'a ProgressReporter>, initial_states: Option<&InitialStates>)
+        -> Result<(PtIcmMinResults, PtIcmThermalSamples, Vec<PTState<IsingState>>), TamcError>{
+    let mut pticm = PtIcmRunner::new(instance, params)?;
+    pticm.control = control;
+    pticm.progress = progress;
+    let num_replicas = params.num_replica_chains as usize * pticm.beta_vec.len();
+    let init_state = initial_states.map(|init| pticm.init_state_from(&init.replicas(instance, num_replicas)))
+        .transpose()?;
+    return if params.threads > 1 {
+        pticm.run_parallel_seeded(init_state)
     } else {
-        pticm.run(None)
+        pticm.run(init_state)
     };
-    let (gs_results, samp_results, _) = results;
+}
+
+/// Print a summary of the results and write them to the output files of the program
+pub fn write_results(prog: &Prog, gs_results: &PtIcmMinResults, samp_results: &PtIcmThermalSamples) -> anyhow::Result<()>{
+    let params = &gs_results.params;
+    let sample_output = prog.sample_output.clone().unwrap_or("samples.bin".to_string());
     println!("PT-ICM Done.");
     println!("** Ground state energy **");
-    println!("  e = {}", gs_results.min_results.gs_energies.last().unwrap());
-    let &nsw = gs_results.min_results.gs_time_steps.last().unwrap();
-    let pct_rem = ((params.num_sweeps - nsw) as f64) / (params.num_sweeps as f64);
-    println!("  nsw = {}", nsw);
-    println!("  % remaining = {:4.3}%", pct_rem*100.0);
-    {
-        let f = File::create(&prog.output_file)
-            .expect("Failed to create yaml output file");
-        serde_yaml::to_writer(f, &gs_results)
-            .expect("Failed to write to yaml file.")
+    if let (Some(e), Some(&nsw)) = (gs_results.min_results.gs_energies.last(), gs_results.min_results.gs_time_steps.last()){
+        let pct_rem = ((params.num_sweeps - nsw) as f64) / (params.num_sweeps as f64);
+        println!("  e = {}", e);
+        println!("  nsw = {}", nsw);
+        println!("  % remaining = {:4.3}%", pct_rem*100.0);
     }
-    {
-        let mut f = File::create(&sample_output)
-            .expect("Failed to create sample output file");
-        let ext = Path::new(&sample_output).extension().and_then(OsStr::to_str);
-        if ext == Some("pkl"){
-            serde_pickle::to_writer(&mut f, &samp_results, serde_pickle::SerOptions::default());
-        } else {
-            bincode::serialize_into(&mut f, &samp_results).expect("Failed to serialize");
+    if let (Some(reason), Some(sweeps)) = (gs_results.stop_reason, gs_results.sweeps_completed){
+        if reason != StopReason::Completed{
+            println!("  stopped on {:?} after {} sweeps", reason, sweeps);
         }
     }
+    {
+        let f = File::create(&prog.output_file)
+            .with_context(|| format!("Failed to create output file {}", prog.output_file))?;
+        serde_yaml::to_writer(f, gs_results)
+            .with_context(|| format!("Failed to write PT results to {}", prog.output_file))?;
+    }
+    write_samples(&sample_output, samp_results)
 }
 
+/// Write the thermal samples in pickle format if the file name ends in `.pkl`, and otherwise in bincode
+pub fn write_samples(sample_output: &str, samp_results: &PtIcmThermalSamples) -> anyhow::Result<()>{
+    let mut f = File::create(sample_output)
+        .with_context(|| format!("Failed to create sample output file {}", sample_output))?;
+    let ext = Path::new(sample_output).extension().and_then(OsStr::to_str);
+    if ext == Some("pkl"){
+        serde_pickle::to_writer(&mut f, samp_results, serde_pickle::SerOptions::default())
+            .with_context(|| format!("Failed to write samples to {}", sample_output))?;
+    } else {
+        bincode::serialize_into(&mut f, samp_results)
+            .with_context(|| format!("Failed to write samples to {}", sample_output))?;
+    }
+    Ok(())
+}
 
 #[cfg(test)]
 mod tests {
@@ -669,8 +1099,123 @@ mod tests {
     use tamc_core::traits::*;
 
     use crate::ising::{BqmIsingInstance, rand_ising_state};
-    use crate::pt::{BetaOptions, pt_icm_minimize, PtIcmParams};
+    use tamc_core::icm::{HoudayerOptions, HoudayerSampler};
+    use crate::ising::{IsingSampler, IsingState};
+    use rand::distributions::Uniform;
+    use crate::descent::DescentKind;
+    use crate::pt::{BetaOptions, pt_icm_minimize, PtIcmInstance, PtIcmParams, PtIcmRunner, PtIcmState};
     use crate::ising::tests::make_ising_2d_instance;
+    use crate::control::{RunControl, StopReason};
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_houdayer_cluster_move(){
+        let l = 8;
+        let n: u32 = l*l;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1234);
+        let instance = make_ising_2d_instance(l as usize);
+        let icm_sampler = HoudayerSampler::new(&instance, HoudayerOptions::default());
+        let mut ws = icm_sampler.workspace();
+        let mut replica1 = rand_ising_state(n, &instance, &mut rng);
+        let mut replica2 = rand_ising_state(n, &instance, &mut rng);
+        for _ in 0..100{
+            let e_tot = instance.energy_ref(&replica1) + instance.energy_ref(&replica2);
+            let q = replica1.overlap(&replica2);
+            let mag = replica1.mag() + replica2.mag();
+            let cluster_size = icm_sampler.pair_advance(&mut replica1, &mut replica2, &mut ws, &mut rng);
+            // The move swaps spins with q=-1, so the overlap and combined magnetization are unchanged
+            assert_eq!(q, replica1.overlap(&replica2));
+            assert_eq!(mag, replica1.mag() + replica2.mag());
+            assert_eq!(cluster_size, ws.cluster().len());
+            assert!(cluster_size <= ((n as i64 - q) / 2) as usize);
+            // On a zero-bias instance the total energy of the pair is conserved
+            let e_tot2 = instance.energy_ref(&replica1) + instance.energy_ref(&replica2);
+            assert!((e_tot - e_tot2).abs() < 1.0e-3);
+        }
+        let cluster_size = icm_sampler.pair_advance(&mut replica1.clone(), &mut replica1, &mut ws, &mut rng);
+        assert_eq!(cluster_size, 0);
+        // Clusters above the maximum size are rejected
+        let options = HoudayerOptions{ max_cluster_size: Some(1), max_cluster_fraction: None };
+        let icm_sampler = HoudayerSampler::new(&instance, options);
+        let mut replica1 = instance_ground_state(n);
+        let mut replica2 = replica1.clone();
+        for i in 0..n{ replica2.accept_move(i); }
+        let cluster_size = icm_sampler.pair_advance(&mut replica1, &mut replica2, &mut ws, &mut rng);
+        assert_eq!(cluster_size, 0);
+        assert_eq!(replica1.mag(), n as i64);
+    }
+
+    /// The cluster move before the adjacency-list sampler: a petgraph BFS over the sites with q=-1,
+    /// allocating the overlap, the differing sites and the discovered set on every move
+    fn petgraph_cluster_move<R: Rng+?Sized>(replica1: &mut IsingState, replica2: &mut IsingState,
+                                            graph: &petgraph::csr::Csr<(), ()>, rng: &mut R) -> usize{
+        use petgraph::visit::{Bfs, NodeFiltered};
+        let overlap: Vec<i8> = replica1.arr.iter().zip(replica2.arr.iter()).map(|(&s1, &s2)| s1 * s2).collect();
+        let idxs: Vec<u32> = (0..overlap.len() as u32).filter(|&i| overlap[i as usize] < 0).collect();
+        let init_spin = match idxs.choose(rng){
+            None => return 0,
+            Some(&k) => k
+        };
+        let filtered_graph = NodeFiltered::from_fn(graph, |i| overlap[i as usize] < 0);
+        let mut bfs = Bfs::new(&filtered_graph, init_spin);
+        while bfs.next(&filtered_graph).is_some(){ }
+        for i in bfs.discovered.ones(){
+            std::mem::swap(&mut replica1.arr[i], &mut replica2.arr[i]);
+        }
+        replica1.energy_init = false;
+        replica2.energy_init = false;
+        return bfs.discovered.count_ones(..);
+    }
+
+    /// Time the cluster move against the petgraph move it replaced, for replicas differing in a fraction p of sites,
+    /// and against a Metropolis sweep of one replica. Both moves scan every site to select the initial site,
+    /// but the adjacency-list move allocates nothing.
+    /// Run with `cargo test --release bench_houdayer_cluster_move -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_houdayer_cluster_move(){
+        let l = 128;
+        let n: u32 = (l*l) as u32;
+        let num_moves = 1000;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1234);
+        let instance = make_ising_2d_instance(l);
+        let graph = instance.to_csr_graph();
+        let icm_sampler = HoudayerSampler::new(&instance, HoudayerOptions::default());
+        let mut ws = icm_sampler.workspace();
+        let metropolis = IsingSampler::new(&instance, 1.0, n);
+        println!("{:>6} {:>12} {:>12} {:>12} {:>12}", "p", "cluster", "sampler (us)", "petgraph (us)", "sweep (us)");
+        for p in [0.001, 0.01, 0.1, 0.5]{
+            let mut replica1 = rand_ising_state(n, &instance, &mut rng);
+            let mut replica2 = replica1.clone();
+            for i in 0..n{
+                if rng.gen_bool(p){ replica2.accept_move(i); }
+            }
+            let q = replica1.overlap(&replica2);
+            let mut cluster_sum = 0;
+            let start = std::time::Instant::now();
+            for _ in 0..num_moves{
+                cluster_sum += icm_sampler.pair_advance(&mut replica1, &mut replica2, &mut ws, &mut rng);
+            }
+            let t_sampler = start.elapsed().as_secs_f64() * 1.0e6 / num_moves as f64;
+            let start = std::time::Instant::now();
+            for _ in 0..num_moves{
+                petgraph_cluster_move(&mut replica1, &mut replica2, &graph, &mut rng);
+            }
+            let t_petgraph = start.elapsed().as_secs_f64() * 1.0e6 / num_moves as f64;
+            assert_eq!(q, replica1.overlap(&replica2));
+            let start = std::time::Instant::now();
+            for _ in 0..num_moves{
+                metropolis.sweep(&mut replica1, &mut rng);
+            }
+            let t_sweep = start.elapsed().as_secs_f64() * 1.0e6 / num_moves as f64;
+            println!("{:>6} {:>12.1} {:>12.2} {:>12.2} {:>12.2}", p, cluster_sum as f64 / num_moves as f64,
+                     t_sampler, t_petgraph, t_sweep);
+        }
+    }
+
+    fn instance_ground_state(n: u32) -> IsingState{
+        IsingState{arr: [1].repeat(n as usize), energy: 0.0, energy_init: false}
+    }
 
     #[test]
     fn test_ising_2d_pt(){
@@ -698,10 +1243,11 @@ mod tests {
         let mut pt_icm_params = PtIcmParams::default();
         pt_icm_params.num_sweeps = num_sweeps;
         pt_icm_params.beta = BetaOptions::new_geometric(0.1, 10.0, num_betas as u32);
+        pt_icm_params.lo_beta = Some(1.0);
         let opts_str = serde_yaml::to_string(&pt_icm_params).unwrap();
         println!("{}", opts_str);
         let beta_arr = pt_icm_params.beta.get_beta_arr();
-        let pt_results = pt_icm_minimize(&instance,  &pt_icm_params);
+        let pt_results = pt_icm_minimize(&instance,  &pt_icm_params).unwrap();
         for (&e, &t) in pt_results.min_results.gs_energies.iter()
                 .zip(pt_results.min_results.gs_time_steps.iter()){
             println!("t={}, e = {}", t, e)
@@ -711,5 +1257,155 @@ mod tests {
         for (&b, &p) in beta_arr.iter().zip(acc_prob.iter()){
             println!("beta {} : acc_p = {}", b, p)
         }
+        let icm_stats = &pt_results.icm_cluster_stats;
+        for (&b, (&m, &c)) in beta_arr.iter().zip(icm_stats.num_moves.iter().zip(icm_stats.mean_size.iter())){
+            println!("beta {} : icm moves = {}, mean cluster size = {}", b, m, c)
+        }
+    }
+
+    #[test]
+    fn test_ising_2d_pt_early_stop(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        let mut params = PtIcmParams::default();
+        params.num_sweeps = 1_000_000;
+        params.beta = BetaOptions::new_geometric(0.2, 2.0, 8);
+        params.lo_num_beta = Some(4);
+        params.target_energy = Some(-2.0 * (l*l) as f32);
+        params.time_limit = Some(60.0);
+        let (results, _, _) = PtIcmRunner::new(&instance, &params).unwrap().run(None).unwrap();
+        let sweeps = results.sweeps_completed.unwrap();
+        println!("stopped after {} sweeps: {:?}", sweeps, results.stop_reason);
+        assert_eq!(results.stop_reason, Some(StopReason::TargetEnergy));
+        assert_eq!(*results.min_results.gs_energies.last().unwrap(), -2.0 * (l*l) as f32);
+        assert_eq!(*results.min_results.gs_time_steps.last().unwrap(), sweeps - 1);
+
+        // a time limit that has expired by the end of the first sweep stops the run there
+        params.target_energy = None;
+        params.time_limit = Some(1.0e-9);
+        let (results, _, _) = PtIcmRunner::new(&instance, &params).unwrap().run(None).unwrap();
+        assert_eq!(results.stop_reason, Some(StopReason::TimeLimit));
+        assert_eq!(results.sweeps_completed, Some(1));
+        assert!(!results.min_results.gs_energies.is_empty());
+
+        // as does a stop requested through the control before the run
+        params.time_limit = None;
+        let control = RunControl::new(None, None);
+        control.request_stop();
+        let (results, _, _) = PtIcmRunner::new(&instance, &params).unwrap()
+            .with_control(control.handle(0)).run(None).unwrap();
+        assert_eq!(results.stop_reason, Some(StopReason::Requested));
+        assert_eq!(results.sweeps_completed, Some(1));
+        assert!(PtIcmRunner::new(&instance, &params).unwrap().init_state_from(&[]).is_err());
+        assert!(PtIcmRunner::new(&instance, &params).unwrap().run(Some(Vec::new())).is_err());
+    }
+
+    /// A ferromagnetic ring of boolean sites, sampled without any of the Ising types
+    struct Ring{ n: u32 }
+
+    #[derive(Clone)]
+    struct RingState{ sites: Vec<bool> }
+
+    impl State<u32> for RingState{
+        fn accept_move(&mut self, mv: u32){
+            self.sites[mv as usize] ^= true;
+        }
+    }
+
+    impl ReplicaState for RingState{
+        fn site_differs(&self, other: &Self, i: u32) -> bool{
+            self.sites[i as usize] != other.sites[i as usize]
+        }
+        fn exchange_site(&mut self, other: &mut Self, i: u32){
+            std::mem::swap(&mut self.sites[i as usize], &mut other.sites[i as usize]);
+        }
     }
-}
\ No newline at end of file
+
+    impl PtIcmState for RingState{
+        fn overlap(&self, other: &Self) -> i64{
+            self.sites.iter().zip(other.sites.iter()).map(|(a, b)| if a == b { 1 } else { -1 }).sum()
+        }
+        fn as_bytes(&self) -> Vec<u8>{
+            self.sites.iter().map(|&b| b as u8).collect()
+        }
+        fn as_u64_vec(&self) -> Vec<u64>{
+            self.sites.iter().map(|&b| b as u64).collect()
+        }
+        fn reset_energy(&mut self){ }
+    }
+
+    impl Instance<u32, RingState> for Ring{
+        type Energy = f32;
+        fn energy_ref(&self, state: &RingState) -> f32{
+            let s = &state.sites;
+            (0..s.len()).map(|i| if s[i] == s[(i+1) % s.len()] { -1.0 } else { 1.0 }).sum()
+        }
+        fn energy(&self, state: &mut RingState) -> f32{
+            self.energy_ref(state)
+        }
+        unsafe fn delta_energy(&self, state: &mut RingState, mv: &u32) -> f32{
+            let s = &state.sites;
+            let n = s.len();
+            let i = *mv as usize;
+            let e_i = |j: usize| if s[i] == s[j] { -1.0 } else { 1.0 };
+            -2.0 * (e_i((i + 1) % n) + e_i((i + n - 1) % n))
+        }
+        fn size(&self) -> usize{
+            self.n as usize
+        }
+    }
+
+    impl GraphInstance for Ring{
+        fn num_sites(&self) -> usize{
+            self.n as usize
+        }
+        fn for_each_neighbor<F: FnMut(u32)>(&self, i: u32, mut f: F){
+            f((i + 1) % self.n);
+            f((i + self.n - 1) % self.n);
+        }
+    }
+
+    impl<'a> PtIcmInstance<'a> for Ring{
+        type State = RingState;
+        type Sampler = MetropolisSampler<'a, f32, u32, RingState, Ring, Uniform<u32>>;
+        fn sampler(&'a self, beta: f32) -> Self::Sampler{
+            MetropolisSampler::new_uniform(self, beta, self.n)
+        }
+        fn random_state<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> RingState{
+            RingState{ sites: (0..self.n).map(|_| rng.gen()).collect() }
+        }
+        fn replica_energy(&self, state: &mut RingState) -> f32{
+            self.energy_ref(state)
+        }
+    }
+
+    #[test]
+    fn test_generic_pt_icm(){
+        let ring = Ring{ n: 32 };
+        let mut params = PtIcmParams::default();
+        params.num_sweeps = 2000;
+        params.beta = BetaOptions::new_geometric(0.2, 4.0, 8);
+        params.lo_num_beta = Some(4);
+        params.num_replica_chains = 4;
+        params.sample = Some(10);
+        params.sample_states = Some(10);
+        params.quench = Some(DescentKind::Greedy);
+        for threads in [1, 2]{
+            params.threads = threads;
+            let runner = PtIcmRunner::new(&ring, &params).unwrap();
+            let (results, samples, final_state) = with_seed(Some(1234), || if threads > 1 {
+                runner.run_parallel()
+            } else {
+                runner.run(None)
+            }).unwrap();
+            assert_eq!(*results.min_results.gs_energies.last().unwrap(), -32.0);
+            // the ring has no quench, so it is skipped
+            assert!(results.quench.is_none());
+            assert_eq!(samples.e.len(), 8);
+            assert_eq!(samples.q[0].len(), samples.e[0].len() / 2);
+            assert!(samples.q.iter().flatten().all(|&q| q.abs() <= 32));
+            assert_eq!(samples.samples[0][0].len(), 32);
+            assert_eq!(final_state.len(), params.num_replica_chains as usize);
+        }
+    }
+}
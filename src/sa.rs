@@ -5,6 +5,7 @@ use ndarray::prelude::*;
 use serde::{Serialize, Deserialize};
 use petgraph::csr::Csr;
 use rayon::prelude::*;
+use crate::util::{fill_seed, run_in_pool};
 use crate::pt::BetaOptions;
 use crate::ising::{Spin, BqmIsingInstance, IsingState, rand_ising_state};
 use rand::distributions::Uniform;
@@ -12,17 +13,66 @@ use rand::prelude::*;
 use rand_xoshiro::Xoshiro256PlusPlus;
 use tamc_core::traits::Instance;
 use tamc_core::sa;
+use tamc_core::sa::AisEstimate;
+use crate::descent::{DescentKind, QuenchResults};
 use std::{iter, time};
 use itertools::{Itertools, min};
 use log::{debug, info};
 use tamc_core::metropolis::MetropolisSampler;
 use crate::Prog;
+use crate::error::TamcError;
+use crate::validate::{validate_limits, validate_observables, Validation};
+use crate::observe::{ising_observer_factories, ObservableOutput, ObservableSpec, Observer, ObserverFactory, Replicas};
+use anyhow::Context;
+use crate::control::{self, ControlHandle, RunControl, StopReason};
+use crate::progress::{ProgressReporter, ProgressStatus};
+use crate::warmstart::InitialStates;
+use std::ops::ControlFlow;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct SaParams {
     pub beta: BetaOptions,
     pub num_replicas: u32,
-    pub threads: u32
+    pub threads: u32,
+    /// Track annealed importance sampling weights to estimate log Z at the final beta
+    pub ais: Option<bool>,
+    /// Quench every final state into a local minimum
+    pub quench: Option<DescentKind>,
+    /// Stop annealing after this many seconds
+    pub time_limit: Option<f64>,
+    /// Stop annealing once a replica reaches this energy.
+    /// The replica energies are then evaluated after every sweep.
+    pub target_energy: Option<f32>,
+    /// Built-in observables to record along the schedule
+    pub observables: Option<Vec<ObservableSpec>>
+}
+
+impl SaParams{
+    /// Check every parameter and their combinations before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        self.beta.validate("beta", &mut v);
+        if self.num_replicas == 0{
+            v.error("num_replicas", "at least one replica is required", "set num_replicas to e.g. 32");
+        }
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        } else if self.num_replicas > 0 && self.threads > self.num_replicas{
+            v.error("threads", format!("{} threads exceed the {} replicas", self.threads, self.num_replicas),
+                    format!("set threads to at most {}", self.num_replicas));
+        } else if self.num_replicas % self.threads != 0{
+            v.warn("num_replicas", format!("{} replicas do not split evenly between {} threads, so only {} are run",
+                                           self.num_replicas, self.threads, self.threads * (self.num_replicas / self.threads)),
+                   "use a multiple of threads for num_replicas");
+        }
+        if self.ais == Some(true) && (self.time_limit.is_some() || self.target_energy.is_some()){
+            v.warn("ais", "AIS runs always complete the schedule, so time_limit and target_energy are ignored",
+                   "remove time_limit and target_energy, or disable ais");
+        }
+        validate_limits(self.time_limit, self.target_energy, &mut v);
+        validate_observables(&self.observables, &mut v);
+        return v;
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -31,6 +81,15 @@ pub struct AnnealMinResults {
     pub timing: f64,
     pub min_energy: f32,
     pub energies: Vec<f32>,
+    pub ais_log_weights: Option<Vec<f64>>,
+    pub ais: Option<AisEstimate>,
+    /// Pre- and post-quench energies. energies and min_energy are after the quench
+    pub quench: Option<QuenchResults>,
+    pub stop_reason: Option<StopReason>,
+    /// Number of schedule steps run before stopping, the largest of any thread
+    pub sweeps_completed: Option<u32>,
+    /// Outputs of the observers, joined over the replicas of all threads
+    pub observables: Option<Vec<ObservableOutput>>
 }
 
 impl AnnealMinResults {
@@ -39,7 +98,13 @@ impl AnnealMinResults {
             params,
             min_energy: 0.0,
             energies: Vec::new(),
-            timing: 0.0
+            timing: 0.0,
+            ais_log_weights: None,
+            ais: None,
+            quench: None,
+            stop_reason: None,
+            sweeps_completed: None,
+            observables: None
         };
     }
 
@@ -49,9 +114,51 @@ impl AnnealMinResults {
             .map(|x| x.timing).sum();
         let min_energy = vec_results.iter().map(|x|x.min_energy)
             .min_by(|x,y|x.partial_cmp(y).unwrap()).unwrap();
+        let quench = if vec_results.iter().all(|x| x.quench.is_some()){
+            let mut quench = vec_results.first().unwrap().quench.clone().unwrap();
+            for x in vec_results.iter().skip(1){
+                let q = x.quench.as_ref().unwrap();
+                quench.pre_energies.extend_from_slice(&q.pre_energies);
+                quench.post_energies.extend_from_slice(&q.post_energies);
+                quench.num_flips.extend_from_slice(&q.num_flips);
+            }
+            Some(quench)
+        } else {
+            None
+        };
+        let ais_log_weights = if vec_results.iter().all(|x| x.ais_log_weights.is_some()){
+            Some(vec_results.iter().map(|x| x.ais_log_weights.clone().unwrap()).concat())
+        } else {
+            None
+        };
+        // all threads share the stopping conditions, so the first early stop applies to every thread
+        let stop_reason = vec_results.iter().filter_map(|x| x.stop_reason)
+            .find(|&r| r != StopReason::Completed)
+            .or(vec_results.first().unwrap().stop_reason);
+        let sweeps_completed = vec_results.iter().filter_map(|x| x.sweeps_completed).max();
+        let observables = if vec_results.iter().all(|x| x.observables.is_some()){
+            let mut per_thread = vec_results.iter().map(|x| x.observables.clone().unwrap()).collect_vec();
+            let num_observables = per_thread[0].len();
+            Some((0..num_observables).rev()
+                .map(|_| ObservableOutput::join_replicas(per_thread.iter_mut().map(|o| o.pop().unwrap()).collect()).unwrap())
+                .collect_vec().into_iter().rev().collect())
+        } else {
+            None
+        };
         let energies = vec_results.into_iter().map(|x|x.energies).concat();
-        return Self{params, timing, min_energy, energies};
+        return Self{params, timing, min_energy, energies, ais_log_weights, ais: None, quench,
+            stop_reason, sweeps_completed, observables};
     }
+
+    /// Estimate log Z at the final beta from the AIS log weights of all replicas,
+    /// starting from uniformly random states with Z(0) = 2^N
+    fn apply_ais_estimate(&mut self, instance_size: usize){
+        if let Some(log_weights) = &self.ais_log_weights{
+            let log_z0 = (instance_size as f64) * f64::ln(2.0);
+            self.ais = Some(AisEstimate::from_log_weights(log_weights, log_z0));
+        }
+    }
+
     fn apply_measurements(&mut self, instance: &BqmIsingInstance, sa_state: &mut Vec<IsingState>)
     {
         let energies: Vec<f32> = sa_state.iter().map(|st| instance.energy_ref(st)).collect();
@@ -86,32 +193,98 @@ impl AnnealState{
             instance_size, num_replicas, bytes
         }
     }
+
+    /// The states of the replicas
+    pub fn states(&self) -> Result<Vec<IsingState>, TamcError>{
+        let bpr = (self.instance_size as usize + 7) / 8;
+        if self.bytes.len() != bpr * self.num_replicas as usize{
+            return Err(TamcError::size_mismatch("anneal state bytes", bpr * self.num_replicas as usize, self.bytes.len()));
+        }
+        return Ok(self.bytes.chunks(bpr.max(1)).take(self.num_replicas as usize)
+            .map(|b| IsingState::from_bytes(b, self.instance_size).unwrap())
+            .collect());
+    }
 }
-struct SaRunner<'a>{
+pub struct SaRunner<'a>{
     params: &'a SaParams,
     instance: &'a BqmIsingInstance,
-    beta_vec: Vec<f32>
+    beta_vec: Vec<f32>,
+    control: Option<ControlHandle<'a>>,
+    progress: Option<&'a ProgressReporter>,
+    observers: Vec<ObserverFactory<'a, BqmIsingInstance, IsingState>>
 }
 impl<'a> SaRunner<'a>{
-    pub fn new(instance: &'a BqmIsingInstance, params: &'a SaParams) -> Self
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a SaParams) -> Result<Self, TamcError>
     {
+        params.validate().into_result()?;
         let beta_vec = params.beta.get_beta_arr();
-        let num_betas = beta_vec.len();
         let beta_arr = Array1::from_vec(beta_vec.clone());
-        let beta_diff : Array1<f32> = beta_arr.slice(s![1..]).to_owned() - beta_arr.slice(s![..-1]);
-        if !beta_diff.iter().all(|&x|x>=0.0) {
-            panic!("beta array must be non-decreasing")
-        }
         debug!("Temperature (beta) array:\n\t {:5.4} ", beta_arr);
 
-        return Self{params, instance, beta_vec};
+        let observers = ising_observer_factories(params.observables.as_deref().unwrap_or(&[]));
+        return Ok(Self{params, instance, beta_vec, control: None, progress: None, observers});
+    }
+
+    /// Register an observer, created anew for each run and each thread from `factory`.
+    /// The outputs of the threads are joined along the replica axis.
+    pub fn with_observer<F>(mut self, factory: F) -> Self
+    where F: Fn() -> Box<dyn Observer<BqmIsingInstance, IsingState> + 'a> + Send + Sync + 'a
+    {
+        self.observers.push(Box::new(factory));
+        return self;
+    }
+
+    /// Notify the observers due at schedule step i
+    fn observe(&self, i: usize, states: &[IsingState],
+               observers: &mut [Box<dyn Observer<BqmIsingInstance, IsingState> + 'a>]){
+        let i = i as u32;
+        if observers.iter().any(|obs| i % obs.interval().max(1) == 0){
+            let betas = std::slice::from_ref(&self.beta_vec[i as usize]);
+            let replicas = Replicas{betas, chains: states.iter().map(std::slice::from_ref).collect()};
+            for obs in observers.iter_mut().filter(|obs| i % obs.interval().max(1) == 0){
+                obs.on_sweep(i, self.instance, &replicas);
+            }
+        }
+    }
+
+    /// Stop annealing early when the control requests it, and report the final minimum energies to it.
+    /// AIS runs always complete the schedule, and ignore the time limit and target energy.
+    pub fn with_control(mut self, control: ControlHandle<'a>) -> Self{
+        self.control = Some(control);
+        return self;
     }
 
-    pub fn run(&self, initial_state: Option<Vec<IsingState>>) -> (AnnealMinResults, Vec<IsingState>){
+    /// Periodically report the progress of the run. The threads of a parallel run share the reporter,
+    /// and each report shows the replicas of the thread that made it.
+    pub fn with_progress(mut self, progress: &'a ProgressReporter) -> Self{
+        self.progress = Some(progress);
+        return self;
+    }
+
+    /// Report the progress after schedule step i if a report is due
+    fn report_progress(&self, i: usize, states: &[IsingState], limits: &RunControl){
+        if let Some(progress) = self.progress{
+            if progress.due(){
+                let e = states.iter().map(|st| self.instance.energy_ref(st))
+                    .fold(f32::INFINITY, f32::min);
+                progress.report(&ProgressStatus{
+                    method: "SA",
+                    sweeps_done: i as u32 + 1,
+                    total_sweeps: self.beta_vec.len() as u32,
+                    elapsed: limits.elapsed(),
+                    current_energy: Some(e),
+                    swap_acceptances: None,
+                    swap_attempts: 0,
+                    round_trips: None
+                });
+            }
+        }
+    }
+
+    pub fn run(&self, initial_state: Option<Vec<IsingState>>) -> Result<(AnnealMinResults, Vec<IsingState>), TamcError>{
         // seed and create random number generator
-        let mut rngt = thread_rng();
         let mut seed_seq = [0u8; 32];
-        rngt.fill_bytes(&mut seed_seq);
+        fill_seed(&mut seed_seq);
         let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
         // randomly generate initial states
         let mut sa_state = match initial_state{
@@ -119,7 +292,8 @@ impl<'a> SaRunner<'a>{
             Some(st) => { st }
         };
         info!("-- SA begin");
-        let mut sa_results = self.sa_loop(&mut sa_state, &mut rng);
+        let limits = RunControl::new(self.params.target_energy, self.params.time_limit);
+        let mut sa_results = self.sa_loop(&mut sa_state, &mut rng, &limits);
         info!("-- SA Finished");
         let t_us = sa_results.timing;
         let t_sec = t_us * (1e6);
@@ -129,15 +303,15 @@ Duration per replica: {:5.4e} s
 ",
             t_sec, t_sec / (self.params.num_replicas as f64));
         //pt_results.final_state = pt_state;
-        return (sa_results, sa_state);
+        return Ok((sa_results, sa_state));
     }
 
 
-    pub fn run_parallel(&self, initial_state: Option<Vec<Vec<IsingState>>>) -> (AnnealMinResults, Vec<IsingState>){
+    /// Run `threads` independent anneals. The initial states, if given, are split into one group for each thread
+    pub fn run_parallel(&self, initial_state: Option<Vec<Vec<IsingState>>>) -> Result<(AnnealMinResults, Vec<IsingState>), TamcError>{
         // seed and create random number generator
-        let mut rngt = thread_rng();
         let mut seed_seq = [0u8; 32];
-        rngt.fill_bytes(&mut seed_seq);
+        fill_seed(&mut seed_seq);
         let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
         let mut rng_vec = Vec::with_capacity(self.params.threads as usize);
         for _ in 0..self.params.threads{
@@ -150,23 +324,33 @@ Duration per replica: {:5.4e} s
             None => {
                 rng_vec.iter_mut().map(|rng| self.generate_init_state(rng, Some(reps_per_thread)))
                     .collect_vec() },
-            Some(st) => { st }
+            Some(st) => {
+                if st.len() != self.params.threads as usize{
+                    return Err(TamcError::size_mismatch("groups of initial states, one for each thread",
+                                                        self.params.threads as usize, st.len()));
+                }
+                st
+            }
         };
         let mut sa_results_vec = Vec::with_capacity(self.params.threads as usize);
-        sa_state.par_iter_mut().zip_eq(rng_vec.par_iter_mut())
-            .map(|(state, rng)| self.sa_loop(state, rng))
-            .collect_into_vec(&mut sa_results_vec);
+        let limits = RunControl::new(self.params.target_energy, self.params.time_limit);
+        // one task per thread, on a pool of `threads` threads
+        run_in_pool(self.params.threads, ||
+            sa_state.par_iter_mut().zip_eq(rng_vec.par_iter_mut())
+                .map(|(state, rng)| self.sa_loop(state, rng, &limits))
+                .collect_into_vec(&mut sa_results_vec));
         let mut sa_results = AnnealMinResults::combine(sa_results_vec);
+        sa_results.apply_ais_estimate(self.instance.size());
         // set the actual number of replicas simulated
         sa_results.params.num_replicas = self.params.threads * reps_per_thread;
         //pt_results.final_state = pt_state;
         let sa_state = sa_state.into_iter().concat();
-        return (sa_results, sa_state);
+        return Ok((sa_results, sa_state));
     }
 
     fn sa_loop<Rn: Rng>(
         &self, sa_state: &mut Vec<IsingState>,
-        rng: &mut Rn
+        rng: &mut Rn, limits: &RunControl
     ) -> AnnealMinResults
     {
         // Initialize samplers
@@ -175,11 +359,48 @@ Duration per replica: {:5.4e} s
         let sampler = MetropolisSampler::new_uniform(self.instance,beta0, n);
 
 
+        let mut ais_log_weights = None;
+        let mut stop_reason = StopReason::Completed;
+        let sweeps_completed;
+        let mut observers = self.observers.iter().map(|f| f()).collect_vec();
         let start = time::Instant::now();
-        sa::simulated_annealing(
-            sampler, sa_state, &self.beta_vec, rng,
-            |_, _| { }
-        );
+        if self.params.ais.unwrap_or(false){
+            let mut log_weights = vec![0.0; sa_state.len()];
+            // AIS ignores the time limit and target energy, but stops on an interruption
+            sweeps_completed = sa::annealed_importance_sampling_until(
+                sampler, sa_state, &self.beta_vec, &mut log_weights, rng,
+                |i, states| {
+                    self.observe(i, states, &mut observers);
+                    self.report_progress(i, states, limits);
+                    if control::interrupted(){
+                        stop_reason = StopReason::Interrupted;
+                        return ControlFlow::Break(());
+                    }
+                    ControlFlow::Continue(())
+                }
+            );
+            // the weights of an incomplete schedule do not estimate Z at the final beta
+            if stop_reason == StopReason::Completed{
+                ais_log_weights = Some(log_weights);
+            }
+        } else {
+            sweeps_completed = sa::simulated_annealing_until(
+                sampler, sa_state, &self.beta_vec, rng,
+                |i, states| {
+                    self.observe(i, states, &mut observers);
+                    self.report_progress(i, states, limits);
+                    if self.params.target_energy.is_some(){
+                        let e = states.iter().map(|st| self.instance.energy_ref(st))
+                            .fold(f32::INFINITY, f32::min);
+                        limits.report(0, e);
+                    }
+                    match control::check_stop(limits, &self.control){
+                        Some(reason) => { stop_reason = reason; ControlFlow::Break(()) }
+                        None => ControlFlow::Continue(())
+                    }
+                }
+            );
+        }
         let end = start.elapsed();
 
         let t_sec = end.as_secs_f64();
@@ -192,6 +413,18 @@ Duration per replica: {:5.4e} s
 
         let mut sa_results = AnnealMinResults::new(self.params.clone());
         sa_results.apply_measurements(&self.instance, sa_state);
+        sa_results.ais_log_weights = ais_log_weights;
+        sa_results.apply_ais_estimate(self.instance.size());
+        if let Some(kind) = self.params.quench{
+            sa_results.quench = Some(QuenchResults::quench_states(self.instance, sa_state, kind));
+            sa_results.apply_measurements(&self.instance, sa_state);
+        }
+        control::report(&self.control, sa_results.min_energy);
+        sa_results.stop_reason = Some(stop_reason);
+        sa_results.sweeps_completed = Some(sweeps_completed as u32);
+        if !observers.is_empty(){
+            sa_results.observables = Some(observers.iter().map(|o| o.output()).collect());
+        }
 
         sa_results.timing = end.as_micros() as f64;
 
@@ -210,25 +443,38 @@ Duration per replica: {:5.4e} s
     }
 }
 
-pub fn run_simulated_annealing(prog: &Prog, params: &SaParams){
-    simple_logger::SimpleLogger::new().with_level(log::LevelFilter::Info).env().init().unwrap();
-    let mut instance = prog.read_instance();
-    let sa_runner = SaRunner::new(&instance, &params);
-    info!(" ** Simulated Annealing **");
-    let (min_results, final_states) =
-        if params.num_replicas > 1{
-            info!("Running multi-threaded SA");
-            sa_runner.run_parallel(None)
-        } else {
-            sa_runner.run(None)
-        };
+/// Run SA on the instance, splitting the replicas between threads if there is more than one replica
+pub fn solve_sa<'a>(instance: &'a BqmIsingInstance, params: &'a SaParams, control: Option<ControlHandle<'a>>,
+                    progress: Option<&'a ProgressReporter>, initial_states: Option<&InitialStates>)
+        -> Result<(AnnealMinResults, Vec<IsingState>), TamcError>{
+    if initial_states.is_some() && params.ais.unwrap_or(false){
+        return Err(TamcError::invalid_parameter("initial_states",
+            "AIS assumes the replicas start from uniformly random states, so it cannot be warm started"));
+    }
+    let mut sa_runner = SaRunner::new(instance, params)?;
+    sa_runner.control = control;
+    sa_runner.progress = progress;
+    return if params.num_replicas > 1{
+        info!("Running multi-threaded SA");
+        let reps_per_thread = (params.num_replicas / params.threads) as usize;
+        let init_state = initial_states.map(|init|
+            init.replicas(instance, reps_per_thread * params.threads as usize)
+                .chunks(reps_per_thread).map(|c| c.to_vec()).collect());
+        sa_runner.run_parallel(init_state)
+    } else {
+        sa_runner.run(initial_states.map(|init| init.replicas(instance, 1)))
+    };
+}
 
+/// Print a summary of the results and write them, along with the lowest energy final states,
+/// to the output files of the program
+pub fn write_results(prog: &Prog, min_results: &AnnealMinResults, final_states: &[IsingState]) -> anyhow::Result<()>{
     let sample_output = prog.sample_output.clone().unwrap_or("samples.bin".to_string());
     let ngs = min_results.energies.iter()
         .fold(0u32,
               |n, &e| {if e <=min_results.min_energy{n+1} else {n} });
     let pgs = (ngs as f64) / (min_results.energies.len() as f64);
-    let tts = (min_results.timing/1e6/(params.num_replicas as f64))
+    let tts = (min_results.timing/1e6/(min_results.params.num_replicas as f64))
         * (f64::log10(0.01)/f64::log10(1.0 - pgs));
     info!(r"
 ** Ground state energy **
@@ -238,34 +484,131 @@ pub fn run_simulated_annealing(prog: &Prog, params: &SaParams){
 ** Time-to-solution **
   tts = {} s
 ", min_results.min_energy, pgs, tts);
+    if let (Some(reason), Some(sweeps)) = (min_results.stop_reason, min_results.sweeps_completed){
+        if reason != StopReason::Completed{
+            info!("Stopped on {:?} after {} sweeps", reason, sweeps);
+        }
+    }
+    if let Some(ais) = &min_results.ais{
+        info!(r"
+** AIS estimate **
+  log Z = {} +/- {:5.4e}
+  ESS = {:5.2}
+", ais.log_z, ais.log_z_var.sqrt(), ais.ess);
+    }
 
     {
         let mut f = File::create(&prog.output_file)
-            .expect("Failed to create output file");
+            .with_context(|| format!("Failed to create output file {}", prog.output_file))?;
         let ext = Path::new(&prog.output_file).extension().and_then(OsStr::to_str);
         if ext == Some("pkl"){
-            serde_pickle::to_writer(&mut f, &min_results,
+            serde_pickle::to_writer(&mut f, min_results,
                                     serde_pickle::SerOptions::default())
-                .expect("Failed to write to pkl file.");
+                .with_context(|| format!("Failed to write SA results to {}", prog.output_file))?;
         } else if ext == Some("yml") {
-            serde_yaml::to_writer(f, &min_results)
-                .expect("Failed to write to yaml file.")
+            serde_yaml::to_writer(f, min_results)
+                .with_context(|| format!("Failed to write SA results to {}", prog.output_file))?;
         }
     }
-    {
-        let gs_energy_states = final_states.iter()
-            .zip(min_results.energies.iter())
-            .filter(|(s, &e)| e <= min_results.min_energy+1.0e-8 )
-            .map(|(s, _)| s.clone()).collect_vec();
-        let gs_compressed_states = AnnealState::new(&gs_energy_states);
-        let mut f = File::create(&sample_output)
-            .expect("Failed to create sample output file");
-        let ext = Path::new(&sample_output).extension().and_then(OsStr::to_str);
-        if ext == Some("pkl"){
-            serde_pickle::to_writer(&mut f, &gs_compressed_states, serde_pickle::SerOptions::default())
-                .expect("Failed to write to pkl file");
-        } else {
-            bincode::serialize_into(&mut f, &gs_compressed_states).expect("Failed to serialize");
-        }
+    write_states(&sample_output, min_results, final_states)
+}
+
+/// Write the final states with the lowest energy as an `AnnealState`,
+/// in pickle format if the file name ends in `.pkl`, and otherwise in bincode
+pub fn write_states(sample_output: &str, min_results: &AnnealMinResults, final_states: &[IsingState]) -> anyhow::Result<()>{
+    let gs_energy_states = final_states.iter()
+        .zip(min_results.energies.iter())
+        .filter(|(_, &e)| e <= min_results.min_energy+1.0e-8 )
+        .map(|(s, _)| s.clone()).collect_vec();
+    let gs_compressed_states = AnnealState::new(&gs_energy_states);
+    let mut f = File::create(sample_output)
+        .with_context(|| format!("Failed to create sample output file {}", sample_output))?;
+    let ext = Path::new(sample_output).extension().and_then(OsStr::to_str);
+    if ext == Some("pkl"){
+        serde_pickle::to_writer(&mut f, &gs_compressed_states, serde_pickle::SerOptions::default())
+            .with_context(|| format!("Failed to write states to {}", sample_output))?;
+    } else {
+        bincode::serialize_into(&mut f, &gs_compressed_states)
+            .with_context(|| format!("Failed to write states to {}", sample_output))?;
     }
-}
\ No newline at end of file
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use tamc_core::traits::Instance;
+    use crate::ising::IsingState;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pt::BetaOptions;
+    use crate::sa::{SaParams, SaRunner};
+    use crate::control::{RunControl, StopReason};
+
+    #[test]
+    fn test_ising_2d_ais(){
+        let l = 3;
+        let n = l*l;
+        let instance = make_ising_2d_instance(l);
+        let beta_f = 1.0;
+        // exact log Z by enumeration
+        let log_z_terms: Vec<f64> = (0..(1u64 << n))
+            .map(|b| {
+                let mut st = IsingState::from_u64_vec(&vec![b], n as u32).unwrap();
+                -beta_f * instance.energy(&mut st) as f64
+            }).collect();
+        let max_t = log_z_terms.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let log_z = max_t + f64::ln(log_z_terms.iter().map(|&t| f64::exp(t - max_t)).sum::<f64>());
+
+        let params = SaParams{
+            beta: BetaOptions::new_geometric(0.01, beta_f as f32, 200),
+            num_replicas: 256,
+            threads: 4,
+            ais: Some(true),
+            quench: None,
+            time_limit: None,
+            target_energy: None,
+            observables: None
+        };
+        let runner = SaRunner::new(&instance, &params).unwrap();
+        let (results, _) = runner.run_parallel(None).unwrap();
+        let ais = results.ais.unwrap();
+        println!("log Z = {}, AIS log Z = {} +/- {}, ESS = {}", log_z, ais.log_z, ais.log_z_var.sqrt(), ais.ess);
+        assert!(ais.ess > 0.5 * (params.num_replicas as f64));
+        assert!((ais.log_z - log_z).abs() < 0.02 * log_z.abs());
+    }
+
+    #[test]
+    fn test_ising_2d_sa_early_stop(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        let mut params = SaParams{
+            beta: BetaOptions::new_geometric(0.1, 5.0, 1_000_000),
+            num_replicas: 8,
+            threads: 2,
+            ais: None,
+            quench: None,
+            time_limit: Some(60.0),
+            target_energy: Some(-2.0 * (l*l) as f32),
+            observables: None
+        };
+        let (results, _) = SaRunner::new(&instance, &params).unwrap().run_parallel(None).unwrap();
+        println!("stopped after {:?} sweeps: {:?}", results.sweeps_completed, results.stop_reason);
+        assert_eq!(results.stop_reason, Some(StopReason::TargetEnergy));
+        assert!(results.sweeps_completed.unwrap() < 1_000_000);
+        assert_eq!(results.min_energy, -2.0 * (l*l) as f32);
+
+        // a time limit that has expired by the end of the first sweep stops the anneal there
+        params.target_energy = None;
+        params.time_limit = Some(1.0e-9);
+        let (results, _) = SaRunner::new(&instance, &params).unwrap().run(None).unwrap();
+        assert_eq!(results.stop_reason, Some(StopReason::TimeLimit));
+        assert_eq!(results.sweeps_completed, Some(1));
+
+        // as does a stop requested through the control before the anneal
+        params.time_limit = None;
+        let control = RunControl::new(None, None);
+        control.request_stop();
+        let (results, _) = SaRunner::new(&instance, &params).unwrap()
+            .with_control(control.handle(0)).run_parallel(None).unwrap();
+        assert_eq!(results.stop_reason, Some(StopReason::Requested));
+        assert_eq!(results.sweeps_completed, Some(1));
+    }
+}
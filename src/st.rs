@@ -0,0 +1,292 @@
+use std::time;
+
+use anyhow::Result;
+use log::info;
+use ndarray::prelude::*;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::ensembles as ens;
+use tamc_core::parallel::ensembles as pens;
+use tamc_core::st::{simulated_tempering_sampler, STState, STWeights};
+use tamc_core::traits::*;
+
+use crate::util::{run_in_pool, seeded_rng, split_rng, write_yaml};
+use crate::Prog;
+use crate::control::{self, StopReason};
+use crate::error::TamcError;
+use crate::ising::{BqmIsingInstance, IsingSampler, IsingState, rand_ising_state};
+use crate::ising_results::MinResults;
+use crate::pt::BetaOptions;
+use crate::validate::Validation;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StParams{
+    pub num_sweeps: u32,
+    pub warmup_fraction: f64,
+    pub beta: BetaOptions,
+    pub weights: STWeights,
+    pub num_replicas: u32,
+    pub threads: u32
+}
+
+impl StParams{
+    /// Check every parameter and their combinations before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.num_sweeps == 0{
+            v.error("num_sweeps", "num_sweeps must be positive", "set num_sweeps, e.g. to 2000");
+        }
+        if !(0.0..1.0).contains(&self.warmup_fraction){
+            v.error("warmup_fraction", format!("warmup_fraction must be in [0, 1), but is {}", self.warmup_fraction),
+                    "set warmup_fraction to e.g. 0.5");
+        }
+        self.beta.validate("beta", &mut v);
+        if self.beta.get_beta_arr().len() == 1{
+            v.warn("beta", "a single temperature makes no tempering moves", "use at least two betas");
+        }
+        if let STWeights::WangLandau{ ln_f0, flatness, ln_f_min } = self.weights{
+            if !(ln_f0 > 0.0){
+                v.error("weights.ln_f0", format!("ln_f0 must be positive, but is {}", ln_f0), "set ln_f0 to e.g. 1.0");
+            }
+            if !(flatness > 0.0 && flatness <= 1.0){
+                v.error("weights.flatness", format!("flatness must be in (0, 1], but is {}", flatness),
+                        "set flatness to e.g. 0.8");
+            }
+            if !(ln_f_min > 0.0){
+                v.error("weights.ln_f_min", format!("ln_f_min must be positive, but is {}", ln_f_min),
+                        "set ln_f_min to e.g. 1e-4");
+            } else if ln_f_min >= ln_f0{
+                v.warn("weights.ln_f_min", format!("ln_f_min = {} is not below ln_f0 = {}, so the weights are never refined", ln_f_min, ln_f0),
+                       "set ln_f_min well below ln_f0");
+            }
+        }
+        if self.num_replicas == 0{
+            v.error("num_replicas", "at least one replica is required", "set num_replicas to e.g. 8");
+        }
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        } else if self.num_replicas > 0 && self.threads > self.num_replicas{
+            v.warn("threads", format!("{} threads exceed the {} replicas", self.threads, self.num_replicas),
+                   format!("set threads to at most {}", self.num_replicas));
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StMinResults{
+    pub params: StParams,
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    pub acceptance_counts: Vec<u32>,
+    pub round_trips: u32,
+    /// Temperature x {min beta, max beta}: visits labeled by the last extreme temperature visited
+    pub diffusion_hist: Vec<[u32; 2]>,
+    pub temperature_hist: Vec<u64>,
+    /// Replica x Temperature: learned log weights at the end of the run
+    pub log_weights: Vec<Vec<f64>>,
+    pub mean_energies: Vec<f64>,
+    pub stop_reason: Option<StopReason>,
+    /// Number of sweeps run before stopping
+    pub sweeps_completed: Option<u32>
+}
+
+impl StMinResults{
+    fn new(params: StParams, num_betas: u32, instance_size: u32) -> Self{
+        let num_betas = num_betas as usize;
+        return Self{
+            params,
+            min_results: MinResults::new(num_betas as u32, instance_size),
+            acceptance_counts: vec![0; num_betas],
+            round_trips: 0,
+            diffusion_hist: vec![[0, 0]; num_betas],
+            temperature_hist: vec![0; num_betas],
+            log_weights: Vec::new(),
+            mean_energies: vec![0.0; num_betas],
+            stop_reason: None,
+            sweeps_completed: None
+        };
+    }
+
+    /// Collect the tempering statistics summed over all replicas
+    fn collect_statistics(&mut self, st_state: &[STState<IsingState>]){
+        let num_betas = self.acceptance_counts.len();
+        let mut acceptance_counts = Array1::zeros(num_betas);
+        let mut energy_sums = vec![0.0; num_betas];
+        for st in st_state.iter(){
+            acceptance_counts += &st.num_acceptances;
+            self.round_trips += st.round_trips;
+            for (h, row) in self.diffusion_hist.iter_mut().zip(st.diffusion_hist.rows()){
+                h[0] += row[0];
+                h[1] += row[1];
+            }
+            for k in 0..num_betas{
+                self.temperature_hist[k] += st.temperature_hist[k];
+                energy_sums[k] += st.energy_sums[k];
+            }
+            self.log_weights.push(st.log_weights.clone());
+        }
+        self.acceptance_counts = acceptance_counts.into_raw_vec();
+        for ((e, &s), &c) in self.mean_energies.iter_mut()
+                .zip(energy_sums.iter()).zip(self.temperature_hist.iter()){
+            *e = if c > 0 { s / c as f64 } else { 0.0 };
+        }
+    }
+}
+
+/// Simulated tempering of independent replicas, each random-walking over the whole temperature ladder
+pub struct StRunner<'a>{
+    params: &'a StParams,
+    instance: &'a BqmIsingInstance,
+    beta_vec: Vec<f32>,
+    meas_init: u32
+}
+
+impl<'a> StRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a StParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        let beta_vec = params.beta.get_beta_arr();
+        let meas_init = (params.warmup_fraction * (params.num_sweeps as f64)) as u32;
+        return Ok(Self{params, instance, beta_vec, meas_init});
+    }
+
+    pub fn run(&self, initial_state: Option<Vec<STState<IsingState>>>) -> Result<(StMinResults, Vec<STState<IsingState>>), TamcError>{
+        // seed and create random number generator
+        let mut rng = seeded_rng();
+        // randomly generate initial states
+        let mut st_state = match initial_state{
+            None => self.generate_init_state(&mut rng),
+            Some(st) => {
+                let num_betas = self.beta_vec.len();
+                if let Some(bad) = st.iter().find(|st| st.log_weights.len() != num_betas){
+                    return Err(TamcError::size_mismatch("log weights of an initial state", num_betas, bad.log_weights.len()));
+                }
+                st
+            }
+        };
+        let n = self.instance.size();
+        let samplers: Vec<_> = self.beta_vec.iter()
+            .map(|&b| IsingSampler::new(self.instance, b, n as u32))
+            .collect();
+        let st_sampler = simulated_tempering_sampler(samplers, self.params.weights);
+        let mut st_results = if self.params.threads > 1{
+            let mut rng_vec = split_rng(&mut rng, st_state.len());
+            let replicas_sampler = pens::ThreadedEnsembleSampler::new(st_sampler);
+            run_in_pool(self.params.threads, || self.st_loop(&replicas_sampler, &mut st_state, &mut rng_vec))
+        } else {
+            let replicas_sampler = ens::EnsembleSampler::new(st_sampler);
+            self.st_loop(&replicas_sampler, &mut st_state, &mut rng)
+        };
+        st_results.collect_statistics(&st_state);
+        return Ok((st_results, st_state));
+    }
+
+    fn st_loop<S, Rn>(&self, replicas_sampler: &S, st_state: &mut Vec<STState<IsingState>>, rng: &mut Rn) -> StMinResults
+    where S: Sampler<Rn, SampleType=Vec<STState<IsingState>>>
+    {
+        let n = self.instance.size();
+        let num_betas = self.beta_vec.len();
+        let mut st_results = StMinResults::new(self.params.clone(), num_betas as u32, n as u32);
+        let mut minimum_e = None;
+        info!("-- ST begin");
+        let start = time::Instant::now();
+        st_results.stop_reason = Some(StopReason::Completed);
+        st_results.sweeps_completed = Some(self.params.num_sweeps);
+        for i in 0..self.params.num_sweeps{
+            if i == self.meas_init{
+                for st in st_state.iter_mut(){
+                    st.freeze_weights();
+                }
+            }
+            replicas_sampler.sweep(st_state, rng);
+            if i >= self.meas_init{
+                self.apply_measurements(i, st_state, &mut minimum_e, &mut st_results.min_results);
+            }
+            if control::interrupted(){
+                st_results.stop_reason = Some(StopReason::Interrupted);
+                st_results.sweeps_completed = Some(i + 1);
+                break;
+            }
+        }
+        let end = start.elapsed();
+        info!("-- ST Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        st_results.min_results.timing = end.as_micros() as f64;
+
+        return st_results;
+    }
+
+    fn apply_measurements(&self, i: u32, st_state: &mut Vec<STState<IsingState>>,
+                          minimum_e: &mut Option<f32>, st_results: &mut MinResults){
+        let (min_e, min_state) = st_state.iter_mut()
+            .map(|st| (self.instance.energy(&mut st.state), &st.state))
+            .min_by(|x, y| x.0.partial_cmp(&y.0).unwrap())
+            .unwrap();
+        st_results.num_measurements += 1;
+        if minimum_e.map_or(true, |x| min_e < x) {
+            *minimum_e = Some(min_e);
+            st_results.gs_states.push(min_state.as_u64_vec());
+            st_results.gs_energies.push(min_e);
+            st_results.gs_time_steps.push(i)
+        }
+    }
+
+    pub fn generate_init_state<Rn: Rng+?Sized>(&self, rng: &mut Rn) -> Vec<STState<IsingState>>{
+        let n = self.instance.size() as u32;
+        let num_betas = self.beta_vec.len();
+        return (0..self.params.num_replicas)
+            .map(|_| STState::new(rand_ising_state(n, self.instance, rng), num_betas, &self.params.weights))
+            .collect();
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &StMinResults) -> Result<()>{
+    println!("ST Done.");
+    println!("** Ground state energy **");
+    if let Some(e) = results.min_results.gs_energies.last(){
+        println!("  e = {}", e);
+    }
+    println!("  round trips = {}", results.round_trips);
+    if let (Some(reason), Some(sweeps)) = (results.stop_reason, results.sweeps_completed){
+        if reason != StopReason::Completed{
+            println!("  stopped on {:?} after {} sweeps", reason, sweeps);
+        }
+    }
+    write_yaml(&prog.output_file, results, "ST")
+}
+
+#[cfg(test)]
+mod tests {
+    use tamc_core::st::STWeights;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pt::BetaOptions;
+    use crate::st::{StParams, StRunner};
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_ising_2d_st(){
+        let l = 8;
+        let instance = make_ising_2d_instance(l);
+        for weights in [STWeights::WangLandau { ln_f0: 1.0, flatness: 0.8, ln_f_min: 1.0e-4 },
+                        STWeights::EnergyAverage]{
+            let params = StParams{
+                num_sweeps: 4000,
+                warmup_fraction: 0.5,
+                beta: BetaOptions::new_geometric(0.1, 2.0, 8),
+                weights,
+                num_replicas: 2,
+                threads: 1
+            };
+            let runner = StRunner::new(&instance, &params).unwrap();
+            let (results, _) = with_seed(Some(1234), || runner.run(None)).unwrap();
+            let &e = results.min_results.gs_energies.last().unwrap();
+            println!("e = {}, round trips = {}", e, results.round_trips);
+            println!("temperature histogram: {:?}", results.temperature_hist);
+            println!("log weights: {:5.2?}", results.log_weights[0]);
+            assert!(results.temperature_hist.iter().all(|&c| c > 0));
+            assert_eq!(e, -2.0 * (l*l) as f32);
+        }
+    }
+}
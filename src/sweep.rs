@@ -0,0 +1,438 @@
+//! Parameter-grid sweeps: a method run for every combination of the values of some of its fields.
+//! The grid is given either explicitly as a `Sweep` method, or by inline lists in an ordinary method file
+//! (e.g. `num_sweeps: [1000, 2000]`), which `inline_sweep` turns into the same `SweepParams`.
+use std::fs::File;
+use std::time;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::{Method, MethodResults, Prog, RunOptions, control, pt, sa};
+use crate::error::TamcError;
+use crate::ising::{BqmIsingInstance, IsingState};
+use crate::pt::PtIcmThermalSamples;
+use crate::validate::{ParamIssue, Validation};
+
+/// Largest number of inline lists in a method file that are considered as grid fields
+const MAX_INLINE_LISTS: usize = 12;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SweepParams{
+    /// The method run in every configuration, e.g. `PT: {...}`.
+    /// Fields set by the grid may be omitted.
+    pub method: Value,
+    /// Lists of values of fields of the method parameters, keyed by their dot-separated path,
+    /// e.g. `num_sweeps` or `beta.Geometric.num_beta`.
+    /// Every combination of values is run.
+    pub grid: Mapping
+}
+
+/// One configuration of the grid
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SweepPoint{
+    /// The value of each grid field
+    pub point: Mapping,
+    pub method: Method
+}
+
+impl SweepParams{
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        match self.expand(){
+            Ok(points) => {
+                for (k, p) in points.iter().enumerate(){
+                    v.extend_nested(&format!("configuration {}", k), p.method.validate());
+                }
+            }
+            Err(issue) => v.errors.push(issue)
+        }
+        return v;
+    }
+
+    /// The Cartesian product of the grid values applied to the method, with the last grid field varying fastest
+    pub fn configurations(&self) -> Result<Vec<SweepPoint>, TamcError>{
+        return self.expand().map_err(|issue| TamcError::InvalidParameters(vec![issue]));
+    }
+
+    fn expand(&self) -> std::result::Result<Vec<SweepPoint>, ParamIssue>{
+        let issue = |field: &str, problem: String, suggestion: &str| ParamIssue{
+            field: field.to_string(), problem, suggestion: suggestion.to_string()};
+        let name = match self.method.as_mapping().filter(|m| m.len() == 1).and_then(|m| m.iter().next()){
+            Some((Value::String(name), _)) => name.clone(),
+            _ => return Err(issue("method", "the method must be a single method such as PT or SA".to_string(),
+                                  "write the method as in a method file, e.g. `PT: {num_sweeps: 1000, ...}`"))
+        };
+        if name == "Sweep"{
+            return Err(issue("method", "sweeps cannot be nested".to_string(), "list every field in a single grid"));
+        }
+        let mut fields = Vec::with_capacity(self.grid.len());
+        for (key, values) in self.grid.iter(){
+            let key = match key.as_str(){
+                Some(k) => k,
+                None => return Err(issue("grid", format!("the field {:?} is not a string", key),
+                                         "use the dot-separated path of a field, e.g. beta.Geometric.num_beta"))
+            };
+            let field = format!("grid.{}", key);
+            match values.as_sequence(){
+                Some(vals) if !vals.is_empty() => fields.push((key, vals)),
+                _ => return Err(issue(&field, "the values must be a non-empty list".to_string(),
+                                      "list the values, e.g. [1000, 2000]"))
+            }
+        }
+
+        let num_points: usize = fields.iter().map(|(_, vals)| vals.len()).product();
+        let mut points = Vec::with_capacity(num_points);
+        let mut idx = vec![0; fields.len()];
+        for k in 0..num_points{
+            let mut method = self.method.clone();
+            let params = method.as_mapping_mut().unwrap().get_mut(&Value::String(name.clone())).unwrap();
+            let mut point = Mapping::new();
+            for (&(key, vals), &i) in fields.iter().zip(idx.iter()){
+                set_path(params, key, vals[i].clone()).map_err(|e|
+                    issue(&format!("grid.{}", key), e, "use the dot-separated path of a field of the method"))?;
+                point.insert(Value::String(key.to_string()), vals[i].clone());
+            }
+            let method: Method = serde_yaml::from_value(method).map_err(|e|
+                issue("grid", format!("configuration {} ({}) is not a valid method: {}", k,
+                                      describe_point(&point), e),
+                      "complete the method so that every combination of the grid values is valid"))?;
+            points.push(SweepPoint{point, method});
+            // advance to the next combination, last field first
+            for (j, (_, vals)) in fields.iter().enumerate().rev(){
+                idx[j] += 1;
+                if idx[j] < vals.len(){
+                    break;
+                }
+                idx[j] = 0;
+            }
+        }
+        return Ok(points);
+    }
+}
+
+/// Interpret the lists of a method that does not parse as grid values, e.g. `PT: {num_sweeps: [1000, 2000], ...}`.
+/// The grid fields are the fewest lists that make a valid method when each is replaced by its first value,
+/// so lists that the method itself accepts, such as `beta: {Arr: [...]}`, are kept as they are.
+/// Lists inside other lists are never grid fields. Returns None if no such set of lists exists.
+pub fn inline_sweep(method: &Value) -> Option<SweepParams>{
+    let (name, params) = method.as_mapping().filter(|m| m.len() == 1).and_then(|m| m.iter().next())?;
+    if name.as_str() == Some("Sweep"){
+        return None;
+    }
+    let mut lists = Vec::new();
+    collect_lists(params, "", &mut lists);
+    if lists.is_empty() || lists.len() > MAX_INLINE_LISTS{
+        return None;
+    }
+    // try the subsets of lists from the smallest
+    let mut subsets: Vec<u32> = (1..(1u32 << lists.len())).collect();
+    subsets.sort_by_key(|s| s.count_ones());
+    for subset in subsets{
+        let mut candidate = method.clone();
+        let params = candidate.as_mapping_mut().unwrap().get_mut(name).unwrap();
+        let mut grid = Mapping::new();
+        for (j, (path, vals)) in lists.iter().enumerate(){
+            if subset & (1 << j) != 0{
+                set_path(params, path, vals[0].clone()).ok()?;
+                grid.insert(Value::String(path.clone()), Value::Sequence(vals.clone()));
+            }
+        }
+        if serde_yaml::from_value::<Method>(candidate.clone()).is_ok(){
+            return Some(SweepParams{method: candidate, grid});
+        }
+    }
+    return None;
+}
+
+/// The dot-separated paths of the non-empty lists in the mappings of `value`
+fn collect_lists(value: &Value, prefix: &str, lists: &mut Vec<(String, Vec<Value>)>){
+    match value{
+        Value::Mapping(m) => {
+            for (key, v) in m.iter(){
+                if let Some(key) = key.as_str(){
+                    let path = if prefix.is_empty() { key.to_string() } else { format!("{}.{}", prefix, key) };
+                    collect_lists(v, &path, lists);
+                }
+            }
+        }
+        Value::Sequence(vals) if !vals.is_empty() => lists.push((prefix.to_string(), vals.clone())),
+        _ => {}
+    }
+}
+
+/// Set the field at the dot-separated `path` of the parameters, adding missing mappings along the way
+fn set_path(params: &mut Value, path: &str, value: Value) -> std::result::Result<(), String>{
+    let mut current = params;
+    for seg in path.split('.'){
+        let map = match current{
+            Value::Null => { *current = Value::Mapping(Mapping::new()); current.as_mapping_mut().unwrap() }
+            Value::Mapping(m) => m,
+            _ => return Err(format!("the parent of `{}` is not a mapping", seg))
+        };
+        let key = Value::String(seg.to_string());
+        if !map.contains_key(&key){
+            map.insert(key.clone(), Value::Null);
+        }
+        current = map.get_mut(&key).unwrap();
+    }
+    *current = value;
+    return Ok(());
+}
+
+/// The grid values of a configuration on one line, e.g. `num_sweeps: 1000, beta.Geometric.num_beta: 16`
+fn describe_point(point: &Mapping) -> String{
+    return serde_yaml::to_string(point).unwrap_or_default()
+        .trim_start_matches("---\n").trim().replace('\n', ", ");
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SweepRunResults{
+    /// The value of each grid field
+    pub point: Mapping,
+    /// The concrete method of this configuration
+    pub method: Method,
+    pub min_energy: Option<f32>,
+    /// The results, or None if the run failed
+    pub results: Option<MethodResults>,
+    /// Why the run failed
+    pub error: Option<String>,
+    /// Thermal samples of a PT run, written to their own file
+    #[serde(skip)]
+    pub samples: Option<PtIcmThermalSamples>,
+    /// Final states of an SA run, written to their own file
+    #[serde(skip)]
+    pub final_states: Option<Vec<IsingState>>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SweepResults{
+    pub params: SweepParams,
+    /// Index of the configuration that found the lowest energy
+    pub best: Option<usize>,
+    pub min_energy: Option<f32>,
+    pub gs_state: Option<Vec<u64>>,
+    pub timing: f64,
+    pub runs: Vec<SweepRunResults>
+}
+
+/// Runs every configuration of a parameter grid on the same instance, one after the other.
+/// A failed configuration is recorded with its error and the sweep continues with the next one.
+pub struct SweepRunner<'a>{
+    params: &'a SweepParams,
+    instance: &'a BqmIsingInstance,
+    points: Vec<SweepPoint>
+}
+
+impl<'a> SweepRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a SweepParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        let points = params.configurations()?;
+        return Ok(Self{params, instance, points});
+    }
+
+    pub fn points(&self) -> &[SweepPoint]{
+        return &self.points;
+    }
+
+    /// Run every configuration with the same options, stopping early only on an interruption
+    pub fn run(&self, options: &RunOptions) -> Result<SweepResults, TamcError>{
+        info!("-- Sweep begin");
+        let start = time::Instant::now();
+        let mut runs = Vec::with_capacity(self.points.len());
+        let mut best: Option<(usize, f32, Option<Vec<u64>>)> = None;
+        for (k, p) in self.points.iter().enumerate(){
+            info!("Configuration {} of {}: {}", k + 1, self.points.len(),
+                  describe_point(&p.point));
+            let mut run = SweepRunResults{point: p.point.clone(), method: p.method.clone(), min_energy: None,
+                results: None, error: None, samples: None, final_states: None};
+            match crate::solve(self.instance, &p.method, options){
+                Ok(run_results) => {
+                    let min_energy = run_results.results.min_energy();
+                    if let Some(e) = min_energy{
+                        if best.as_ref().map_or(true, |b| e < b.1){
+                            best = Some((k, e, run_results.gs_state(self.instance)));
+                        }
+                    }
+                    run.min_energy = min_energy;
+                    run.results = Some(run_results.results);
+                    run.samples = run_results.samples;
+                    run.final_states = run_results.final_states;
+                }
+                Err(e) => {
+                    warn!("Configuration {} failed: {}", k, e);
+                    run.error = Some(e.to_string());
+                }
+            }
+            runs.push(run);
+            if control::interrupted(){
+                break;
+            }
+        }
+        let end = start.elapsed();
+        info!("-- Sweep Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        let (best, min_energy, gs_state) = match best{
+            Some((k, e, st)) => (Some(k), Some(e), st),
+            None => (None, None, None)
+        };
+        return Ok(SweepResults{
+            params: self.params.clone(),
+            best,
+            min_energy,
+            gs_state,
+            timing: end.as_micros() as f64,
+            runs
+        });
+    }
+}
+
+/// The file of configuration `k` for an output file, with `_k` appended to the stem,
+/// e.g. `samples_3.bin` for `samples.bin` or `job_samples_3.part.bin` for `job_samples.part.bin`
+pub fn configuration_file(file: &str, k: usize) -> String{
+    let name_start = file.rfind('/').map_or(0, |i| i + 1);
+    return match file[name_start..].find('.'){
+        Some(i) => format!("{}_{}{}", &file[..name_start + i], k, &file[name_start + i..]),
+        None => format!("{}_{}", file, k)
+    };
+}
+
+/// Print a summary of the results and write them to the output file of the program.
+/// The thermal samples of PT and the final states of SA of configuration `k` are written to
+/// the sample output with `_k` appended to its stem.
+pub fn write_results(prog: &Prog, results: &SweepResults) -> Result<()>{
+    let sample_output = prog.sample_output.clone().unwrap_or("samples.bin".to_string());
+    println!("Sweep Done.");
+    println!("** Ground state energy **");
+    for (k, r) in results.runs.iter().enumerate(){
+        match &r.error{
+            Some(e) => println!("  {}: {} failed: {}", k, describe_point(&r.point), e),
+            None => println!("  {}: {} e = {:?}", k, describe_point(&r.point), r.min_energy)
+        }
+    }
+    if let (Some(k), Some(e)) = (results.best, results.min_energy){
+        println!("  e = {} (configuration {})", e, k);
+    }
+    for (k, r) in results.runs.iter().enumerate(){
+        match (&r.results, &r.samples, &r.final_states){
+            (Some(MethodResults::PT(_)), Some(samples), _) =>
+                pt::write_samples(&configuration_file(&sample_output, k), samples)?,
+            (Some(MethodResults::SA(sa_results)), _, Some(states)) =>
+                sa::write_states(&configuration_file(&sample_output, k), sa_results, states)?,
+            _ => {}
+        }
+    }
+    let f = File::create(&prog.output_file)
+        .with_context(|| format!("Failed to create output file {}", prog.output_file))?;
+    serde_yaml::to_writer(f, results)
+        .with_context(|| format!("Failed to write sweep results to {}", prog.output_file))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Method, RunOptions};
+    use crate::error::TamcError;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pt::{BetaOptions, BetaSpec};
+    use crate::sweep::{configuration_file, inline_sweep, SweepRunner};
+
+    #[test]
+    fn test_sweep(){
+        let yaml = r"---
+Sweep:
+  method:
+    SA:
+      beta:
+        Geometric:
+          beta_min: 0.1
+          beta_max: 5.0
+      num_replicas: 4
+      threads: 1
+  grid:
+    beta.Geometric.num_beta: [64, 256]
+    num_replicas: [2, 4, 8]
+";
+        let method: Method = serde_yaml::from_str(yaml).unwrap();
+        let params = match &method { Method::Sweep(p) => p, _ => panic!("expected a sweep") };
+        let points = params.configurations().unwrap();
+        assert_eq!(points.len(), 6);
+        match &points[4].method{
+            Method::SA(sa) => {
+                assert_eq!(sa.num_replicas, 4);
+                assert!(matches!(sa.beta, BetaOptions::Geometric(BetaSpec{num_beta: 256, ..})));
+            }
+            _ => panic!("expected SA")
+        }
+
+        let instance = make_ising_2d_instance(8);
+        let results = SweepRunner::new(&instance, params).unwrap().run(&RunOptions::default()).unwrap();
+        assert_eq!(results.runs.len(), 6);
+        let best = results.best.unwrap();
+        assert_eq!(results.runs[best].min_energy, results.min_energy);
+        assert!(results.runs.iter().all(|r| r.min_energy >= results.min_energy));
+        // each run is tagged with its concrete parameters
+        for (r, p) in results.runs.iter().zip(points.iter()){
+            match (&r.results, &p.method){
+                (Some(crate::MethodResults::SA(res)), Method::SA(sa)) => assert_eq!(res.params.num_replicas, sa.num_replicas),
+                _ => panic!("expected SA results")
+            }
+        }
+
+        // a combination that does not form a valid method is reported before running
+        let mut bad = params.clone();
+        bad.grid.insert("num_replicas".into(), serde_yaml::from_str("[4, -1]").unwrap());
+        assert!(matches!(bad.configurations(), Err(TamcError::InvalidParameters(_))));
+        let mut bad = params.clone();
+        bad.grid.insert("threads".into(), serde_yaml::from_str("[1, 16]").unwrap());
+        assert!(!bad.validate().is_ok());
+
+        // inline lists in a method are read as a grid, except for lists the method accepts
+        let inline: serde_yaml::Value = serde_yaml::from_str(r"---
+MFT:
+  beta:
+    Arr: [0.5, 1.0, 2.0]
+  tap: false
+  iterations_per_beta: 4
+  damping: [0.0, 0.5]
+  num_inits: [4, 0]
+  polish: ~
+  threads: 1
+").unwrap();
+        let params = inline_sweep(&inline).unwrap();
+        let keys: Vec<&str> = params.grid.iter().map(|(k, _)| k.as_str().unwrap()).collect();
+        assert_eq!(keys, vec!["damping", "num_inits"]);
+        assert!(inline_sweep(&serde_yaml::from_str("MFT: {beta: {Arr: [0.5, 1.0]}}").unwrap()).is_none());
+
+        // invalid configurations are reported before running
+        assert!(matches!(SweepRunner::new(&instance, &params), Err(TamcError::InvalidParameters(_))));
+
+        // a configuration that fails while running is recorded and the others still run
+        let yaml = r"---
+Sweep:
+  method:
+    HREM:
+      num_sweeps: 20
+      warmup_fraction: 0.5
+      beta: 2.0
+      ladder:
+        Interpolate: [0.0, 0.5, 1.0]
+      num_replica_chains: 2
+      threads: 1
+  grid:
+    reference: [{Field: 1.0}, {InstanceFile: no/such/reference.txt}, {Ferromagnet: 1.0}]
+";
+        let params = match serde_yaml::from_str(yaml).unwrap() { Method::Sweep(p) => p, _ => panic!("expected a sweep") };
+        let results = SweepRunner::new(&instance, &params).unwrap().run(&RunOptions::default()).unwrap();
+        assert_eq!(results.runs.len(), 3);
+        for (k, r) in results.runs.iter().enumerate(){
+            assert_eq!(r.error.is_some(), k == 1);
+            assert_eq!(r.results.is_none(), k == 1);
+        }
+        assert!(results.min_energy.is_some());
+
+        assert_eq!(configuration_file("samples.bin", 3), "samples_3.bin");
+        assert_eq!(configuration_file("out/job_samples.part.bin", 0), "out/job_samples_0.part.bin");
+    }
+}
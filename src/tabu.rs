@@ -0,0 +1,290 @@
+use std::time;
+
+use anyhow::Result;
+use log::info;
+use rand::prelude::*;
+use rand::distributions::Uniform;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tamc_core::traits::*;
+
+use crate::util::{run_in_pool, seeded_rng, split_rng, write_yaml};
+use crate::Prog;
+use crate::error::TamcError;
+use crate::control::{self, ControlHandle};
+use crate::ising::{BqmIsingInstance, IsingState, rand_ising_state};
+use crate::ising_results::MinResults;
+use crate::validate::Validation;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TabuParams{
+    /// Number of single flips in each restart
+    pub num_iterations: u32,
+    /// Number of iterations a flipped spin stays tabu
+    pub tenure: u32,
+    pub num_restarts: u32,
+    /// Number of best distinct solutions kept to seed restarts
+    pub num_elite: u32,
+    /// Fraction of spins flipped at random when a restart is seeded from an elite solution
+    pub perturbation_fraction: f64,
+    pub threads: u32
+}
+
+impl TabuParams{
+    /// Check every parameter before running
+    pub fn validate(&self) -> Validation{
+        let mut v = Validation::new();
+        if self.num_iterations == 0{
+            v.error("num_iterations", "num_iterations must be positive", "set num_iterations, e.g. to 10000");
+        }
+        if self.tenure == 0{
+            v.warn("tenure", "a tenure of 0 never makes a spin tabu, so the search can cycle between two states",
+                   "set tenure to e.g. 20");
+        }
+        if self.num_restarts == 0{
+            v.error("num_restarts", "num_restarts must be positive", "set num_restarts, e.g. to 100");
+        }
+        if self.num_elite == 0{
+            v.warn("num_elite", "no elite solutions are kept, so every restart begins from a random state",
+                   "set num_elite to e.g. 4");
+        }
+        if !(0.0..=1.0).contains(&self.perturbation_fraction){
+            v.error("perturbation_fraction", format!("perturbation_fraction must be in [0, 1], but is {}", self.perturbation_fraction),
+                    "set perturbation_fraction to e.g. 0.1");
+        }
+        if self.threads == 0{
+            v.error("threads", "threads must be positive", "set threads to e.g. 1");
+        }
+        return v;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TabuMinResults{
+    pub params: TabuParams,
+    /// The ground state trace is indexed by restart
+    #[serde(flatten)]
+    pub min_results: MinResults,
+    /// Best energy found in each restart
+    pub restart_energies: Vec<f32>
+}
+
+/// Single-flip tabu search state with incrementally updated energy changes
+struct TabuSearch<'a>{
+    instance: &'a BqmIsingInstance,
+    delta_e: Vec<f32>,
+    tabu_until: Vec<u32>
+}
+
+impl<'a> TabuSearch<'a>{
+    fn new(instance: &'a BqmIsingInstance) -> Self{
+        let n = instance.size();
+        return Self{instance, delta_e: vec![0.0; n], tabu_until: vec![0; n]};
+    }
+
+    fn init(&mut self, state: &mut IsingState){
+        for i in 0..self.delta_e.len(){
+            self.delta_e[i] = unsafe { self.instance.delta_energy(state, &(i as u32)) };
+        }
+        for t in self.tabu_until.iter_mut(){
+            *t = 0;
+        }
+    }
+
+    /// Flip spin i and update the energy changes of its neighbors
+    ///     ΔE_j += -4 K_ij s_i s_j
+    fn flip(&mut self, state: &mut IsingState, i: u32){
+        state.accept_move(i);
+        let si = state[i as usize] as f32;
+        self.delta_e[i as usize] = -self.delta_e[i as usize];
+        for &(j, k) in self.instance.coupling_vecs[i as usize].iter(){
+            let sj = state[j as usize] as f32;
+            self.delta_e[j as usize] -= 4.0 * k * si * sj;
+        }
+    }
+
+    /// Run tabu search from the state for a number of iterations.
+    /// Returns the best energy and state found.
+    fn search(&mut self, state: &mut IsingState, num_iterations: u32, tenure: u32,
+              control: &Option<ControlHandle>) -> (f32, IsingState){
+        self.init(state);
+        let mut e = self.instance.energy_ref(state);
+        let mut best_e = e;
+        let mut best_state = state.clone();
+        for it in 1..=num_iterations{
+            if control::should_stop(control){
+                break;
+            }
+            let mut choice: Option<(usize, f32)> = None;
+            for (i, &de) in self.delta_e.iter().enumerate(){
+                // aspiration: a tabu move is allowed if it improves on the best energy
+                let allowed = self.tabu_until[i] < it || e + de < best_e;
+                if allowed && choice.map_or(true, |(_, de_min)| de < de_min){
+                    choice = Some((i, de));
+                }
+            }
+            let (i, de) = match choice{
+                None => continue,
+                Some(c) => c
+            };
+            self.flip(state, i as u32);
+            self.tabu_until[i] = it + tenure;
+            e += de;
+            if e < best_e{
+                best_e = e;
+                best_state.clone_from(state);
+                control::report(control, e);
+            }
+        }
+        best_state.energy = best_e;
+        best_state.energy_init = true;
+        return (best_e, best_state);
+    }
+}
+
+/// Multi-start tabu search. Restarts run in parallel rounds of one restart per thread.
+/// After the first round, each restart begins from a random elite solution with a
+/// fraction of its spins flipped at random.
+pub struct TabuRunner<'a>{
+    params: &'a TabuParams,
+    instance: &'a BqmIsingInstance,
+    control: Option<ControlHandle<'a>>
+}
+
+impl<'a> TabuRunner<'a>{
+    pub fn new(instance: &'a BqmIsingInstance, params: &'a TabuParams) -> Result<Self, TamcError>{
+        params.validate().into_result()?;
+        return Ok(Self{params, instance, control: None});
+    }
+
+    /// Stop the run early when the control requests it, and report new minimum energies to it
+    pub fn with_control(mut self, control: ControlHandle<'a>) -> Self{
+        self.control = Some(control);
+        return self;
+    }
+
+    pub fn run(&self) -> Result<TabuMinResults, TamcError>{
+        // seed and create random number generators, one for each thread
+        let mut rng = seeded_rng();
+        let threads = self.params.threads.max(1);
+        let mut rng_vec = split_rng(&mut rng, threads as usize);
+        let n = self.instance.size();
+        let mut min_results = MinResults::new(0, n as u32);
+        let mut restart_energies = Vec::with_capacity(self.params.num_restarts as usize);
+        let mut elite: Vec<(f32, IsingState)> = Vec::new();
+        info!("-- Tabu begin");
+        let start = time::Instant::now();
+        let mut restart = 0;
+        run_in_pool(threads, || {
+            while restart < self.params.num_restarts && !control::should_stop(&self.control){
+                let round_size = (self.params.num_restarts - restart).min(threads) as usize;
+                let mut round_results = Vec::with_capacity(round_size);
+                rng_vec[..round_size].par_iter_mut()
+                    .map(|rng| {
+                        let mut init_state = self.restart_state(&elite, rng);
+                        let mut search = TabuSearch::new(self.instance);
+                        search.search(&mut init_state, self.params.num_iterations, self.params.tenure, &self.control)
+                    })
+                    .collect_into_vec(&mut round_results);
+                for (e, st) in round_results.into_iter(){
+                    restart_energies.push(e);
+                    min_results.record(e, restart, || st.as_u64_vec());
+                    self.update_elite(&mut elite, e, st);
+                    restart += 1;
+                }
+            }
+        });
+        let end = start.elapsed();
+        info!("-- Tabu Finished");
+        info!("Duration: {:5.4} s", end.as_secs_f64());
+        min_results.num_measurements = restart;
+        min_results.timing = end.as_micros() as f64;
+
+        return Ok(TabuMinResults{params: self.params.clone(), min_results, restart_energies});
+    }
+
+    fn restart_state<Rn: Rng+?Sized>(&self, elite: &[(f32, IsingState)], rng: &mut Rn) -> IsingState{
+        let n = self.instance.size() as u32;
+        if elite.is_empty(){
+            return rand_ising_state(n, self.instance, rng);
+        }
+        let mut state = elite[rng.gen_range(0..elite.len())].1.clone();
+        let num_perturb = (self.params.perturbation_fraction * n as f64).round() as u32;
+        let site_distr = Uniform::new(0, n);
+        for _ in 0..num_perturb{
+            state.accept_move(rng.sample(site_distr));
+        }
+        state.energy_init = false;
+        return state;
+    }
+
+    /// Keep the best num_elite distinct solutions sorted by energy
+    fn update_elite(&self, elite: &mut Vec<(f32, IsingState)>, e: f32, st: IsingState){
+        let num_elite = self.params.num_elite as usize;
+        if num_elite == 0 || elite.iter().any(|(_, s)| s.arr == st.arr){
+            return;
+        }
+        if elite.len() == num_elite && e >= elite.last().unwrap().0{
+            return;
+        }
+        let pos = elite.iter().position(|(e2, _)| e < *e2).unwrap_or(elite.len());
+        elite.insert(pos, (e, st));
+        elite.truncate(num_elite);
+    }
+}
+
+/// Print a summary of the results and write them to the output file of the program
+pub fn write_results(prog: &Prog, results: &TabuMinResults) -> Result<()>{
+    println!("Tabu Done.");
+    println!("** Ground state energy **");
+    if let Some(&e_min) = results.min_results.gs_energies.last(){
+        println!("  e = {}", e_min);
+        let num_gs = results.restart_energies.iter().filter(|&&e| e <= e_min).count();
+        println!("  restarts reaching e = {}/{}", num_gs, results.restart_energies.len());
+    }
+    write_yaml(&prog.output_file, results, "tabu")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+    use tamc_core::traits::*;
+    use crate::ising::rand_ising_state;
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::tabu::{TabuParams, TabuRunner, TabuSearch};
+    use crate::util::with_seed;
+
+    #[test]
+    fn test_ising_2d_tabu(){
+        let l = 8;
+        let n = (l*l) as u32;
+        let instance = make_ising_2d_instance(l);
+        // the incremental energy changes agree with direct evaluation
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1234);
+        let mut st = rand_ising_state(n, &instance, &mut rng);
+        let mut search = TabuSearch::new(&instance);
+        search.init(&mut st);
+        for _ in 0..100{
+            search.flip(&mut st, rng.gen_range(0..n));
+        }
+        for i in 0..n{
+            assert_eq!(search.delta_e[i as usize], unsafe { instance.delta_energy(&mut st, &i) });
+        }
+
+        let params = TabuParams{
+            num_iterations: 1000,
+            tenure: 8,
+            num_restarts: 8,
+            num_elite: 2,
+            perturbation_fraction: 0.1,
+            threads: 2
+        };
+        let results = with_seed(Some(1234), || TabuRunner::new(&instance, &params).unwrap().run()).unwrap();
+        let &e = results.min_results.gs_energies.last().unwrap();
+        println!("e = {}, restart energies: {:?}", e, results.restart_energies);
+        assert_eq!(results.restart_energies.len(), 8);
+        assert_eq!(e, -2.0 * (l*l) as f32);
+    }
+}
@@ -6,7 +6,8 @@ use std::fs::File;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::path::Path;
-use log::debug;
+use std::cell::RefCell;
+use log::{debug, warn};
 use ndarray::prelude::*;
 use nom::{InputTake, IResult};
 use nom::character::complete::{digit1, space1};
@@ -21,7 +22,93 @@ use petgraph::prelude::*;
 use petgraph::csr::Csr;
 use petgraph::Undirected;
 use rand::prelude::*;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
 use serde::Serialize;
+use crate::error::TamcError;
+use anyhow::Context;
+
+thread_local!{
+    static SEED_RNG: RefCell<Option<Xoshiro256PlusPlus>> = RefCell::new(None);
+}
+
+/// Fill the seed of the random number generator of a run.
+/// Inside `with_seed`, seeds are drawn from the given seed so that runs are reproducible,
+/// and otherwise from entropy.
+pub fn fill_seed(seed_seq: &mut [u8; 32]){
+    SEED_RNG.with(|r| match r.borrow_mut().as_mut(){
+        Some(rng) => rng.fill_bytes(seed_seq),
+        None => thread_rng().fill_bytes(seed_seq)
+    });
+}
+
+/// The random number generator of a run, seeded by `fill_seed`
+pub fn seeded_rng() -> Xoshiro256PlusPlus{
+    let mut seed_seq = [0u8; 32];
+    fill_seed(&mut seed_seq);
+    return Xoshiro256PlusPlus::from_seed(seed_seq);
+}
+
+/// Independent generators for `num` parallel tasks, each a jump ahead of the previous one
+pub fn split_rng(rng: &mut Xoshiro256PlusPlus, num: usize) -> Vec<Xoshiro256PlusPlus>{
+    let mut rng_vec = Vec::with_capacity(num);
+    for _ in 0..num{
+        rng_vec.push(rng.clone());
+        rng.jump();
+    }
+    return rng_vec;
+}
+
+/// Split `total` units of work evenly between one parallel task per generator, rounding up,
+/// and collect the results of `f(units, rng)` of each task
+pub fn split_work<T, F>(total: u32, rng_vec: &mut [Xoshiro256PlusPlus], f: F) -> Vec<T>
+where T: Send, F: Fn(u32, &mut Xoshiro256PlusPlus) -> T + Sync + Send
+{
+    let tasks = rng_vec.len().max(1) as u32;
+    let units_per_task = (total + tasks - 1) / tasks;
+    let mut results = Vec::with_capacity(rng_vec.len());
+    rng_vec.par_iter_mut()
+        .map(|rng| f(units_per_task, rng))
+        .collect_into_vec(&mut results);
+    return results;
+}
+
+/// Call `f` with the runs it starts on this thread seeded from `seed`, if any
+pub fn with_seed<T, F: FnOnce() -> T>(seed: Option<u64>, f: F) -> T{
+    let prev = SEED_RNG.with(|r| r.replace(seed.map(Xoshiro256PlusPlus::seed_from_u64)));
+    let result = f();
+    SEED_RNG.with(|r| r.replace(prev));
+    return result;
+}
+
+/// A thread pool for the parallel work of one run
+pub fn thread_pool(threads: u32) -> Result<rayon::ThreadPool, TamcError>{
+    return rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.max(1) as usize)
+        .build()
+        .map_err(TamcError::ThreadPool);
+}
+
+/// Run `f` on a dedicated pool of `threads` threads, so that its parallel work uses no other threads.
+/// If the pool cannot be built, `f` runs on the current pool.
+pub fn run_in_pool<T: Send, F: FnOnce() -> T + Send>(threads: u32, f: F) -> T{
+    return match thread_pool(threads){
+        Ok(pool) => pool.install(f),
+        Err(e) => {
+            warn!("{}. Running on the current thread pool.", e);
+            f()
+        }
+    };
+}
+
+/// Write the results of a method to a YAML file
+pub fn write_yaml<T: Serialize>(output_file: &str, results: &T, method: &str) -> anyhow::Result<()>{
+    let f = File::create(output_file)
+        .with_context(|| format!("Failed to create output file {}", output_file))?;
+    serde_yaml::to_writer(f, results)
+        .with_context(|| format!("Failed to write {} results to {}", method, output_file))?;
+    Ok(())
+}
 
 pub fn write_data<P: AsRef<Path>+AsRef<OsStr>, T: Serialize>(output_file: &P, ser_data: &T) -> anyhow::Result<()>{
 
@@ -66,24 +153,31 @@ pub mod connectivity_list{
 }
 
 
-pub fn read_adjacency_list_from_file(filename: &str) -> Result<Vec<BTreeMap<usize, f32>>, io::Error> {
-    let file = File::open(filename);
-    let file = match file {Ok(f) => f, Err(e) => return Err(e) };
+/// Blank lines and comment lines starting with `#` are skipped by the text readers
+pub(crate) fn is_skipped_line(line: &str) -> bool{
+    let line = line.trim();
+    return line.is_empty() || line.starts_with('#');
+}
+
+pub fn read_adjacency_list_from_file(filename: &str) -> Result<Vec<BTreeMap<usize, f32>>, TamcError> {
+    let file = File::open(filename).map_err(|e| TamcError::Io(e, filename.to_string()))?;
 
-    read_adjacency_list(file)
+    read_adjacency_list(file).map_err(|e| e.in_file(filename))
 }
 
-pub fn read_adjacency_list<R: io::Read>(input: R) -> Result<Vec<BTreeMap<usize, f32>>, io::Error>
+pub fn read_adjacency_list<R: io::Read>(input: R) -> Result<Vec<BTreeMap<usize, f32>>, TamcError>
 {
     use connectivity_list::parse_line;
     use std::cmp::max;
-    use std::error::Error;
 
     let reader = BufReader::new(input);
 
     let mut adj_list : Vec<BTreeMap<usize, f32>> = Vec::new();
     for (_i, line) in reader.lines().enumerate(){
-        let line = match line{Ok(l) => l, Err(e) => return Err(e)};
+        let line = line?;
+        if is_skipped_line(&line){
+            continue;
+        }
         match parse_line(&line){
             Ok((i, j, h)) => {
                 let i = i as usize; let j = j as usize;
@@ -99,7 +193,8 @@ pub fn read_adjacency_list<R: io::Read>(input: R) -> Result<Vec<BTreeMap<usize,
                 }
             }
             Err(e) =>{
-                println!("Ignoring line {}: {}", _i, e)
+                return Err(TamcError::Parse{file: String::new(), line: _i + 1,
+                    msg: format!("expected 'i j K', found '{}' ({})", line, e)});
             }
         }
 
@@ -109,11 +204,8 @@ pub fn read_adjacency_list<R: io::Read>(input: R) -> Result<Vec<BTreeMap<usize,
 }
 
 
-pub fn read_txt_vec<R: io::Read>(input: R) -> Result<Vec<f64>, io::Error>
+pub fn read_txt_vec<R: io::Read>(input: R) -> Result<Vec<f64>, TamcError>
 {
-    use std::cmp::max;
-    use std::error::Error;
-
     pub fn parse_line(line: &str) -> Result<f64, nom::Err<nom::error::Error<&str>> >{
         let mut parser = delimited(space0, parse_fixed, multispace0);
         let (i, d) = parser(line)?;
@@ -124,13 +216,17 @@ pub fn read_txt_vec<R: io::Read>(input: R) -> Result<Vec<f64>, io::Error>
 
     let mut dvec : Vec<f64> = Vec::new();
     for (_i, line) in reader.lines().enumerate(){
-        let line = match line{Ok(l) => l, Err(e) => return Err(e)};
+        let line = line?;
+        if is_skipped_line(&line){
+            continue;
+        }
         match parse_line(&line){
             Ok(d) => {
                 dvec.push(d);
             }
             Err(e) =>{
-                println!("Ignoring line {}: {}", _i, e)
+                return Err(TamcError::Parse{file: String::new(), line: _i + 1,
+                    msg: format!("expected a number, found '{}' ({})", line, e)});
             }
         }
     };
@@ -139,11 +235,8 @@ pub fn read_txt_vec<R: io::Read>(input: R) -> Result<Vec<f64>, io::Error>
 }
 
 
-pub fn read_u32_lines<R: io::Read>(input: R) -> Result<Vec<Vec<u32>>, io::Error>
+pub fn read_u32_lines<R: io::Read>(input: R) -> Result<Vec<Vec<u32>>, TamcError>
 {
-    use std::cmp::max;
-    use std::error::Error;
-
     pub fn parse_line(line: &str) -> Result<Vec<u32>, nom::Err<nom::error::Error<&str>> >{
         let mut float_parser = delimited(space0, parse_u32, multispace0);
         let mut line_parser = many1(float_parser);
@@ -155,15 +248,18 @@ pub fn read_u32_lines<R: io::Read>(input: R) -> Result<Vec<Vec<u32>>, io::Error>
 
     let mut dvec : Vec<Vec<u32>> = Vec::new();
     for (_i, line) in reader.lines().enumerate(){
-        let line = match line{Ok(l) => l, Err(e) => return Err(e)};
+        let line = line?;
+        if is_skipped_line(&line){
+            debug!("Ignoring line {}", _i + 1);
+            continue;
+        }
         match parse_line(&line){
             Ok(d) => {
                 dvec.push(d);
             }
             Err(e) =>{
-                if line.len() > 0 {
-                    debug!("Ignoring line {}: {}", _i, e);
-                }
+                return Err(TamcError::Parse{file: String::new(), line: _i + 1,
+                    msg: format!("expected a list of indices, found '{}' ({})", line, e)});
             }
         }
     };
@@ -210,4 +306,19 @@ pub fn bayesian_bootstrap<Rn: Rng+?Sized>(num_bootstraps: usize, n: usize, rng:
     let gaps : Array2<f64> = rn_arr.slice(s![.., 1..]).to_owned() - rn_arr.slice(s![.., 0..-1]);
 
     return gaps
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use rayon::prelude::*;
+    use crate::util::run_in_pool;
+
+    #[test]
+    fn test_run_in_pool(){
+        assert_eq!(run_in_pool(2, rayon::current_num_threads), 2);
+        // nested pools are sized independently of the enclosing pool
+        let inner: Vec<usize> = run_in_pool(3, ||
+            (0..3).into_par_iter().map(|_| run_in_pool(1, rayon::current_num_threads)).collect());
+        assert_eq!(inner, vec![1, 1, 1]);
+        assert_eq!(run_in_pool(0, rayon::current_num_threads), 1);
+    }
+}
@@ -0,0 +1,198 @@
+//! Validation of method parameters before a run.
+//! Each problem names the offending field and suggests a fix,
+//! and suspicious but legal settings are reported as warnings.
+use std::fmt;
+
+use log::warn;
+
+use crate::error::TamcError;
+use crate::observe::ObservableSpec;
+
+/// A problem with one parameter
+#[derive(Clone, Debug)]
+pub struct ParamIssue{
+    pub field: String,
+    pub problem: String,
+    pub suggestion: String
+}
+
+impl fmt::Display for ParamIssue{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}. Suggestion: {}", self.field, self.problem, self.suggestion)
+    }
+}
+
+/// All errors and warnings found in a set of parameters
+#[derive(Clone, Debug, Default)]
+pub struct Validation{
+    pub errors: Vec<ParamIssue>,
+    pub warnings: Vec<ParamIssue>
+}
+
+impl Validation{
+    pub fn new() -> Self{
+        return Self::default();
+    }
+
+    pub fn error(&mut self, field: &str, problem: impl Into<String>, suggestion: impl Into<String>){
+        self.errors.push(ParamIssue{field: field.to_string(), problem: problem.into(), suggestion: suggestion.into()});
+    }
+
+    pub fn warn(&mut self, field: &str, problem: impl Into<String>, suggestion: impl Into<String>){
+        self.warnings.push(ParamIssue{field: field.to_string(), problem: problem.into(), suggestion: suggestion.into()});
+    }
+
+    /// Add the issues of a nested parameter set, prefixing their fields with `prefix.`
+    pub fn extend_nested(&mut self, prefix: &str, other: Validation){
+        let nest = |mut p: ParamIssue| { p.field = format!("{}.{}", prefix, p.field); p };
+        self.errors.extend(other.errors.into_iter().map(nest));
+        self.warnings.extend(other.warnings.into_iter().map(nest));
+    }
+
+    pub fn is_ok(&self) -> bool{
+        return self.errors.is_empty();
+    }
+
+    /// Log every warning
+    pub fn log_warnings(&self){
+        for w in self.warnings.iter(){
+            warn!("{}", w);
+        }
+    }
+
+    /// Returns the warnings if there are no errors, and otherwise all the errors
+    pub fn into_result(self) -> Result<Vec<ParamIssue>, TamcError>{
+        return if self.errors.is_empty(){
+            Ok(self.warnings)
+        } else {
+            Err(TamcError::InvalidParameters(self.errors))
+        };
+    }
+}
+
+/// Check the optional time limit and target energy shared by several methods
+pub(crate) fn validate_limits(time_limit: Option<f64>, target_energy: Option<f32>, v: &mut Validation){
+    if let Some(t) = time_limit{
+        if !(t > 0.0 && t.is_finite()){
+            v.error("time_limit", format!("time_limit must be a positive number of seconds, but is {}", t),
+                    "set a positive time_limit or remove it");
+        }
+    }
+    if let Some(e) = target_energy{
+        if !e.is_finite(){
+            v.error("target_energy", "target_energy must be finite", "set a finite target_energy or remove it");
+        }
+    }
+}
+
+/// Check the sampling intervals of the optional built-in observables
+pub(crate) fn validate_observables(observables: &Option<Vec<ObservableSpec>>, v: &mut Validation){
+    for spec in observables.iter().flatten(){
+        if spec.interval == 0{
+            v.error("observables", format!("the interval of {:?} must be positive", spec.observable),
+                    "set the interval to the number of sweeps between measurements, e.g. 1");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Method;
+    use crate::descent::{DescentKind, DescentParams};
+    use crate::error::TamcError;
+    use crate::gla::GlaParams;
+    use crate::hrem::{HremLadder, HremParams, HremReference};
+    use crate::pipeline::PipelineParams;
+    use crate::pt::{BetaOptions, PtIcmParams};
+    use crate::sa::SaParams;
+    use crate::st::StParams;
+    use tamc_core::st::STWeights;
+
+    #[test]
+    fn test_validate_params(){
+        assert!(PtIcmParams{lo_num_beta: Some(4), ..Default::default()}.validate().is_ok());
+        let params = PtIcmParams{
+            beta: BetaOptions::Arr(vec![0.1, 1.0, 0.5]),
+            lo_num_beta: Some(2),
+            num_replica_chains: 3,
+            threads: 16,
+            sample: Some(0),
+            sample_limiting: Some(5),
+            ..Default::default()
+        };
+        let v = params.validate();
+        let fields: Vec<&str> = v.errors.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(fields, vec!["beta", "num_replica_chains", "threads", "sample"]);
+        assert!(v.warnings.iter().any(|p| p.field == "sample_limiting"));
+        match v.into_result(){
+            Err(TamcError::InvalidParameters(errors)) => assert_eq!(errors.len(), 4),
+            _ => panic!("expected invalid parameters")
+        }
+
+        let sa_params = SaParams{
+            beta: BetaOptions::new_geometric(0.1, 5.0, 64),
+            num_replicas: 10,
+            threads: 4,
+            ais: Some(true),
+            quench: None,
+            time_limit: Some(-1.0),
+            target_energy: None,
+            observables: None
+        };
+        let v = sa_params.validate();
+        assert_eq!(v.errors.len(), 1);
+        assert_eq!(v.errors[0].field, "time_limit");
+        let warned: Vec<&str> = v.warnings.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(warned, vec!["num_replicas", "ais"]);
+
+        let gla_params = GlaParams{
+            num_threads: 0,
+            pt_params: PtIcmParams{icm: true, ..Default::default()},
+            partition_file: "no/such/partition.txt".to_string(),
+            quench: None
+        };
+        let v = gla_params.validate();
+        let fields: Vec<&str> = v.errors.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(fields, vec!["num_threads", "partition_file", "pt_params.lo_beta"]);
+
+        let st_params = StParams{
+            num_sweeps: 1000,
+            warmup_fraction: 1.0,
+            beta: BetaOptions::new_geometric(0.1, 5.0, 16),
+            weights: STWeights::WangLandau{ ln_f0: 1.0, flatness: 1.5, ln_f_min: 1e-4 },
+            num_replicas: 0,
+            threads: 1
+        };
+        let v = st_params.validate();
+        let fields: Vec<&str> = v.errors.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(fields, vec!["warmup_fraction", "weights.flatness", "num_replicas"]);
+
+        let hrem_params = HremParams{
+            num_sweeps: 0,
+            warmup_fraction: 0.5,
+            beta: 2.0,
+            reference: HremReference::Field(1.0),
+            ladder: HremLadder::Interpolate(vec![0.0, 0.5, 0.9]),
+            num_replica_chains: 2,
+            threads: 4
+        };
+        let v = hrem_params.validate();
+        let fields: Vec<&str> = v.errors.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(fields, vec!["num_sweeps"]);
+        let warned: Vec<&str> = v.warnings.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(warned, vec!["ladder", "threads"]);
+
+        let descent = |num_restarts| Method::Descent(DescentParams{kind: DescentKind::Greedy, num_restarts, threads: 1});
+        assert!(!descent(0).validate().is_ok());
+        let pipeline_params = PipelineParams{
+            stages: vec![
+                descent(100),
+                Method::SA(SaParams{num_replicas: 0, ..sa_params.clone()}),
+                descent(0)
+            ]
+        };
+        let v = Method::Pipeline(pipeline_params).validate();
+        let fields: Vec<&str> = v.errors.iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(fields, vec!["stages.0", "stages.1.num_replicas", "stages.1.time_limit"]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Initial states of PT and SA runs, read from the output of other solvers or of earlier runs
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use rand::prelude::*;
+use rand::seq::index::sample;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde_yaml::Value;
+use tamc_core::traits::Instance;
+
+use crate::error::TamcError;
+use crate::ising::{BqmIsingInstance, IsingState};
+use crate::sa::AnnealState;
+use crate::util::{fill_seed, is_skipped_line};
+
+/// How the initial states are assigned to the replicas of a run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Assignment{
+    /// Every replica starts from the lowest energy state
+    Broadcast,
+    /// The replicas start from each state in turn, cycling through them as needed
+    Distribute
+}
+
+impl FromStr for Assignment{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_ascii_lowercase().as_str(){
+            "broadcast" => Ok(Assignment::Broadcast),
+            "distribute" => Ok(Assignment::Distribute),
+            _ => Err(format!("unknown assignment '{}', expected broadcast or distribute", s))
+        };
+    }
+}
+
+/// States to start the replicas of a run from.
+/// The first copy of each state is kept as given, and every further copy has a
+/// `perturbation` fraction of its spins flipped at random.
+pub struct InitialStates{
+    pub states: Vec<IsingState>,
+    pub assignment: Assignment,
+    pub perturbation: f64
+}
+
+impl InitialStates{
+    pub fn new(states: Vec<IsingState>) -> Result<Self, TamcError>{
+        if states.is_empty(){
+            return Err(TamcError::invalid_parameter("initial_states", "at least one initial state is required"));
+        }
+        return Ok(Self{states, assignment: Assignment::Distribute, perturbation: 0.0});
+    }
+
+    pub fn with_assignment(mut self, assignment: Assignment) -> Self{
+        self.assignment = assignment;
+        return self;
+    }
+
+    pub fn with_perturbation(mut self, perturbation: f64) -> Result<Self, TamcError>{
+        if !(0.0..=1.0).contains(&perturbation){
+            return Err(TamcError::invalid_parameter("initial_perturbation",
+                format!("the perturbation fraction {} is not between 0 and 1", perturbation)));
+        }
+        self.perturbation = perturbation;
+        return Ok(self);
+    }
+
+    /// Read the states of an instance of `size` spins from a file, by its extension:
+    /// SA final states (`AnnealState`) in bincode (`.bin`) or pickle (`.pkl`),
+    /// the `gs_states` of PT results (`.yml` or `.yaml`), from the lowest energy,
+    /// or else one state per line of `0` (spin +1) and `1` (spin -1) characters.
+    pub fn read(file: &str, size: u32) -> Result<Self, TamcError>{
+        let ext = Path::new(file).extension().and_then(OsStr::to_str);
+        let open = || File::open(file).map_err(|e| TamcError::Io(e, file.to_string()));
+        let invalid = |msg: String| TamcError::invalid_parameter("initial_states", format!("'{}' {}", file, msg));
+        let states = match ext{
+            Some("bin") | Some("pkl") => {
+                let anneal_state: AnnealState = if ext == Some("pkl"){
+                    serde_pickle::from_reader(open()?, serde_pickle::DeOptions::default())
+                        .map_err(|e| invalid(format!("is not a pickled anneal state: {}", e)))?
+                } else {
+                    bincode::deserialize_from(BufReader::new(open()?))
+                        .map_err(|e| invalid(format!("is not a bincode anneal state: {}", e)))?
+                };
+                if anneal_state.instance_size != size{
+                    return Err(TamcError::size_mismatch("initial states", size as usize,
+                                                        anneal_state.instance_size as usize));
+                }
+                anneal_state.states()?
+            }
+            Some("yml") | Some("yaml") => {
+                let results: Value = serde_yaml::from_reader(open()?)
+                    .map_err(|e| invalid(format!("is not a YAML file: {}", e)))?;
+                let gs_states = results.get("min_results").and_then(|m| m.get("gs_states"))
+                    .or_else(|| results.get("gs_states"))
+                    .ok_or_else(|| invalid("has no gs_states".to_string()))?;
+                let gs_states: Vec<Vec<u64>> = serde_yaml::from_value(gs_states.clone())
+                    .map_err(|e| invalid(format!("has invalid gs_states: {}", e)))?;
+                let num_words = (size as usize + 63) / 64;
+                // the trace of ground states ends with the lowest energy
+                gs_states.iter().rev().map(|v|
+                    if v.len() == num_words {
+                        Ok(IsingState::from_u64_vec(v, size).unwrap())
+                    } else {
+                        Err(TamcError::size_mismatch("initial state words", num_words, v.len()))
+                    }).collect::<Result<Vec<_>, _>>()?
+            }
+            _ => read_bitstrings(open()?, size).map_err(|e| e.in_file(file))?
+        };
+        return Self::new(states);
+    }
+
+    /// The initial states of `num_replicas` replicas of a run on the instance
+    pub fn replicas(&self, instance: &BqmIsingInstance, num_replicas: usize) -> Vec<IsingState>{
+        let mut seed_seq = [0u8; 32];
+        fill_seed(&mut seed_seq);
+        let mut rng = Xoshiro256PlusPlus::from_seed(seed_seq);
+        let sources: Vec<&IsingState> = match self.assignment{
+            Assignment::Broadcast => {
+                let best = self.states.iter()
+                    .map(|st| (instance.energy_ref(st), st))
+                    .min_by(|x, y| x.0.partial_cmp(&y.0).unwrap())
+                    .map(|(_, st)| st).unwrap();
+                vec![best]
+            }
+            Assignment::Distribute => self.states.iter().collect()
+        };
+        let n = instance.size();
+        let num_flips = (self.perturbation * n as f64).round() as usize;
+        return (0..num_replicas).map(|k| {
+            let mut st = sources[k % sources.len()].clone();
+            if k >= sources.len() && num_flips > 0{
+                for i in sample(&mut rng, n, num_flips.min(n)).into_iter(){
+                    st.arr[i] *= -1;
+                }
+            }
+            st.energy_init = false;
+            st
+        }).collect();
+    }
+}
+
+/// Read one state per line of `0` and `1` characters, skipping blank lines and `#` comments
+fn read_bitstrings<R: std::io::Read>(input: R, size: u32) -> Result<Vec<IsingState>, TamcError>{
+    let mut states = Vec::new();
+    for (i, line) in BufReader::new(input).lines().enumerate(){
+        let line = line.map_err(|e| TamcError::Io(e, String::new()))?;
+        if is_skipped_line(&line){
+            continue;
+        }
+        let arr = line.trim().chars().map(|c| match c{
+            '0' => Ok(1),
+            '1' => Ok(-1),
+            _ => Err(TamcError::Parse{file: String::new(), line: i + 1,
+                msg: format!("expected a string of 0 and 1, found '{}'", c)})
+        }).collect::<Result<Vec<_>, _>>()?;
+        if arr.len() != size as usize{
+            return Err(TamcError::Parse{file: String::new(), line: i + 1,
+                msg: format!("expected {} spins, found {}", size, arr.len())});
+        }
+        states.push(IsingState{arr, energy: 0.0, energy_init: false});
+    }
+    return Ok(states);
+}
+
+#[cfg(test)]
+mod tests {
+    use tamc_core::traits::Instance;
+    use crate::{Method, RunOptions, solve};
+    use crate::ising::tests::make_ising_2d_instance;
+    use crate::pt::{BetaOptions, PtIcmParams};
+    use crate::sa::{AnnealState, SaParams};
+    use crate::warmstart::{Assignment, InitialStates};
+
+    #[test]
+    fn test_warm_start(){
+        let instance = make_ising_2d_instance(8);
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(format!("tamc_warmstart_{}_{}", std::process::id(), name))
+            .to_str().unwrap().to_string();
+        // the ferromagnetic ground state and a random state
+        let text_file = path("states.txt");
+        let random: String = (0..64).map(|i| if i % 3 == 0 { '1' } else { '0' }).collect();
+        std::fs::write(&text_file, format!("# ground state\n{}\n{}\n", "0".repeat(64), random)).unwrap();
+        let init = InitialStates::read(&text_file, 64).unwrap();
+        std::fs::remove_file(&text_file).unwrap();
+        assert_eq!(init.states.len(), 2);
+        assert!(matches!(InitialStates::read(&text_file, 64), Err(_)));
+
+        // the SA state files read back to the same states
+        let bin_file = path("states.bin");
+        bincode::serialize_into(std::fs::File::create(&bin_file).unwrap(), &AnnealState::new(&init.states)).unwrap();
+        let from_bin = InitialStates::read(&bin_file, 64).unwrap();
+        std::fs::remove_file(&bin_file).unwrap();
+        assert!(from_bin.states.iter().zip(init.states.iter()).all(|(a, b)| a.arr == b.arr));
+
+        let init = init.with_assignment(Assignment::Broadcast).with_perturbation(0.25).unwrap();
+        let replicas = init.replicas(&instance, 4);
+        assert_eq!(instance.energy_ref(&replicas[0]), -128.0);
+        for st in replicas[1..].iter(){
+            assert_eq!(st.arr.iter().filter(|&&s| s < 0).count(), 16);
+        }
+        assert!(init.with_perturbation(1.5).is_err());
+
+        // a cold PT run started from the ground state keeps it
+        let init = InitialStates::read(&{
+            let f = path("gs.txt");
+            std::fs::write(&f, "0".repeat(64)).unwrap();
+            f
+        }, 64).unwrap();
+        std::fs::remove_file(path("gs.txt")).unwrap();
+        let method = Method::PT(PtIcmParams{
+            num_sweeps: 4,
+            warmup_fraction: 0.0,
+            beta: BetaOptions::new_geometric(4.0, 5.0, 4),
+            icm: false,
+            ..Default::default()
+        });
+        let results = solve(&instance, &method, &RunOptions{initial_states: Some(&init), ..Default::default()}).unwrap();
+        assert_eq!(results.results.min_energy(), Some(-128.0));
+
+        // AIS weights are only unbiased from uniformly random initial states
+        let ais = Method::SA(SaParams{
+            beta: BetaOptions::new_geometric(0.1, 1.0, 8),
+            num_replicas: 4,
+            threads: 1,
+            ais: Some(true),
+            quench: None,
+            time_limit: None,
+            target_energy: None,
+            observables: None
+        });
+        assert!(solve(&instance, &ais, &RunOptions{initial_states: Some(&init), ..Default::default()}).is_err());
+    }
+}
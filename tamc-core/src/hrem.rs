@@ -0,0 +1,71 @@
+//! Hamiltonian replica exchange
+use std::marker::PhantomData;
+use crate::traits::*;
+use crate::pt::PTState;
+use num_traits::real::Real;
+use rand::Rng;
+use rand::distributions::{Standard, Distribution};
+
+/// Replica exchange over a ladder of samplers that may each have a different Hamiltonian,
+/// such as an interpolation between two instances or a varying penalty weight.
+/// A swap of the states x_j and x_{j+1} of neighboring rungs is accepted with probability
+///     $$ \min(1, \exp(u_j(x_j) + u_{j+1}(x_{j+1}) - u_j(x_{j+1}) - u_{j+1}(x_j))) $$
+/// where u_j is the reduced energy of rung j.
+/// When every rung has the same Hamiltonian, this reduces to parallel tempering in beta.
+pub struct HamiltonianExchangeSampler<S, R>{
+    rungs: Vec<S>,
+    _phantom: PhantomData<R>
+}
+
+pub fn hamiltonian_exchange_sampler<R, S>(rungs: Vec<S>) -> HamiltonianExchangeSampler<S, R>
+where
+    S: ReducedEnergy<R>, R: Real
+{
+    return HamiltonianExchangeSampler{rungs, _phantom: PhantomData};
+}
+
+impl<S, R> HamiltonianExchangeSampler<S, R>{
+    pub fn num_rungs(&self) -> usize{
+        return self.rungs.len();
+    }
+    pub fn rungs(&self) -> &[S]{
+        return &self.rungs;
+    }
+}
+
+impl<R, Rn: Rng+?Sized, S> Sampler<Rn> for HamiltonianExchangeSampler<S, R>
+where   R: Real, Standard: Distribution<R>,
+        S: Sampler<Rn> + ReducedEnergy<R, Microstate=<S as Sampler<Rn>>::SampleType>
+{
+    type SampleType = PTState<S::SampleType>;
+
+    fn advance(&self, state: &mut PTState<S::SampleType>, rng: &mut Rn) {
+        let n = state.states.len();
+        if self.rungs.len() != n{
+            panic!("HamiltonianExchangeSampler: Expected a chain of {} states but got {}", n, self.rungs.len());
+        }
+        // Apply replica exchange moves with cross-evaluated reduced energies
+        for j in 0..n-1{
+            let (lo, hi) = state.states.split_at_mut(j+1);
+            let (xa, xb) = (&mut lo[j], &mut hi[0]);
+            let (sa, sb) = (&self.rungs[j], &self.rungs[j+1]);
+            let dlt: R = sa.reduced_energy(xa) + sb.reduced_energy(xb)
+                - sa.reduced_energy(xb) - sb.reduced_energy(xa);
+            if dlt >= R::zero() || (rng.sample::<R, _>(Standard) < R::exp(dlt)){
+                state.swap_states(j);
+                sa.state_received(&mut state.states[j]);
+                sb.state_received(&mut state.states[j+1]);
+            }
+        }
+        state.update_round_trips();
+        // Sweep samples
+        for (sampler, xi) in self.rungs.iter()
+                .zip(state.states.iter_mut()){
+            sampler.sweep(xi,  rng);
+        }
+    }
+
+    fn sweep(&self, state: &mut PTState<S::SampleType>, rng: &mut Rn){
+        self.advance(state, rng);
+    }
+}
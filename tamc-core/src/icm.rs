@@ -0,0 +1,170 @@
+//! Replica cluster moves between pairs of states
+use crate::traits::*;
+use rand::Rng;
+
+/// Options for restricting the size of the clusters that may be exchanged.
+/// A cluster that exceeds either limit is rejected and the replicas are left unchanged.
+/// Capping the cluster size (e.g. Zhu, Ochoa and Katzgraber) avoids exchanging
+/// percolating clusters, which are essentially a relabeling of the two replicas.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HoudayerOptions{
+    pub max_cluster_size: Option<usize>,
+    pub max_cluster_fraction: Option<f64>
+}
+
+/// Flat adjacency lists of the instance graph
+struct Adjacency{
+    row_idxs: Vec<usize>,
+    neighbors: Vec<u32>
+}
+
+impl Adjacency{
+    fn from_instance<I: GraphInstance>(instance: &I) -> Self{
+        let n = instance.num_sites();
+        if n  > u32::MAX as usize{
+            panic!("Adjacency: instance size must fit in u32")
+        }
+        let mut row_idxs = Vec::with_capacity(n+1);
+        let mut neighbors = Vec::new();
+        row_idxs.push(0);
+        for i in 0..n{
+            instance.for_each_neighbor(i as u32, |j| neighbors.push(j));
+            row_idxs.push(neighbors.len());
+        }
+        neighbors.shrink_to_fit();
+        return Self{row_idxs, neighbors};
+    }
+
+    #[inline]
+    unsafe fn uget_row(&self, i: usize) -> &[u32]{
+        let j_beg = *self.row_idxs.get_unchecked(i);
+        let j_end = *self.row_idxs.get_unchecked(i+1);
+        return self.neighbors.get_unchecked(j_beg..j_end);
+    }
+}
+
+/// Reusable buffers for the cluster move of a single replica pair
+pub struct ClusterWorkspace{
+    /// Sites where the two replicas disagree
+    diff_sites: Vec<u32>,
+    /// Sites discovered by the cluster search
+    discovered: Vec<bool>,
+    /// BFS queue. After the search, this holds every site in the cluster
+    queue: Vec<u32>,
+}
+
+impl ClusterWorkspace{
+    pub fn new(n: usize) -> Self{
+        return Self{
+            diff_sites: Vec::with_capacity(n),
+            discovered: vec![false; n],
+            queue: Vec::with_capacity(n)
+        };
+    }
+    /// The sites of the most recently grown cluster
+    pub fn cluster(&self) -> &[u32]{
+        return &self.queue;
+    }
+}
+
+/// Houdayer cluster move over the interaction graph of any graph-structured instance.
+/// A random site where the two replicas differ is chosen and the connected cluster of differing
+/// sites containing it is exchanged between the replicas.
+/// For pairwise interactions, the total energy of the two replicas is unchanged by the exchange,
+/// so the move is always accepted, unless the cluster exceeds the size restrictions.
+pub struct HoudayerSampler<St>{
+    adjacency: Adjacency,
+    max_cluster_size: usize,
+    _phantom: std::marker::PhantomData<fn(St)>
+}
+
+impl<St: ReplicaState> HoudayerSampler<St>{
+    pub fn new<I: GraphInstance>(instance: &I, options: HoudayerOptions) -> Self{
+        let adjacency = Adjacency::from_instance(instance);
+        let n = instance.num_sites();
+        let mut max_cluster_size = options.max_cluster_size.unwrap_or(n);
+        if let Some(f) = options.max_cluster_fraction{
+            max_cluster_size = max_cluster_size.min((f * n as f64).floor() as usize);
+        }
+        return Self{adjacency, max_cluster_size, _phantom: std::marker::PhantomData};
+    }
+
+    pub fn num_sites(&self) -> usize{
+        return self.adjacency.row_idxs.len() - 1;
+    }
+
+    pub fn workspace(&self) -> ClusterWorkspace{
+        return ClusterWorkspace::new(self.num_sites());
+    }
+
+    /// Grow the cluster of differing sites containing `init_site` into `ws.queue`.
+    /// Returns false if the search was stopped for exceeding the maximum cluster size.
+    fn grow_cluster(&self, state1: &St, state2: &St, init_site: u32, ws: &mut ClusterWorkspace) -> bool{
+        ws.queue.clear();
+        ws.queue.push(init_site);
+        ws.discovered[init_site as usize] = true;
+        let mut head = 0;
+        let mut accepted = true;
+        'bfs: while head < ws.queue.len(){
+            let i = ws.queue[head];
+            head += 1;
+            let neighbors = unsafe { self.adjacency.uget_row(i as usize) };
+            for &j in neighbors.iter(){
+                let d = unsafe { ws.discovered.get_unchecked_mut(j as usize) };
+                if !*d && state1.site_differs(state2, j) {
+                    *d = true;
+                    ws.queue.push(j);
+                    if ws.queue.len() > self.max_cluster_size{
+                        accepted = false;
+                        break 'bfs;
+                    }
+                }
+            }
+        }
+        // Reset the discovered set for the next move
+        for &i in ws.queue.iter(){
+            unsafe { *ws.discovered.get_unchecked_mut(i as usize) = false; }
+        }
+        return accepted;
+    }
+}
+
+impl<St: ReplicaState, Rn: Rng+?Sized> PairSampler<Rn> for HoudayerSampler<St>{
+    type SampleType = St;
+    type Workspace = ClusterWorkspace;
+
+    fn workspace(&self) -> ClusterWorkspace{
+        return HoudayerSampler::workspace(self);
+    }
+
+    /// Selecting the initial site scans all n sites, so a move costs O(n) even when the replicas differ
+    /// in only a few sites. The differing sites cannot be tracked between moves, as the replicas are also
+    /// changed by sweeps and exchanged between temperatures outside of this sampler.
+    /// The scan is cheap next to the sweep of both replicas that accompanies every move.
+    fn pair_advance(&self, state1: &mut St, state2: &mut St, ws: &mut ClusterWorkspace, rng: &mut Rn) -> usize{
+        let n = self.num_sites();
+        // Select a random site that differs between the replicas
+        ws.diff_sites.clear();
+        for i in 0..n as u32{
+            if state1.site_differs(state2, i){
+                ws.diff_sites.push(i);
+            }
+        }
+        if ws.diff_sites.is_empty(){
+            ws.queue.clear();
+            return 0;
+        }
+        let init_site = ws.diff_sites[rng.gen_range(0..ws.diff_sites.len())];
+        if !self.grow_cluster(state1, state2, init_site, ws){
+            ws.queue.clear();
+            return 0;
+        }
+        // Finally, exchange the whole cluster
+        for &i in ws.queue.iter(){
+            state1.exchange_site(state2, i);
+        }
+        state1.sites_exchanged();
+        state2.sites_exchanged();
+        return ws.queue.len();
+    }
+}
@@ -1,6 +1,9 @@
 pub mod traits;
 pub mod metropolis;
 pub mod ensembles;
+pub mod icm;
+pub mod hrem;
+pub mod st;
 pub mod pt;
 pub mod sa;
 pub mod util;
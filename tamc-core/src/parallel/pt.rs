@@ -8,6 +8,7 @@ use num_traits::Num;
 pub use crate::pt::{PTState, PTRoundTrip};
 
 use rayon::prelude::*;
+use std::ops::Range;
 
 /// Implementes the Parallel Tempering algorithm on a vector of macrocanonical samplers
 pub struct ThreadedPTSampler<S, R>{
@@ -27,6 +28,36 @@ pub fn parallel_tempering_sampler<R, S>(sampler_chain: Vec<S> )
     return ThreadedPTSampler{tempering_chain: sampler_chain, delta_beta};
 }
 
+impl<S, R> ThreadedPTSampler<S, R>{
+    pub fn num_temperatures(&self) -> usize{
+        return self.tempering_chain.len();
+    }
+
+    /// Applies a two-replica move between the states of two PT chains at each temperature index in
+    /// `temperatures` in parallel, using one workspace and one rng per temperature.
+    /// The return value of each move is written into `moves`
+    pub fn pair_advance<P, Rn>(&self, pair_sampler: &P,
+                               chain1: &mut PTState<P::SampleType>, chain2: &mut PTState<P::SampleType>,
+                               temperatures: Range<usize>, workspaces: &mut [P::Workspace],
+                               moves: &mut [usize], rng_vec: &mut Vec<Rn>)
+    where   P: PairSampler<Rn> + Sync,
+            P::SampleType: Send,
+            P::Workspace: Send,
+            Rn: Send
+    {
+        let n = self.tempering_chain.len();
+        if chain1.states.len() != n || chain2.states.len() != n{
+            panic!("ThreadedPTSampler: Expected a chain of {} states but got {} and {}",
+                   n, chain1.states.len(), chain2.states.len());
+        }
+        chain1.states[temperatures.clone()].par_iter_mut()
+            .zip_eq(chain2.states[temperatures.clone()].par_iter_mut())
+            .zip_eq(workspaces.par_iter_mut())
+            .zip_eq(moves.par_iter_mut())
+            .zip_eq(rng_vec[temperatures].par_iter_mut())
+            .for_each(|((((x1, x2), ws), m), rng)| *m = pair_sampler.pair_advance(x1, x2, ws, rng));
+    }
+}
 
 impl<R, Rn: Rng+Send, S>
 Sampler<Vec<Rn>>
@@ -5,6 +5,7 @@ use num_traits::Num;
 use rand::Rng;
 use rand::distributions::{Standard, Distribution};
 use serde::{Serialize, Deserialize};
+use std::ops::Range;
 
 #[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum PTRoundTrip{
@@ -108,6 +109,33 @@ where
     return ParallelTemperingSampler{tempering_chain: sampler_chain, delta_beta};
 }
 
+impl<S, R> ParallelTemperingSampler<S, R>{
+    pub fn num_temperatures(&self) -> usize{
+        return self.tempering_chain.len();
+    }
+
+    /// Applies a two-replica move between the states of two PT chains at each temperature index in
+    /// `temperatures`, using one workspace per temperature.
+    /// The return value of each move is written into `moves`
+    pub fn pair_advance<P, Rn: ?Sized>(&self, pair_sampler: &P,
+                                       chain1: &mut PTState<P::SampleType>, chain2: &mut PTState<P::SampleType>,
+                                       temperatures: Range<usize>, workspaces: &mut [P::Workspace],
+                                       moves: &mut [usize], rng: &mut Rn)
+    where P: PairSampler<Rn>
+    {
+        let n = self.tempering_chain.len();
+        if chain1.states.len() != n || chain2.states.len() != n{
+            panic!("ParallelTemperingSampler: Expected a chain of {} states but got {} and {}",
+                   n, chain1.states.len(), chain2.states.len());
+        }
+        for (((x1, x2), ws), m) in chain1.states[temperatures.clone()].iter_mut()
+                .zip(chain2.states[temperatures].iter_mut())
+                .zip(workspaces.iter_mut())
+                .zip(moves.iter_mut()){
+            *m = pair_sampler.pair_advance(x1, x2, ws, rng);
+        }
+    }
+}
 
 impl<R, Rn: Rng+?Sized, S: MacroSampler<R, Rn>>
 Sampler<Rn>
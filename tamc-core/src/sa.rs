@@ -1,5 +1,6 @@
 //! Simulated annealing of a Metropolis sampler
 use std::cell::Cell;
+use std::ops::ControlFlow;
 use num_traits::{FromPrimitive, Num, PrimInt};
 use crate::traits::{State, Instance, Sampler, Macrostate, MacroSampler};
 use crate::ensembles::EnsembleSampler;
@@ -8,6 +9,7 @@ use rand::prelude::*;
 use rand::distributions::Standard;
 use num_traits::real::Real;
 use rand::distributions::uniform::SampleUniform;
+use serde::{Serialize, Deserialize};
 
 
 pub fn geometric_beta_schedule(beta0: f64, betaf: f64, num_sweeps: usize) -> Vec<f64>{
@@ -31,6 +33,26 @@ pub fn simulated_annealing<R, N, St, I, D: Distribution<N>, Rn: Rng+?Sized, F: F
     rng: &mut Rn,
     mut measure: F
 )
+where
+    I: Instance<N, St, Energy=R>,
+    St: State<N>,
+    Standard: Distribution<R>,
+    R: Real,
+    N: Num + FromPrimitive
+{
+    simulated_annealing_until(sampler, states, beta_schedule, rng,
+        |i, st| { measure(i, st); ControlFlow::Continue(()) });
+}
+
+/// Simulated annealing that stops early once the measurement returns `ControlFlow::Break`.
+/// Returns the number of sweeps performed
+pub fn simulated_annealing_until<R, N, St, I, D: Distribution<N>, Rn: Rng+?Sized, F: FnMut(usize, &Vec<St>) -> ControlFlow<()>>(
+    sampler : MetropolisSampler<R, N, St, I, D>,
+    states: &mut Vec<St>,
+    beta_schedule : &[R],
+    rng: &mut Rn,
+    mut measure: F
+) -> usize
 where
     I: Instance<N, St, Energy=R>,
     St: State<N>,
@@ -43,7 +65,101 @@ where
     for i in 0..num_beta{
         ensemble_sampler.sub_sampler.beta = beta_schedule[i];
         ensemble_sampler.sweep(states,  rng);
-        measure(i, &states);
+        if measure(i, &states).is_break(){
+            return i + 1;
+        }
+    }
+    return num_beta;
+}
+
+
+/// Annealed importance sampling with Metropolis MC.
+/// The initial states are treated as samples at β = 0, i.e. uniformly random states.
+/// Before the sweep at each β_k, the log importance weight of each replica is incremented by
+///     -(β_k - β_{k-1}) E(x),      β_{-1} = 0
+/// so that the average of exp(log_weights) is an unbiased estimate of Z(β_f) / Z(0)
+pub fn annealed_importance_sampling<R, N, St, I, D: Distribution<N>, Rn: Rng+?Sized, F: FnMut(usize, &Vec<St>)>(
+    sampler : MetropolisSampler<R, N, St, I, D>,
+    states: &mut Vec<St>,
+    beta_schedule : &[R],
+    log_weights: &mut [f64],
+    rng: &mut Rn,
+    mut measure: F
+)
+where
+    I: Instance<N, St, Energy=R>,
+    St: State<N>,
+    Standard: Distribution<R>,
+    R: Real,
+    N: Num + FromPrimitive
+{
+    annealed_importance_sampling_until(sampler, states, beta_schedule, log_weights, rng,
+        |i, st| { measure(i, st); ControlFlow::Continue(()) });
+}
+
+/// Annealed importance sampling that stops early once the measurement returns `ControlFlow::Break`.
+/// Returns the number of sweeps performed. The log weights of an early stop
+/// estimate Z at the last β reached rather than at the end of the schedule.
+pub fn annealed_importance_sampling_until<R, N, St, I, D: Distribution<N>, Rn: Rng+?Sized, F: FnMut(usize, &Vec<St>) -> ControlFlow<()>>(
+    sampler : MetropolisSampler<R, N, St, I, D>,
+    states: &mut Vec<St>,
+    beta_schedule : &[R],
+    log_weights: &mut [f64],
+    rng: &mut Rn,
+    mut measure: F
+) -> usize
+where
+    I: Instance<N, St, Energy=R>,
+    St: State<N>,
+    Standard: Distribution<R>,
+    R: Real,
+    N: Num + FromPrimitive
+{
+    if log_weights.len() != states.len(){
+        panic!("annealed_importance_sampling: Expected {} log weights but got {}", states.len(), log_weights.len());
+    }
+    let num_beta = beta_schedule.len();
+    let mut ensemble_sampler = EnsembleSampler::new(sampler);
+    let mut beta_prev = R::zero();
+    for i in 0..num_beta{
+        let beta = beta_schedule[i];
+        let db = (beta - beta_prev).to_f64().unwrap();
+        for (st, lw) in states.iter().zip(log_weights.iter_mut()){
+            let e = ensemble_sampler.sub_sampler.instance.energy_ref(st);
+            *lw -= db * e.to_f64().unwrap();
+        }
+        beta_prev = beta;
+        ensemble_sampler.sub_sampler.beta = beta;
+        ensemble_sampler.sweep(states,  rng);
+        if measure(i, &states).is_break(){
+            return i + 1;
+        }
     }
+    return num_beta;
+}
+
+/// Estimate of log Z from annealed importance sampling weights
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct AisEstimate{
+    pub log_z: f64,
+    /// Delta method estimate of the variance of log_z
+    pub log_z_var: f64,
+    /// Effective sample size (Σ w)^2 / Σ w^2
+    pub ess: f64
 }
 
+impl AisEstimate{
+    /// Combine the log importance weights with the log partition function log_z0 at β = 0
+    pub fn from_log_weights(log_weights: &[f64], log_z0: f64) -> Self{
+        let m = log_weights.len() as f64;
+        let max_lw = log_weights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let (sum_w, sum_w2) = log_weights.iter()
+            .map(|&lw| f64::exp(lw - max_lw))
+            .fold((0.0, 0.0), |(s, s2), w| (s + w, s2 + w * w));
+        let log_z = log_z0 + max_lw + f64::ln(sum_w / m);
+        let ess = sum_w * sum_w / sum_w2;
+        // var(w) / (m <w>^2) = 1/ESS - 1/m
+        let log_z_var = (1.0 / ess - 1.0 / m).max(0.0);
+        return Self{log_z, log_z_var, ess};
+    }
+}
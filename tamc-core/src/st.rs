@@ -0,0 +1,220 @@
+//! Simulated tempering of a single replica over a temperature ladder
+use crate::traits::*;
+use crate::pt::PTRoundTrip;
+use ndarray::prelude::*;
+use num_traits::real::Real;
+use rand::Rng;
+use rand::distributions::{Standard, Distribution};
+use serde::{Serialize, Deserialize};
+
+/// Online learning scheme for the simulated tempering log weights g_k,
+/// where the joint distribution is  π(x, k) ∝ exp(-β_k E(x) + g_k)
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum STWeights{
+    /// Wang-Landau updates: the weight of the current temperature is lowered by ln_f after every step.
+    /// ln_f is halved whenever the visit histogram is flat, i.e. every count is at least
+    /// `flatness` times the mean count, and updates stop once ln_f falls below ln_f_min
+    WangLandau{ ln_f0: f64, flatness: f64, ln_f_min: f64 },
+    /// Weights from the running average energy at each temperature,
+    ///     g_{k+1} - g_k = (β_{k+1} - β_k) (<E>_k + <E>_{k+1}) / 2
+    EnergyAverage
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct STState<St>{
+    pub state: St,
+    pub beta_idx: usize,
+    pub log_weights: Vec<f64>,
+    /// Whether the log weights are still being learned
+    pub adapt_weights: bool,
+    /// Current Wang-Landau modification factor
+    pub ln_f: f64,
+    /// Wang-Landau visit histogram since the last reduction of ln_f
+    pub wl_hist: Vec<u64>,
+    pub energy_sums: Vec<f64>,
+    /// Total number of visits to each temperature
+    pub temperature_hist: Vec<u64>,
+    /// Accepted transitions between temperatures k and k+1
+    pub num_acceptances: Array1<u32>,
+    pub round_trips: u32,
+    pub round_trip_tag: PTRoundTrip,
+    pub diffusion_hist: Array2<u32>
+}
+
+impl<St> STState<St>{
+    pub fn new(state: St, num_betas: usize, weights: &STWeights) -> Self{
+        let ln_f = match weights{
+            STWeights::WangLandau { ln_f0, .. } => *ln_f0,
+            STWeights::EnergyAverage => 0.0
+        };
+        return Self{
+            state,
+            beta_idx: 0,
+            log_weights: vec![0.0; num_betas],
+            adapt_weights: true,
+            ln_f,
+            wl_hist: vec![0; num_betas],
+            energy_sums: vec![0.0; num_betas],
+            temperature_hist: vec![0; num_betas],
+            num_acceptances: Array1::zeros(num_betas),
+            round_trips: 0,
+            round_trip_tag: PTRoundTrip::MinBeta,
+            diffusion_hist: Array2::zeros((num_betas, 2))
+        };
+    }
+
+    pub fn reset_tags(&mut self){
+        let n = self.log_weights.len();
+        self.num_acceptances = Array1::zeros(n);
+        self.diffusion_hist = Array2::zeros((n, 2));
+        self.round_trips = 0;
+        self.round_trip_tag = PTRoundTrip::None;
+        self.update_round_trips();
+    }
+
+    /// Stop learning the log weights, e.g. at the end of the warmup,
+    /// so that later samples are drawn from a fixed distribution
+    pub fn freeze_weights(&mut self){
+        self.adapt_weights = false;
+    }
+
+    /// Mean energy at each temperature visited so far
+    pub fn mean_energies(&self) -> Vec<f64>{
+        return (0..self.energy_sums.len()).map(|k| self.mean_energy(k)).collect();
+    }
+
+    fn mean_energy(&self, k: usize) -> f64{
+        let c = self.temperature_hist[k];
+        return if c > 0 { self.energy_sums[k] / c as f64 } else { 0.0 };
+    }
+
+    fn update_round_trips(&mut self){
+        let n = self.log_weights.len();
+        let tag = &mut self.round_trip_tag;
+        if self.beta_idx == 0{
+            match *tag{
+                PTRoundTrip::None => {*tag = PTRoundTrip::MinBeta},
+                PTRoundTrip::MaxBetaReflected => {self.round_trips += 1; *tag = PTRoundTrip::MinBeta},
+                PTRoundTrip::MaxBeta => {*tag = PTRoundTrip::MinBetaReflected},
+                _ => {}
+            };
+        } else if self.beta_idx == n-1{
+            match *tag{
+                PTRoundTrip::None => {*tag = PTRoundTrip::MaxBeta},
+                PTRoundTrip::MinBetaReflected => {self.round_trips += 1; *tag = PTRoundTrip::MaxBeta},
+                PTRoundTrip::MinBeta => {*tag = PTRoundTrip::MaxBetaReflected},
+                _ => {}
+            };
+        }
+        let mut h = self.diffusion_hist.row_mut(self.beta_idx);
+        match self.round_trip_tag{
+            PTRoundTrip::MinBeta | PTRoundTrip::MinBetaReflected => { h[0] += 1; }
+            PTRoundTrip::MaxBeta | PTRoundTrip::MaxBetaReflected => { h[1] += 1; }
+            _ => {}
+        };
+    }
+}
+
+/// Simulated tempering: a single replica random-walks over a ladder of macrocanonical samplers.
+/// Each step sweeps the state at the current temperature, then proposes a move to a neighboring temperature
+/// accepted with the Metropolis probability of π(x, k) ∝ exp(-β_k E(x) + g_k).
+/// The log weights g_k are learned online, as an estimate of -log Z_k that makes the walk uniform in k.
+pub struct SimulatedTemperingSampler<S, R>{
+    tempering_chain: Vec<S>,
+    betas: Vec<R>,
+    weights: STWeights
+}
+
+pub fn simulated_tempering_sampler<R, S>(sampler_chain: Vec<S>, weights: STWeights)
+        -> SimulatedTemperingSampler<S, R>
+where
+        S: Macrostate<R>, R: Real
+{
+    let betas : Vec<R> = sampler_chain.iter().map(|s|s.beta()).collect();
+    return SimulatedTemperingSampler{tempering_chain: sampler_chain, betas, weights};
+}
+
+impl<S, R: Real> SimulatedTemperingSampler<S, R>{
+    pub fn num_temperatures(&self) -> usize{
+        return self.tempering_chain.len();
+    }
+
+    /// Update the log weights after a step that measured the energy at temperature `k_meas`
+    fn update_weights<St>(&self, st: &mut STState<St>, k_meas: usize){
+        if !st.adapt_weights{
+            return;
+        }
+        let k = st.beta_idx;
+        match self.weights{
+            STWeights::WangLandau { flatness, ln_f_min, .. } => {
+                if st.ln_f < ln_f_min{
+                    return;
+                }
+                st.log_weights[k] -= st.ln_f;
+                st.wl_hist[k] += 1;
+                let &min_h = st.wl_hist.iter().min().unwrap();
+                let mean_h = st.wl_hist.iter().sum::<u64>() as f64 / st.wl_hist.len() as f64;
+                if min_h > 0 && (min_h as f64) >= flatness * mean_h{
+                    st.ln_f /= 2.0;
+                    for h in st.wl_hist.iter_mut(){
+                        *h = 0;
+                    }
+                }
+            }
+            STWeights::EnergyAverage => {
+                // Only the mean energy at k_meas has changed, so the weights below it are still up to date
+                let n = self.betas.len();
+                st.log_weights[0] = 0.0;
+                for j in k_meas.saturating_sub(1)..n-1{
+                    // Fall back to the nearest visited temperature
+                    let (e0, e1) = match (st.temperature_hist[j] > 0, st.temperature_hist[j+1] > 0){
+                        (true, true) => (st.mean_energy(j), st.mean_energy(j+1)),
+                        (true, false) => (st.mean_energy(j), st.mean_energy(j)),
+                        (false, true) => (st.mean_energy(j+1), st.mean_energy(j+1)),
+                        (false, false) => (0.0, 0.0)
+                    };
+                    let db = (self.betas[j+1] - self.betas[j]).to_f64().unwrap();
+                    st.log_weights[j+1] = st.log_weights[j] + db * (e0 + e1) / 2.0;
+                }
+            }
+        }
+    }
+}
+
+impl<R, Rn: Rng+?Sized, S: MacroSampler<R, Rn>>
+Sampler<Rn>
+for SimulatedTemperingSampler<S, R>
+where R: Real, Standard: Distribution<R>
+{
+    type SampleType = STState<S::SampleType>;
+
+    fn advance(&self, st: &mut STState<S::SampleType>, rng: &mut Rn) {
+        let n = self.tempering_chain.len();
+        if st.log_weights.len() != n{
+            panic!("SimulatedTemperingSampler: Expected weights for {} temperatures but got {}", n, st.log_weights.len());
+        }
+        // Sweep the state at the current temperature
+        let k = st.beta_idx;
+        let sampler = &self.tempering_chain[k];
+        sampler.sweep(&mut st.state, rng);
+        let e = sampler.energy(&mut st.state);
+        st.energy_sums[k] += e.to_f64().unwrap();
+        st.temperature_hist[k] += 1;
+        // Propose a move to a neighboring temperature
+        let k2 = if rng.gen::<bool>() { k + 1 } else { k.wrapping_sub(1) };
+        if k2 < n {
+            let db = (self.betas[k2] - self.betas[k]).to_f64().unwrap();
+            let dlt = -db * e.to_f64().unwrap() + st.log_weights[k2] - st.log_weights[k];
+            if dlt >= 0.0 || rng.gen::<f64>() < f64::exp(dlt){
+                st.beta_idx = k2;
+                st.num_acceptances[k.min(k2)] += 1;
+            }
+        }
+        st.update_round_trips();
+        self.update_weights(st, k);
+    }
+
+    fn sweep(&self, st: &mut STState<S::SampleType>, rng: &mut Rn){
+        self.advance(st, rng);
+    }
+}
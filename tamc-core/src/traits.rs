@@ -51,4 +51,45 @@ pub trait MacroSampler<R,  Rn: ?Sized>: Sampler<Rn> + Macrostate<R, Microstate=<
 
 impl<R,  Rn: ?Sized, T> MacroSampler<R, Rn> for T
     where T: Sampler<Rn> + Macrostate<R, Microstate=<T as Sampler<Rn>>::SampleType>
-{}
\ No newline at end of file
+{}
+
+/// An instance whose sites are the nodes of an interaction graph
+pub trait GraphInstance{
+    fn num_sites(&self) -> usize;
+    /// Visit each neighbor of site i
+    fn for_each_neighbor<F: FnMut(u32)>(&self, i: u32, f: F);
+}
+
+/// A state that can be compared and exchanged site-by-site with another replica of the same instance
+pub trait ReplicaState{
+    /// Whether site i takes a different value in the two replicas
+    fn site_differs(&self, other: &Self, i: u32) -> bool;
+    /// Exchange the value of site i between the two replicas
+    fn exchange_site(&mut self, other: &mut Self, i: u32);
+    /// Called once after a move has exchanged sites, e.g. to invalidate cached energies
+    fn sites_exchanged(&mut self){ }
+}
+
+/// A sampler that jointly advances two replicas of the same ensemble, such as a cluster move
+/// between two replicas at the same temperature.
+/// Intermediate data needed by the move is kept in a reusable workspace.
+pub trait PairSampler<Rn: ?Sized>{
+    type SampleType;
+    type Workspace;
+    fn workspace(&self) -> Self::Workspace;
+    /// Returns the number of sites changed by the move in each replica
+    fn pair_advance(&self, state1: &mut Self::SampleType, state2: &mut Self::SampleType,
+                    ws: &mut Self::Workspace, rng: &mut Rn) -> usize;
+}
+
+/// The reduced energy u(x) of a sampler whose stationary distribution is proportional to exp(-u(x)),
+/// e.g. u(x) = beta * H(x).
+/// Replica exchange between samplers with different Hamiltonians requires evaluating the reduced energy of
+/// each sampler on the states of the others.
+pub trait ReducedEnergy<R>{
+    type Microstate;
+    fn reduced_energy(&self, st: &mut Self::Microstate) -> R;
+    /// Called when a state from a different sampler is exchanged into this one,
+    /// e.g. to invalidate an energy cached for the previous Hamiltonian
+    fn state_received(&self, _st: &mut Self::Microstate){ }
+}